@@ -0,0 +1,449 @@
+// Admin :: Lets the raftfs command talk to running nodes.
+//
+// `raftfs status`, `raftfs remove` and `raftfs transfer-leader` ask the
+// node mounted on a directory, through a unix socket at
+// TARGET/.raftfs/control.  `raftfs join` asks a member of an existing
+// cluster to let a new machine in, through that member's raft port, and
+// so do the others when the node they asked sends them on to the leader.
+// Nodes also fetch blobs from each other this way, and compare their
+// trees when verifying.  Either way the conversation is a request frame
+// and then a response frame, framed just like raft messages.
+//
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use super::blob::{self, Hash};
+use super::codec::{self, DecodeError, Reader};
+use super::merkle::Item;
+use super::node::{Handle, Status};
+use super::raft::{ChangeError, LogIndex, Member, NodeId, Role};
+use super::state_machine::META_DIR;
+use super::tcp;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// How is the node doing?
+    Status,
+    /// Please add a node listening at `addr` to the cluster, with the id
+    /// given if there is one.
+    Join { id: Option<NodeId>, addr: SocketAddr },
+    /// Please take node `id` out of the cluster.
+    Remove { id: NodeId },
+    /// Please hand leadership to node `id`.
+    Transfer { id: NodeId },
+    /// Please send the contents of blob `hash`.
+    Blob { hash: Hash },
+    /// Please check that every replica agrees with yours.
+    Verify,
+    /// What does your summary as of checkpoint `index` say about `path`?
+    Summary { index: LogIndex, path: PathBuf },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// How the node is doing, and who it thinks is in the cluster.
+    Status(Status),
+    /// The new node has been given `id`, and these are its peers.
+    Joined { id: NodeId, peers: Vec<(NodeId, SocketAddr)> },
+    /// The node is on its way out of the cluster.
+    Removed,
+    /// The leader is handing over.
+    Transferring,
+    /// The contents of the blob asked for.
+    Blob(Vec<u8>),
+    /// The replicas were compared as of checkpoint `index`, and these are
+    /// the differences.
+    Verified { index: LogIndex, report: Vec<String> },
+    /// What the summary says about the path asked about.
+    Summary(Item),
+    /// Only the leader can do that; try it there, if we know where it is.
+    Redirect(Option<SocketAddr>),
+    /// The request was refused, for the reason given.
+    Refused(String),
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Request::Status => codec::put_u8(&mut out, 1),
+            Request::Join { id, addr } => {
+                codec::put_u8(&mut out, 2);
+                codec::put_bool(&mut out, id.is_some());
+                codec::put_u64(&mut out, id.unwrap_or(0));
+                put_addr(&mut out, &addr);
+            },
+            Request::Remove { id } => {
+                codec::put_u8(&mut out, 3);
+                codec::put_u64(&mut out, id);
+            },
+            Request::Transfer { id } => {
+                codec::put_u8(&mut out, 4);
+                codec::put_u64(&mut out, id);
+            },
+            Request::Blob { ref hash } => {
+                codec::put_u8(&mut out, 5);
+                codec::put_bytes(&mut out, hash);
+            },
+            Request::Verify => codec::put_u8(&mut out, 6),
+            Request::Summary { index, ref path } => {
+                codec::put_u8(&mut out, 7);
+                codec::put_u64(&mut out, index);
+                codec::put_os(&mut out, path.as_os_str());
+            },
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Request, DecodeError> {
+        let mut r = Reader::new(data);
+        let request = match r.u8()? {
+            1 => Request::Status,
+            2 => {
+                let given = r.bool()?;
+                let id = r.u64()?;
+                Request::Join { id: if given { Some(id) } else { None }, addr: addr(&mut r)? }
+            },
+            3 => Request::Remove { id: r.u64()? },
+            4 => Request::Transfer { id: r.u64()? },
+            5 => Request::Blob {
+                hash: blob::from_bytes(&r.bytes()?).ok_or(DecodeError::Invalid("hash"))?,
+            },
+            6 => Request::Verify,
+            7 => Request::Summary { index: r.u64()?, path: r.path()? },
+            t => return Err(DecodeError::UnknownTag(t)),
+        };
+        r.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            Response::Status(ref s) => {
+                codec::put_u8(&mut out, 1);
+                codec::put_u64(&mut out, s.id);
+                codec::put_u8(&mut out, match s.role {
+                    Role::Follower => 0,
+                    Role::Candidate => 1,
+                    Role::Leader => 2,
+                });
+                codec::put_u64(&mut out, s.term);
+                codec::put_bool(&mut out, s.leader.is_some());
+                codec::put_u64(&mut out, s.leader.unwrap_or(0));
+                codec::put_bool(&mut out, s.quorum);
+                codec::put_u64(&mut out, s.commit_index);
+                codec::put_u64(&mut out, s.applied);
+                codec::put_u32(&mut out, s.members.len() as u32);
+                for m in &s.members {
+                    codec::put_u64(&mut out, m.id);
+                    codec::put_bytes(&mut out, m.addr.as_bytes());
+                }
+            },
+            Response::Joined { id, ref peers } => {
+                codec::put_u8(&mut out, 2);
+                codec::put_u64(&mut out, id);
+                put_peers(&mut out, peers);
+            },
+            Response::Removed => codec::put_u8(&mut out, 5),
+            Response::Transferring => codec::put_u8(&mut out, 6),
+            Response::Blob(ref data) => {
+                codec::put_u8(&mut out, 7);
+                codec::put_bytes(&mut out, data);
+            },
+            Response::Verified { index, ref report } => {
+                codec::put_u8(&mut out, 8);
+                codec::put_u64(&mut out, index);
+                codec::put_u32(&mut out, report.len() as u32);
+                for line in report {
+                    codec::put_bytes(&mut out, line.as_bytes());
+                }
+            },
+            Response::Summary(ref item) => {
+                codec::put_u8(&mut out, 9);
+                item.encode(&mut out);
+            },
+            Response::Redirect(leader) => {
+                codec::put_u8(&mut out, 3);
+                codec::put_bool(&mut out, leader.is_some());
+                if let Some(addr) = leader {
+                    put_addr(&mut out, &addr);
+                }
+            },
+            Response::Refused(ref why) => {
+                codec::put_u8(&mut out, 4);
+                codec::put_bytes(&mut out, why.as_bytes());
+            },
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Response, DecodeError> {
+        let mut r = Reader::new(data);
+        let response = match r.u8()? {
+            1 => {
+                let id = r.u64()?;
+                let role = match r.u8()? {
+                    0 => Role::Follower,
+                    1 => Role::Candidate,
+                    2 => Role::Leader,
+                    _ => return Err(DecodeError::Invalid("role")),
+                };
+                let term = r.u64()?;
+                let has_leader = r.bool()?;
+                let leader = r.u64()?;
+                let quorum = r.bool()?;
+                let commit_index = r.u64()?;
+                let applied = r.u64()?;
+                let mut members = Vec::new();
+                for _ in 0..r.u32()? {
+                    let id = r.u64()?;
+                    let addr = String::from_utf8(r.bytes()?)
+                        .map_err(|_| DecodeError::Invalid("address"))?;
+                    members.push(Member { id: id, addr: addr });
+                }
+                let status = Status {
+                    id: id,
+                    role: role,
+                    term: term,
+                    leader: if has_leader { Some(leader) } else { None },
+                    quorum: quorum,
+                    commit_index: commit_index,
+                    applied: applied,
+                    members: members,
+                };
+                Response::Status(status)
+            },
+            2 => {
+                let id = r.u64()?;
+                Response::Joined { id: id, peers: get_peers(&mut r)? }
+            },
+            3 => Response::Redirect(if r.bool()? { Some(addr(&mut r)?) } else { None }),
+            4 => Response::Refused(String::from_utf8(r.bytes()?)
+                                   .map_err(|_| DecodeError::Invalid("reason"))?),
+            5 => Response::Removed,
+            6 => Response::Transferring,
+            7 => Response::Blob(r.bytes()?),
+            8 => {
+                let index = r.u64()?;
+                let mut report = Vec::new();
+                for _ in 0..r.u32()? {
+                    report.push(String::from_utf8(r.bytes()?)
+                                .map_err(|_| DecodeError::Invalid("report"))?);
+                }
+                Response::Verified { index: index, report: report }
+            },
+            9 => Response::Summary(Item::decode(&mut r)?),
+            t => return Err(DecodeError::UnknownTag(t)),
+        };
+        r.finish()?;
+        Ok(response)
+    }
+}
+
+fn put_addr(out: &mut Vec<u8>, addr: &SocketAddr) {
+    codec::put_bytes(out, addr.to_string().as_bytes());
+}
+
+fn addr(r: &mut Reader) -> Result<SocketAddr, DecodeError> {
+    String::from_utf8(r.bytes()?).ok().and_then(|a| a.parse().ok())
+        .ok_or(DecodeError::Invalid("address"))
+}
+
+fn put_peers(out: &mut Vec<u8>, peers: &[(NodeId, SocketAddr)]) {
+    codec::put_u32(out, peers.len() as u32);
+    for &(id, ref addr) in peers {
+        codec::put_u64(out, id);
+        put_addr(out, addr);
+    }
+}
+
+fn get_peers(r: &mut Reader) -> Result<Vec<(NodeId, SocketAddr)>, DecodeError> {
+    let mut peers = Vec::new();
+    for _ in 0..r.u32()? {
+        let id = r.u64()?;
+        peers.push((id, addr(r)?));
+    }
+    Ok(peers)
+}
+
+/// Answers requests on behalf of a running node.
+pub struct Responder {
+    node: Handle,
+}
+
+impl Responder {
+    pub fn new(node: Handle) -> Responder {
+        Responder { node: node }
+    }
+
+    /// Answer an encoded request with an encoded response.
+    pub fn answer(&self, request: &[u8]) -> Vec<u8> {
+        let response = match Request::decode(request) {
+            Ok(request) => self.respond(request),
+            Err(e) => Response::Refused(format!("bad request: {}", e)),
+        };
+        response.encode()
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        let result = match request {
+            Request::Status => return Response::Status(self.node.status()),
+            Request::Join { id, addr } => match self.node.add_member(id, addr.to_string()) {
+                Ok((id, members)) => {
+                    let peers = members.iter().filter(|m| m.id != id)
+                        .filter_map(|m| m.addr.parse().ok().map(|addr| (m.id, addr)))
+                        .collect();
+                    return Response::Joined { id: id, peers: peers };
+                },
+                Err(e) => e,
+            },
+            Request::Remove { id } => match self.node.remove_member(id) {
+                Ok(()) => return Response::Removed,
+                Err(e) => e,
+            },
+            Request::Transfer { id } => match self.node.transfer_leadership(id) {
+                Ok(()) => return Response::Transferring,
+                Err(ChangeError::NoChange) => {
+                    return Response::Refused(format!("node {} is the leader already, or not a member", id));
+                },
+                Err(e) => e,
+            },
+            Request::Blob { hash } => return match self.node.blob(&hash) {
+                Some(data) => Response::Blob(data),
+                None => Response::Refused(format!("no blob {}", blob::hex(&hash))),
+            },
+            Request::Verify => return match self.node.verify() {
+                Ok((index, report)) => Response::Verified { index: index, report: report },
+                Err(e) => Response::Refused(format!("unable to verify: {}",
+                                                    io::Error::from_raw_os_error(e))),
+            },
+            Request::Summary { index, ref path } => return match self.node.summary(index, path) {
+                Ok(item) => Response::Summary(item),
+                Err(why) => Response::Refused(why),
+            },
+        };
+        match result {
+            ChangeError::NotLeader(leader) => {
+                let members = self.node.status().members;
+                let addr = members.iter().find(|m| Some(m.id) == leader)
+                    .and_then(|m| m.addr.parse().ok());
+                Response::Redirect(addr)
+            },
+            ChangeError::Busy => {
+                Response::Refused("another change is under way; try again".to_string())
+            },
+            ChangeError::NoChange => Response::Refused("that would change nothing".to_string()),
+        }
+    }
+}
+
+/// Where the node mounted on `target` listens for `raftfs status`.
+fn control_path(target: &Path) -> PathBuf {
+    target.join(META_DIR).join("control")
+}
+
+/// Answer requests from this machine about the node running on `target`.
+pub fn serve_local(target: &Path, responder: Arc<Responder>) -> io::Result<()> {
+    let path = control_path(target);
+    // A socket left behind by a node that died; nobody is listening.
+    fs::remove_file(&path).ok();
+    let listener = UnixListener::bind(&path)?;
+    thread::Builder::new().name("raftfs-control".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("accept on control socket failed: {}", e);
+                    continue;
+                },
+            };
+            let responder = responder.clone();
+            thread::spawn(move || {
+                let answer = |request: &[u8]| responder.answer(request);
+                let result = stream.try_clone()
+                    .and_then(|w| tcp::converse(BufReader::new(stream), w, &answer));
+                if let Err(e) = result {
+                    debug!("dropping control connection: {}", e);
+                }
+            });
+        }
+    })?;
+    Ok(())
+}
+
+/// Ask the node running on `target` on this machine.
+pub fn ask_local(target: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(control_path(target))?;
+    tcp::write_frame(&mut stream, &request.encode())?;
+    match tcp::read_frame(&mut stream)? {
+        Some(response) => decode_response(&response),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "node hung up without answering")),
+    }
+}
+
+/// Ask the node listening at `addr`, which may be on another machine.
+pub fn ask(addr: &SocketAddr, request: &Request) -> io::Result<Response> {
+    decode_response(&tcp::ask(addr, &request.encode())?)
+}
+
+fn decode_response(data: &[u8]) -> io::Result<Response> {
+    Response::decode(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let addr: SocketAddr = "10.0.0.3:7420".parse().unwrap();
+        for request in [Request::Status,
+                            Request::Join { id: None, addr: addr },
+                            Request::Join { id: Some(3), addr: addr },
+                            Request::Remove { id: 2 },
+                            Request::Transfer { id: 3 },
+                            Request::Blob { hash: [7; 32] },
+                            Request::Verify,
+                            Request::Summary { index: 9, path: PathBuf::from("/a/b") }] {
+            assert_eq!(Request::decode(&request.encode()), Ok(request));
+        }
+        let status = Status {
+            id: 1,
+            role: Role::Leader,
+            term: 4,
+            leader: Some(1),
+            quorum: true,
+            commit_index: 17,
+            applied: 16,
+            members: vec![Member { id: 1, addr: "10.0.0.1:7420".to_string() },
+                          Member { id: 2, addr: String::new() }],
+        };
+        for response in vec![Response::Status(status),
+                             Response::Removed,
+                             Response::Transferring,
+                             Response::Blob(vec![1, 2, 3]),
+                             Response::Verified { index: 9, report: vec!["node 2: odd".to_string()] },
+                             Response::Summary(Item {
+                                 hash: [1; 32],
+                                 own: [2; 32],
+                                 children: Some(vec![(OsString::from("x"), [3; 32])]),
+                             }),
+                             Response::Joined { id: 3, peers: vec![(1, addr), (2, addr)] },
+                             Response::Redirect(None),
+                             Response::Redirect(Some(addr)),
+                             Response::Refused("no".to_string())] {
+            assert_eq!(Response::decode(&response.encode()), Ok(response));
+        }
+        assert_eq!(Request::decode(&[9]), Err(DecodeError::UnknownTag(9)));
+    }
+}
@@ -0,0 +1,963 @@
+// Archive :: Whole-filesystem snapshots, for bringing new nodes up to date.
+//
+// When a follower needs entries the leader no longer has in its log, the
+// leader sends it an archive of the backing directory as it stood at some
+// log index, and then the entries after that.  Taking the archive uses the
+// same copy-on-write machinery as user snapshots: we create the hidden
+// snapshot .snapshots/.raftfs-INDEX while nothing else is being applied,
+// and can then pack it up at leisure while the live tree moves on.
+//
+// An archive is a header (magic, index, term), then the state machine's
+// applied file as of the index, followed by records, one per directory
+// entry, each parents first.  A regular file's record is
+// followed by its contents.  A name for something that already has one in
+// the archive is a link record, followed by that earlier name, so files
+// with several names keep them all.  A later record for a path replaces an
+// earlier one, which lets us correct a file that changed under us while
+// we were reading it.
+//
+// Users' snapshots are part of the tree, as every node has them, so they
+// go in too, just as they stood at the index, apart from the copies of
+// hard-linked files each keeps under the inode numbers of the live tree
+// (see snapshot.rs).  Those numbers mean nothing on another node, so each
+// such copy goes instead under .snapshots/NAME/.raftfs/links/PATH, where
+// PATH is a name the file has in the live tree, and is filed under its
+// new inode number once the archive has been unpacked.
+//
+// TARGET/.raftfs/snapshot holds the archive we would send.  An archive
+// being received goes in TARGET/.raftfs/incoming until it is complete.
+//
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::codec::{self, Reader};
+use super::libc_extras::libc;
+use super::raft::{LogIndex, SnapshotStore, Term};
+use super::snapshot;
+use super::state_machine::{applied_path, META_DIR};
+
+const MAGIC: &[u8; 8] = b"raftsnp3";
+const HEADER: u64 = 24;
+
+/// The largest applied file we will believe.
+const MAX_APPLIED: usize = 16 << 20;
+
+const END: u8 = 0;
+const DIR: u8 = 1;
+const FILE: u8 = 2;
+const SYMLINK: u8 = 3;
+const NODE: u8 = 4;
+const LINK: u8 = 5;
+
+/// The archive a node would send, and the one it loads after receiving it.
+pub fn current_path(target: &Path) -> PathBuf {
+    target.join(META_DIR).join("snapshot")
+}
+
+/// The name in .snapshots of the snapshot an archive is packed from.
+pub fn snapshot_name(index: LogIndex) -> String {
+    format!("{}-{}", META_DIR, index)
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn cstr(path: &Path) -> CString {
+    unsafe { CString::from_vec_unchecked(path.as_os_str().as_bytes().to_vec()) }
+}
+
+fn read_header(path: &Path) -> io::Result<(LogIndex, Term)> {
+    let mut header = [0; HEADER as usize];
+    File::open(path)?.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(invalid(format!("{:?} is not a raftfs snapshot", path)));
+    }
+    let mut r = Reader::new(&header[8..]);
+    Ok((r.u64().unwrap(), r.u64().unwrap()))
+}
+
+/// What an entry in the archive is, and the metadata it should end up with.
+struct Record {
+    kind: u8,
+    path: PathBuf,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: (i64, u32),
+}
+
+fn put_record(w: &mut dyn Write, record: &Record) -> io::Result<()> {
+    let mut out = Vec::new();
+    codec::put_u8(&mut out, record.kind);
+    codec::put_os(&mut out, record.path.as_os_str());
+    codec::put_u32(&mut out, record.mode);
+    codec::put_u32(&mut out, record.uid);
+    codec::put_u32(&mut out, record.gid);
+    codec::put_u64(&mut out, record.mtime.0 as u64);
+    codec::put_u32(&mut out, record.mtime.1);
+    w.write_all(&out)
+}
+
+fn get<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; n];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn get_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    Ok(Reader::new(&get(r, 4)?).u32().unwrap())
+}
+
+fn get_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    Ok(Reader::new(&get(r, 8)?).u64().unwrap())
+}
+
+fn get_os<R: Read>(r: &mut R) -> io::Result<OsString> {
+    let n = get_u32(r)? as usize;
+    if n > libc::PATH_MAX as usize {
+        return Err(invalid(format!("name of {} bytes in snapshot", n)));
+    }
+    Ok(OsString::from_vec(get(r, n)?))
+}
+
+fn get_path<R: Read>(r: &mut R) -> io::Result<PathBuf> {
+    let path = PathBuf::from(get_os(r)?);
+    // This came over the network, so it had better stay inside the target.
+    if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(invalid(format!("bad path {:?} in snapshot", path)));
+    }
+    Ok(path)
+}
+
+/// Read the next record, or None at the end of the archive.
+fn get_record<R: Read>(r: &mut R) -> io::Result<Option<Record>> {
+    let kind = get(r, 1)?[0];
+    if kind == END {
+        return Ok(None);
+    }
+    if kind > LINK {
+        return Err(invalid(format!("unknown record {} in snapshot", kind)));
+    }
+    let path = get_path(r)?;
+    Ok(Some(Record {
+        kind: kind,
+        path: path,
+        mode: get_u32(r)?,
+        uid: get_u32(r)?,
+        gid: get_u32(r)?,
+        mtime: (get_u64(r)? as i64, get_u32(r)?),
+    }))
+}
+
+/// Write an archive of the backing directory `target` as it was when the
+/// snapshot `snap` was taken, along with `applied`, the contents of the
+/// applied file at that point.
+pub fn pack(target: &Path, snap: &Path, out: &Path, index: LogIndex, term: Term,
+            applied: &[u8]) -> io::Result<u64> {
+    let file = File::create(out)?;
+    {
+        let mut w = BufWriter::new(&file);
+        let mut header = MAGIC.to_vec();
+        codec::put_u64(&mut header, index);
+        codec::put_u64(&mut header, term);
+        codec::put_bytes(&mut header, applied);
+        w.write_all(&header)?;
+        let mut walk = Walk { wanted: linked_inodes(target, snap)?, found: HashMap::new(), sent: HashMap::new() };
+        pack_dir(&mut w, target, snap, Path::new(""), &mut walk)?;
+        // The users' snapshots go last, once we know where the files they
+        // keep linked copies of live.
+        pack_dir(&mut w, target, snap, Path::new(".snapshots"), &mut walk)?;
+        w.write_all(&[END])?;
+        w.flush()?;
+    }
+    file.sync_all()?;
+    Ok(file.metadata()?.len())
+}
+
+fn names(dir: &Path) -> io::Result<Vec<OsString>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|e| e.map(|e| e.file_name())).collect(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTDIR) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// What we learn about the tree as we walk it.
+struct Walk {
+    // The inode numbers users' snapshots keep linked copies under.
+    wanted: HashSet<u64>,
+    // A path in the live tree for each of those we have come across.
+    found: HashMap<u64, PathBuf>,
+    // The first name we sent for each file with more than one.
+    sent: HashMap<u64, PathBuf>,
+}
+
+impl Walk {
+    /// Send `source` (whose metadata is `meta`) as `path`, or just a link
+    /// to it if we sent the same file under another name already.
+    fn send(&mut self, w: &mut dyn Write, source: &Path, path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+        if meta.is_dir() || meta.nlink() < 2 {
+            return pack_entry(w, source, path, meta);
+        }
+        match self.sent.get(&meta.ino()) {
+            Some(first) => {
+                put_record(w, &Record { kind: LINK, path: path.to_path_buf(), mode: meta.mode(), uid: meta.uid(),
+                                        gid: meta.gid(), mtime: (meta.mtime(), meta.mtime_nsec() as u32) })?;
+                let mut extra = Vec::new();
+                codec::put_os(&mut extra, first.as_os_str());
+                w.write_all(&extra)
+            },
+            None => {
+                self.sent.insert(meta.ino(), path.to_path_buf());
+                pack_entry(w, source, path, meta)
+            },
+        }
+    }
+}
+
+/// The users' snapshots as of `snap`: the live ones, and any removed since.
+fn user_snapshots(target: &Path, snap: &Path) -> io::Result<BTreeSet<OsString>> {
+    let mut all = BTreeSet::new();
+    all.extend(names(&target.join(".snapshots"))?);
+    all.extend(names(&snap.join(".snapshots"))?);
+    Ok(all.into_iter().filter(|n| !n.to_string_lossy().starts_with(META_DIR)).collect())
+}
+
+fn links_dir(snapshot: &Path) -> PathBuf {
+    snapshot.join(META_DIR).join("links")
+}
+
+fn linked_inodes(target: &Path, snap: &Path) -> io::Result<HashSet<u64>> {
+    let mut wanted = HashSet::new();
+    for name in user_snapshots(target, snap)? {
+        for dir in &[target, snap] {
+            let links = links_dir(&dir.join(".snapshots").join(&name));
+            wanted.extend(names(&links)?.iter().filter_map(|n| n.to_str().and_then(|n| n.parse::<u64>().ok())));
+        }
+    }
+    Ok(wanted)
+}
+
+fn pack_dir(w: &mut dyn Write, target: &Path, snap: &Path, rel: &Path, walk: &mut Walk) -> io::Result<()> {
+    // Directories are the union of what is live and what the snapshot
+    // saved, so that is what we walk.
+    let mut all = BTreeSet::new();
+    all.extend(names(&target.join(rel))?);
+    all.extend(names(&snap.join(rel))?);
+    let at_root = rel == Path::new("");
+    let in_snapshots = rel == Path::new(".snapshots");
+    let in_a_snapshot = rel.parent() == Some(Path::new(".snapshots"));
+    for name in all {
+        if (at_root || in_a_snapshot) && name == OsStr::new(META_DIR) {
+            continue;
+        }
+        if in_snapshots && name.to_string_lossy().starts_with(META_DIR) {
+            continue; // the snapshots we take for ourselves, this one included
+        }
+        let path = rel.join(&name);
+        let live = target.join(&path);
+        let saved = snap.join(&path);
+        let source = match saved.symlink_metadata() {
+            Ok(ref m) if m.file_type().is_socket() => continue, // a whiteout
+            Ok(ref m) if m.is_dir() && live.is_dir() => live.clone(),
+            Ok(_) => saved.clone(),
+            Err(_) => snapshot::linked_copy_of(snap, &live).unwrap_or_else(|| live.clone()),
+        };
+        let meta = match source.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue, // gone already
+            Err(e) => return Err(e),
+        };
+        if source != saved && !path.starts_with(".snapshots") {
+            // The name is as it was, so the file it names is too.
+            if let Ok(m) = live.symlink_metadata() {
+                if walk.wanted.contains(&m.ino()) {
+                    walk.found.entry(m.ino()).or_insert_with(|| path.clone());
+                }
+            }
+        }
+        walk.send(w, &source, &path, &meta)?;
+        if meta.is_file() && source == live {
+            // It was saved while we were reading it, so what we read may
+            // be a mixture.  Send the saved copy too, which replaces it.
+            let saved = match saved.symlink_metadata() {
+                Ok(_) => Some(saved),
+                Err(_) => snapshot::linked_copy_of(snap, &live),
+            };
+            if let Some(saved) = saved {
+                let meta = saved.symlink_metadata()?;
+                pack_entry(w, &saved, &path, &meta)?;
+                if meta.nlink() > 1 {
+                    // Its other names are saved as links to this.
+                    walk.sent.entry(meta.ino()).or_insert_with(|| path.clone());
+                }
+            }
+        }
+        if meta.is_dir() && !(at_root && name == OsStr::new(".snapshots")) {
+            pack_dir(w, target, snap, &path, walk)?;
+        }
+        if meta.is_dir() && in_snapshots {
+            pack_links(w, &source, &path, walk)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send the linked copies kept by the snapshot at `source`, which is
+/// `rel` in the tree, under the paths of the files they are copies of.
+fn pack_links(w: &mut dyn Write, source: &Path, rel: &Path, walk: &mut Walk) -> io::Result<()> {
+    let dir = links_dir(source);
+    let mut sent = HashSet::new();
+    for name in names(&dir)? {
+        let path = match name.to_str().and_then(|n| n.parse().ok()).and_then(|ino| walk.found.get(&ino)) {
+            Some(path) => path.clone(),
+            None => continue, // nothing in the live tree is that file now
+        };
+        let copy = dir.join(&name);
+        let meta = match copy.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue, // removed already
+            Err(e) => return Err(e),
+        };
+        // The directories on the way, parents first.
+        let keyed = links_dir(rel).join(&path);
+        let mut on_the_way: Vec<&Path> = keyed.ancestors().skip(1).take_while(|a| *a != rel).collect();
+        on_the_way.reverse();
+        for at in on_the_way {
+            if sent.insert(at.to_path_buf()) {
+                let from = if at.starts_with(links_dir(rel)) { dir.clone() } else { source.join(META_DIR) };
+                pack_entry(w, &from, at, &from.symlink_metadata()?)?;
+            }
+        }
+        walk.send(w, &copy, &keyed, &meta)?;
+    }
+    Ok(())
+}
+
+/// File the linked copies in each snapshot under `target` by the inode
+/// numbers of the files they are copies of here.
+fn rekey_links(target: &Path) -> io::Result<()> {
+    for name in names(&target.join(".snapshots"))? {
+        let links = links_dir(&target.join(".snapshots").join(name));
+        if !links.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let keyed = links.with_extension("new");
+        fs::create_dir(&keyed)?;
+        rekey(target, &links, Path::new(""), &keyed)?;
+        fs::remove_dir_all(&links)?;
+        fs::rename(&keyed, &links)?;
+    }
+    Ok(())
+}
+
+fn rekey(target: &Path, links: &Path, rel: &Path, keyed: &Path) -> io::Result<()> {
+    for name in names(&links.join(rel))? {
+        let path = rel.join(&name);
+        let copy = links.join(&path);
+        if copy.symlink_metadata()?.is_dir() {
+            rekey(target, links, &path, keyed)?;
+        } else if let Ok(meta) = target.join(&path).symlink_metadata() {
+            fs::rename(&copy, keyed.join(meta.ino().to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+fn pack_entry(w: &mut dyn Write, source: &Path, path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let file_type = meta.file_type();
+    let kind = if file_type.is_dir() {
+        DIR
+    } else if file_type.is_file() {
+        FILE
+    } else if file_type.is_symlink() {
+        SYMLINK
+    } else {
+        NODE
+    };
+    put_record(w, &Record {
+        kind: kind,
+        path: path.to_path_buf(),
+        mode: meta.mode(),
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mtime: (meta.mtime(), meta.mtime_nsec() as u32),
+    })?;
+    let mut extra = Vec::new();
+    match kind {
+        FILE => {
+            // Exactly as many bytes as we promise, even if it changes size.
+            let size = meta.len();
+            codec::put_u64(&mut extra, size);
+            w.write_all(&extra)?;
+            let copied = io::copy(&mut File::open(source)?.take(size), w)?;
+            io::copy(&mut io::repeat(0).take(size - copied), w)?;
+            return Ok(());
+        },
+        SYMLINK => codec::put_os(&mut extra, fs::read_link(source)?.as_os_str()),
+        NODE => codec::put_u64(&mut extra, meta.rdev()),
+        _ => (),
+    }
+    w.write_all(&extra)
+}
+
+/// Replace the contents of `target` (apart from our metadata) with the
+/// archive at `archive`, returning the index and term it was taken at and
+/// the applied file that came with it.
+pub fn unpack(target: &Path, archive: &Path) -> io::Result<(LogIndex, Term, Vec<u8>)> {
+    let (index, term) = read_header(archive)?;
+    info!("loading snapshot through {} into {:?}", index, target);
+    for name in names(target)? {
+        if name == OsStr::new(META_DIR) {
+            continue;
+        }
+        let path = target.join(&name);
+        if path.symlink_metadata()?.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    let mut r = BufReader::new(File::open(archive)?);
+    get(&mut r, HEADER as usize)?;
+    let n = get_u32(&mut r)? as usize;
+    if n > MAX_APPLIED {
+        return Err(invalid(format!("applied file of {} bytes in snapshot", n)));
+    }
+    let applied = get(&mut r, n)?;
+    let mut dirs = Vec::new();
+    // The directories this archive made, which are the only places it may
+    // put anything: a symlink in the way could lead anywhere.
+    let mut made = BTreeSet::new();
+    // And what else it made, which is all a link may be made to.
+    let mut others = HashSet::new();
+    while let Some(record) = get_record(&mut r)? {
+        if record.path == Path::new("") || record.path.starts_with(META_DIR) {
+            return Err(invalid(format!("snapshot may not hold {:?}", record.path)));
+        }
+        match record.path.parent() {
+            Some(parent) if parent != Path::new("") && !made.contains(parent) => {
+                return Err(invalid(format!("{:?} in snapshot is not in a directory", record.path)));
+            },
+            _ => (),
+        }
+        let path = target.join(&record.path);
+        match path.symlink_metadata() {
+            Ok(ref m) if m.is_dir() && record.kind == DIR => (),
+            Ok(ref m) if m.is_dir() => fs::remove_dir_all(&path)?,
+            Ok(_) => fs::remove_file(&path)?,
+            Err(_) => (),
+        }
+        others.remove(&record.path);
+        if record.kind != DIR && made.contains(&record.path) {
+            made.retain(|d: &PathBuf| !d.starts_with(&record.path));
+            others.retain(|o: &PathBuf| !o.starts_with(&record.path));
+        }
+        match record.kind {
+            DIR => {
+                if !path.is_dir() {
+                    fs::create_dir(&path)?;
+                }
+                made.insert(record.path.clone());
+                dirs.push(record);
+                continue;
+            },
+            FILE => {
+                let size = get_u64(&mut r)?;
+                let mut f = File::create(&path)?;
+                if io::copy(&mut (&mut r).take(size), &mut f)? != size {
+                    return Err(invalid(format!("snapshot ends inside {:?}", record.path)));
+                }
+            },
+            SYMLINK => ::std::os::unix::fs::symlink(get_os(&mut r)?, &path)?,
+            LINK => {
+                let first = get_path(&mut r)?;
+                if !others.contains(&first) {
+                    return Err(invalid(format!("{:?} in snapshot links to {:?}, which it lacks",
+                                               record.path, first)));
+                }
+                fs::hard_link(target.join(&first), &path)?;
+                others.insert(record.path);
+                continue; // it has the metadata of the first already
+            },
+            _ => {
+                let rdev = get_u64(&mut r)?;
+                if unsafe { libc::mknod(cstr(&path).as_ptr(), record.mode, rdev) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            },
+        }
+        set_metadata(target, &record)?;
+        others.insert(record.path);
+    }
+    // Filling a directory changes its mtime, so those go last.
+    for record in dirs.iter().rev() {
+        if made.contains(&record.path) {
+            set_metadata(target, record)?;
+        }
+    }
+    rekey_links(target)?;
+    let dir = File::open(target)?;
+    if unsafe { libc::syncfs(::std::os::unix::io::AsRawFd::as_raw_fd(&dir)) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((index, term, applied))
+}
+
+fn set_metadata(target: &Path, record: &Record) -> io::Result<()> {
+    let path = cstr(&target.join(&record.path));
+    unsafe {
+        // Only root may give files away; anybody else keeps them.
+        if libc::lchown(path.as_ptr(), record.uid, record.gid) == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EPERM) {
+                return Err(e);
+            }
+        }
+        if record.kind != SYMLINK && libc::chmod(path.as_ptr(), record.mode & 0o7777) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let times = [
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            libc::timespec { tv_sec: record.mtime.0 as libc::time_t,
+                             tv_nsec: record.mtime.1 as libc::c_long },
+        ];
+        if libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(),
+                           libc::AT_SYMLINK_NOFOLLOW) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// An archive being packed in the background.
+struct Packing {
+    index: LogIndex,
+    term: Term,
+    // Set once packing is over.
+    result: Arc<Mutex<Option<io::Result<u64>>>>,
+    // We received a newer snapshot meanwhile, so this one is not wanted.
+    discard: bool,
+}
+
+/// Keeps archives in the metadata directory of the backing directory.
+pub struct ArchiveStore {
+    target: PathBuf,
+    current: Option<(LogIndex, Term, u64)>,
+    packing: Option<Packing>,
+    // The index of the archive being received, and how much we have.
+    incoming: Option<(LogIndex, u64)>,
+}
+
+impl ArchiveStore {
+    pub fn open(target: PathBuf) -> io::Result<ArchiveStore> {
+        // Whatever we were packing when we stopped is no use now.
+        for name in names(&target.join(".snapshots"))? {
+            if name.to_string_lossy().starts_with(&format!("{}-", META_DIR)) {
+                snapshot::remove_tree(&target.join(".snapshots").join(name))?;
+            }
+        }
+        let path = current_path(&target);
+        let current = match read_header(&path) {
+            Ok((index, term)) => Some((index, term, path.metadata()?.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(ArchiveStore { target: target, current: current, packing: None, incoming: None })
+    }
+
+    fn meta(&self, name: &str) -> PathBuf {
+        self.target.join(META_DIR).join(name)
+    }
+
+    /// Make the archive at `from` the current one.
+    fn promote(&mut self, from: &Path, index: LogIndex, term: Term) -> io::Result<()> {
+        fs::rename(from, current_path(&self.target))?;
+        File::open(self.target.join(META_DIR))?.sync_all()?;
+        self.current = Some((index, term, current_path(&self.target).metadata()?.len()));
+        Ok(())
+    }
+}
+
+impl SnapshotStore for ArchiveStore {
+    fn current(&mut self) -> Option<(LogIndex, Term, u64)> {
+        let finished = self.packing.as_ref()
+            .and_then(|p| p.result.lock().unwrap().take().map(|r| (p.index, p.term, p.discard, r)));
+        if let Some((index, term, discard, result)) = finished {
+            self.packing = None;
+            // We hold the node's lock, so nothing is being saved into it.
+            let snap = self.target.join(".snapshots").join(snapshot_name(index));
+            if let Err(e) = snapshot::remove_tree(&snap) {
+                error!("unable to remove {:?}: {}", snap, e);
+            }
+            let packed = self.meta("snapshot.tmp");
+            match result {
+                Ok(_) if !discard => {
+                    match self.promote(&packed, index, term) {
+                        Ok(()) => info!("snapshot through {} is ready to send", index),
+                        Err(e) => error!("unable to keep snapshot through {}: {}", index, e),
+                    }
+                },
+                Ok(_) => (),
+                Err(e) => error!("unable to pack snapshot through {}: {}", index, e),
+            }
+            fs::remove_file(&packed).ok();
+        }
+        self.current
+    }
+
+    fn take(&mut self, index: LogIndex, term: Term) {
+        if self.packing.is_some() {
+            return;
+        }
+        let snapshots = self.target.join(".snapshots");
+        let snap = snapshots.join(snapshot_name(index));
+        let result = fs::create_dir_all(&snapshots).and_then(|_| fs::create_dir(&snap));
+        if let Err(e) = result {
+            error!("unable to take snapshot {:?}: {}", snap, e);
+            return;
+        }
+        info!("packing snapshot through {}", index);
+        let result = Arc::new(Mutex::new(None));
+        self.packing = Some(Packing { index: index, term: term, result: result.clone(), discard: false });
+        // Nothing is applied while we hold the node's lock, so this goes
+        // with the snapshot we just took.
+        let applied = fs::read(applied_path(&self.target)).unwrap_or_else(|e| {
+            warn!("unable to read the applied file for the snapshot: {}", e);
+            Vec::new()
+        });
+        let target = self.target.clone();
+        let out = self.meta("snapshot.tmp");
+        thread::spawn(move || {
+            let packed = pack(&target, &snap, &out, index, term, &applied);
+            *result.lock().unwrap() = Some(packed);
+        });
+    }
+
+    fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        File::open(current_path(&self.target))?.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn receive(&mut self, index: LogIndex, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let path = self.meta("incoming");
+        if offset == 0 {
+            File::create(&path)?;
+            self.incoming = Some((index, 0));
+        }
+        match self.incoming {
+            Some((i, have)) if i == index && have == offset => {
+                OpenOptions::new().write(true).open(&path)?.write_all_at(data, offset)?;
+                let have = offset + data.len() as u64;
+                self.incoming = Some((index, have));
+                Ok(have)
+            },
+            Some((i, have)) if i == index => Ok(have),
+            _ => Ok(0),
+        }
+    }
+
+    fn finish(&mut self, index: LogIndex, term: Term) -> io::Result<()> {
+        let path = self.meta("incoming");
+        File::open(&path)?.sync_all()?;
+        if read_header(&path)? != (index, term) {
+            return Err(invalid(format!("snapshot received as {} is not what it claims", index)));
+        }
+        if let Some(ref mut packing) = self.packing {
+            packing.discard = true;
+        }
+        self.incoming = None;
+        self.promote(&path, index, term)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-archive-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join(META_DIR)).unwrap();
+        dir
+    }
+
+    fn populate(dir: &Path) {
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file"), b"contents").unwrap();
+        fs::write(dir.join("top"), b"").unwrap();
+        ::std::os::unix::fs::symlink("a/b/file", dir.join("link")).unwrap();
+        fs::create_dir(dir.join(".snapshots")).unwrap();
+        fs::set_permissions(dir.join("a/b/file"),
+                            ::std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+    }
+
+    #[test]
+    fn unpack_reproduces_what_was_packed() {
+        let from = tempdir("from");
+        let to = tempdir("to");
+        populate(&from);
+        fs::write(to.join("stale"), b"goes away").unwrap();
+        fs::write(to.join(META_DIR).join("applied"), b"3\n").unwrap();
+
+        let archive = from.join(META_DIR).join("packed");
+        let snap = from.join(".snapshots").join(snapshot_name(7));
+        fs::create_dir(&snap).unwrap();
+        pack(&from, &snap, &archive, 7, 2, b"7\n").unwrap();
+        fs::remove_dir(&snap).unwrap();
+        assert_eq!(unpack(&to, &archive).unwrap(), (7, 2, b"7\n".to_vec()));
+
+        assert!(!to.join("stale").exists());
+        assert_eq!(fs::read(to.join(META_DIR).join("applied")).unwrap(), b"3\n");
+        assert_eq!(fs::read(to.join("a/b/file")).unwrap(), b"contents");
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), PathBuf::from("a/b/file"));
+        assert!(to.join(".snapshots").is_dir());
+        let (a, b) = (from.join("a/b/file").metadata().unwrap(), to.join("a/b/file").metadata().unwrap());
+        assert_eq!((a.mode(), a.mtime(), a.mtime_nsec()), (b.mode(), b.mtime(), b.mtime_nsec()));
+        let (a, b) = (from.join("a").metadata().unwrap(), to.join("a").metadata().unwrap());
+        assert_eq!((a.mode(), a.mtime(), a.mtime_nsec()), (b.mode(), b.mtime(), b.mtime_nsec()));
+    }
+
+    #[test]
+    fn packs_the_tree_as_of_the_snapshot() {
+        let from = tempdir("cow");
+        let to = tempdir("cow-to");
+        populate(&from);
+        let snap = from.join(".snapshots").join(snapshot_name(3));
+        fs::create_dir(&snap).unwrap();
+        // After the snapshot: top is replaced (and saved first), and new
+        // is created (with a whiteout).
+        fs::copy(from.join("top"), snap.join("top")).unwrap();
+        fs::write(from.join("top"), b"changed").unwrap();
+        unsafe { libc::mknod(cstr(&snap.join("new")).as_ptr(), libc::S_IFSOCK, 0) };
+        fs::write(from.join("new"), b"too late").unwrap();
+
+        let archive = from.join(META_DIR).join("packed");
+        pack(&from, &snap, &archive, 3, 1, b"").unwrap();
+        unpack(&to, &archive).unwrap();
+        assert_eq!(fs::read(to.join("top")).unwrap(), b"");
+        assert!(!to.join("new").exists());
+        assert!(!to.join(".snapshots").join(snapshot_name(3)).exists());
+    }
+
+    /// An archive at `archive` holding `records`, each followed by `extra`.
+    fn write_archive(archive: &Path, records: Vec<(Record, Vec<u8>)>) {
+        let mut data = MAGIC.to_vec();
+        codec::put_u64(&mut data, 1);
+        codec::put_u64(&mut data, 1);
+        codec::put_bytes(&mut data, b"");
+        for (record, extra) in records {
+            put_record(&mut data, &record).unwrap();
+            data.extend(extra);
+        }
+        data.push(END);
+        fs::write(archive, &data).unwrap();
+    }
+
+    fn record(kind: u8, path: &str, mode: u32) -> Record {
+        Record { kind: kind, path: PathBuf::from(path), mode: mode, uid: 0, gid: 0, mtime: (0, 0) }
+    }
+
+    #[test]
+    fn users_snapshots_go_along() {
+        use super::super::fsop::FsOp;
+        use super::super::merkle;
+        use super::super::state_machine::StateMachine;
+        let (from, to) = (tempdir("users"), tempdir("users-to"));
+        let mkdir = |parent: &str, name: &str| {
+            FsOp::Mkdir { parent: PathBuf::from(parent), name: OsString::from(name), mode: 0o755 }
+        };
+        let write = |path: &str, data: &[u8]| {
+            FsOp::Write { path: PathBuf::from(path), handle: None, offset: 0, data: data.to_vec() }
+        };
+        let before = vec![
+            mkdir("/", ".snapshots"),
+            mkdir("/", "d"),
+            FsOp::Create { parent: PathBuf::from("/d"), name: OsString::from("f"), mode: 0o644,
+                           flags: libc::O_WRONLY as u32, handle: None },
+            write("/d/f", b"hello"),
+            FsOp::Link { path: PathBuf::from("/d/f"), newparent: PathBuf::from("/d"), newname: OsString::from("g") },
+            mkdir("/.snapshots", "u"),
+            mkdir("/.snapshots", "v"),
+            // Saves f in both, along with a copy for g, which is the same file.
+            write("/d/f", b"HELLO"),
+            mkdir("/d", "new"),
+        ];
+        // What happens while the archive is being packed.
+        let after = [
+            write("/d/g", b"later"),
+            mkdir("/.snapshots", "w"),
+            FsOp::Rmdir { parent: PathBuf::from("/.snapshots"), name: OsString::from("v") },
+        ];
+        let mut sm = StateMachine::open(from.clone()).unwrap();
+        for (i, op) in before.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+        }
+        let snap = from.join(".snapshots").join(snapshot_name(9));
+        fs::create_dir(&snap).unwrap();
+        for (i, op) in after.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 10, op), Ok(()));
+        }
+        let archive = from.join(META_DIR).join("packed");
+        pack(&from, &snap, &archive, 9, 1, b"").unwrap();
+        unpack(&to, &archive).unwrap();
+
+        // As of the archive, v was still there and w was not yet.
+        let snapshots = to.join(".snapshots");
+        assert!(snapshots.join("v").is_dir() && !snapshots.join("w").exists());
+        assert_eq!(fs::read(to.join("d/g")).unwrap(), b"HELLO");
+        assert_eq!(fs::read(snapshots.join("u/d/f")).unwrap(), b"hello");
+        assert!(snapshots.join("u/d/new").symlink_metadata().unwrap().file_type().is_socket());
+        let linked = snapshot::linked_copy_of(&snapshots.join("u"), &to.join("d/g")).unwrap();
+        assert_eq!(fs::read(linked).unwrap(), b"hello");
+
+        // Catching up from there leaves the two alike.
+        let mut sm = StateMachine::open(to.clone()).unwrap();
+        for (i, op) in after.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 10, op), Ok(()));
+        }
+        let (ours, theirs) = (merkle::summarize(&from).unwrap(), merkle::summarize(&to).unwrap());
+        assert_eq!(merkle::compare(&ours, |p| Ok(theirs.get(p).unwrap().clone())).unwrap(), vec![]);
+        assert_eq!(fs::read(snapshots.join("u/d/g")).unwrap(), b"hello");
+        fs::remove_dir_all(&from).ok();
+        fs::remove_dir_all(&to).ok();
+    }
+
+    #[test]
+    fn removed_snapshots_stay_in_every_archive() {
+        use super::super::fsop::FsOp;
+        use super::super::state_machine::StateMachine;
+        let from = tempdir("removed");
+        let mkdir = |parent: &str, name: &str| {
+            FsOp::Mkdir { parent: PathBuf::from(parent), name: OsString::from(name), mode: 0o755 }
+        };
+        let write = |path: &str, data: &[u8]| {
+            FsOp::Write { path: PathBuf::from(path), handle: None, offset: 0, data: data.to_vec() }
+        };
+        let ops = [
+            mkdir("/", ".snapshots"),
+            FsOp::Create { parent: PathBuf::from("/"), name: OsString::from("f"), mode: 0o644,
+                           flags: libc::O_WRONLY as u32, handle: None },
+            write("/f", b"hello"),
+            FsOp::Link { path: PathBuf::from("/f"), newparent: PathBuf::from("/"), newname: OsString::from("g") },
+            mkdir("/.snapshots", "v"),
+            write("/f", b"HELLO"),
+        ];
+        let mut sm = StateMachine::open(from.clone()).unwrap();
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+        }
+        // Two archives are being packed when v goes.
+        for &index in &[6, 7] {
+            fs::create_dir(from.join(".snapshots").join(snapshot_name(index))).unwrap();
+            let skip = FsOp::Chmod { path: PathBuf::from("/"), mode: 0o755 };
+            assert_eq!(sm.apply(index + 1, &skip), Ok(()));
+        }
+        let rmdir = FsOp::Rmdir { parent: PathBuf::from("/.snapshots"), name: OsString::from("v") };
+        assert_eq!(sm.apply(9, &rmdir), Ok(()));
+        assert!(!from.join(".snapshots").join("v").exists());
+
+        for &index in &[6, 7] {
+            let to = tempdir(&format!("removed-to-{}", index));
+            let snap = from.join(".snapshots").join(snapshot_name(index));
+            let archive = from.join(META_DIR).join("packed");
+            pack(&from, &snap, &archive, index, 1, b"").unwrap();
+            unpack(&to, &archive).unwrap();
+            let v = to.join(".snapshots").join("v");
+            assert_eq!(fs::read(v.join("f")).unwrap(), b"hello");
+            let linked = snapshot::linked_copy_of(&v, &to.join("g")).unwrap();
+            assert_eq!(fs::read(linked).unwrap(), b"hello");
+            fs::remove_dir_all(&to).ok();
+        }
+
+        // The copies go along with the archives.
+        for &index in &[6, 7] {
+            snapshot::remove_tree(&from.join(".snapshots").join(snapshot_name(index))).unwrap();
+        }
+        assert_eq!(fs::read_dir(from.join(".snapshots")).unwrap().count(), 0);
+        assert_eq!(fs::read(from.join("g")).unwrap(), b"HELLO");
+        fs::remove_dir_all(&from).ok();
+    }
+
+    #[test]
+    fn rejects_paths_that_escape() {
+        let to = tempdir("escape");
+        let archive = to.join(META_DIR).join("evil");
+        write_archive(&archive, vec![(record(DIR, "../out", 0o40755), vec![])]);
+        assert!(unpack(&to, &archive).is_err());
+        assert!(!to.parent().unwrap().join("out").exists());
+
+        // Nor may it go through a symlink, even one that was a directory
+        // earlier on, nor link to anything but a file it made itself.
+        let outside = tempdir("escape-outside");
+        let mut link = Vec::new();
+        codec::put_os(&mut link, outside.as_os_str());
+        let mut contents = Vec::new();
+        codec::put_u64(&mut contents, 4);
+        contents.extend_from_slice(b"evil");
+        let mut first = Vec::new();
+        codec::put_os(&mut first, OsStr::new("passwd"));
+        for records in [vec![(record(SYMLINK, "a", 0o120777), link.clone()),
+                             (record(FILE, "a/x", 0o100644), contents.clone())],
+                        vec![(record(DIR, "a", 0o40755), vec![]),
+                             (record(SYMLINK, "a", 0o120777), link.clone()),
+                             (record(FILE, "a/x", 0o100644), contents.clone())],
+                        vec![(record(SYMLINK, "passwd", 0o120777), link.clone()),
+                             (record(DIR, "passwd", 0o40755), vec![]),
+                             (record(LINK, "a", 0o100644), first.clone())]] {
+            write_archive(&archive, records);
+            assert!(unpack(&to, &archive).is_err());
+            assert!(!outside.join("x").exists());
+        }
+        fs::remove_dir_all(&to).ok();
+        fs::remove_dir_all(&outside).ok();
+    }
+
+    #[test]
+    fn store_packs_and_receives() {
+        let from = tempdir("store-from");
+        let to = tempdir("store-to");
+        populate(&from);
+        fs::write(applied_path(&from), b"5\n").unwrap();
+        let mut sender = ArchiveStore::open(from.clone()).unwrap();
+        assert_eq!(sender.current(), None);
+        sender.take(5, 2);
+        assert!(from.join(".snapshots").join(snapshot_name(5)).is_dir());
+        let (index, term, size) = loop {
+            if let Some(current) = sender.current() {
+                break current;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!((index, term), (5, 2));
+        assert!(!from.join(".snapshots").join(snapshot_name(5)).exists());
+
+        let mut receiver = ArchiveStore::open(to.clone()).unwrap();
+        let mut offset = 0;
+        while offset < size {
+            let n = ::std::cmp::min(10, size - offset);
+            let data = sender.read(offset, n as usize).unwrap();
+            // A piece out of order is ignored.
+            assert_eq!(receiver.receive(5, offset + 1, &data).unwrap(), offset);
+            offset = receiver.receive(5, offset, &data).unwrap();
+        }
+        receiver.finish(5, 2).unwrap();
+        assert_eq!(receiver.current(), Some((5, 2, size)));
+        assert_eq!(unpack(&to, &current_path(&to)).unwrap(), (5, 2, b"5\n".to_vec()));
+        assert_eq!(fs::read(to.join("a/b/file")).unwrap(), b"contents");
+        assert_eq!(ArchiveStore::open(to.clone()).unwrap().current(), Some((5, 2, size)));
+    }
+}
@@ -0,0 +1,264 @@
+// Batch :: Gathers small writes into fewer, larger log entries.
+//
+// Through FUSE, copying a big file arrives as thousands of writes of a few
+// KiB each, and a trip through raft for every one would crawl.  So a write
+// to an open file handle is only buffered, and writes that follow on from
+// it (or land inside it) are added to the buffer.  The buffer goes to the
+// log as a single write when it grows large, when it has waited long
+// enough, when the file is flushed, synced or released, and before
+// anything else that could see it or be affected by it: reads and stats of
+// the same file, and every other change to the filesystem, so that changes
+// reach the log in the order they were made.  The same file is the same
+// inode, whatever it is called by now.
+//
+// A failure to commit a buffered write can't be reported by the write
+// call, which has long since returned, so it is reported by the next
+// flush, fsync or release of the handle, just as a kernel reports failed
+// write-back, and by the read or stat that sent it on its way.
+//
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::fsop::{FsOp, Handle};
+use super::libc_extras::libc;
+
+/// Whatever commits an op, returning its result.
+pub type Execute<'a> = &'a dyn Fn(&FsOp) -> Result<(), libc::c_int>;
+
+/// A file open for writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opened {
+    /// The FUSE file handle.
+    pub fh: u64,
+    /// What the log knows the file as, if it has been told.
+    pub handle: Option<Handle>,
+    /// Its inode number, which stays with it through renames.
+    pub ino: u64,
+}
+
+/// Writes to one handle that have yet to go to the log.
+struct Batch {
+    path: PathBuf,
+    file: Opened,
+    offset: u64,
+    data: Vec<u8>,
+    // When the first of these writes was made.
+    since: Instant,
+}
+
+impl Batch {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    fn into_op(self) -> FsOp {
+        FsOp::Write { path: self.path, handle: self.file.handle, offset: self.offset, data: self.data }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    batches: HashMap<u64, Batch>,
+    // Failures of buffered writes, to report for each handle.
+    errors: HashMap<u64, libc::c_int>,
+}
+
+pub struct Batches {
+    state: Mutex<State>,
+    // Held while batches go to the log, so that they go in order.
+    flushing: Mutex<()>,
+    max_size: usize,
+    max_delay: Duration,
+}
+
+impl Batches {
+    /// Batches are flushed once they hold `max_size` bytes or are
+    /// `max_delay` old.
+    pub fn new(max_size: usize, max_delay: Duration) -> Batches {
+        Batches {
+            state: Mutex::new(State::default()),
+            flushing: Mutex::new(()),
+            max_size: max_size,
+            max_delay: max_delay,
+        }
+    }
+
+    /// Buffer a write of `data` at `offset` to `file`, which is at `path`.
+    pub fn write(&self, execute: Execute, file: Opened, path: &Path, offset: u64, data: &[u8]) {
+        let fh = file.fh;
+        let full = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(b) = state.batches.get_mut(&fh) {
+                if b.path == path && offset >= b.offset && offset <= b.end() {
+                    let start = (offset - b.offset) as usize;
+                    let overlap = ::std::cmp::min(data.len(), b.data.len() - start);
+                    b.data[start..start + overlap].copy_from_slice(&data[..overlap]);
+                    b.data.extend_from_slice(&data[overlap..]);
+                    Some(b.data.len() >= self.max_size)
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        };
+        match full {
+            Some(true) => {
+                self.flush_where(execute, |h, _| h == fh).ok();
+            },
+            Some(false) => (),
+            None => {
+                // It doesn't follow on, so what we have goes first.
+                self.flush_where(execute, |h, _| h == fh).ok();
+                let batch = Batch {
+                    path: path.to_owned(),
+                    file: file,
+                    offset: offset,
+                    data: data.to_vec(),
+                    since: Instant::now(),
+                };
+                let full = batch.data.len() >= self.max_size;
+                self.state.lock().unwrap().batches.insert(fh, batch);
+                if full {
+                    self.flush_where(execute, |h, _| h == fh).ok();
+                }
+            },
+        }
+    }
+
+    /// Send what was written through `fh` to the log, and report whether
+    /// every write through it so far has succeeded.
+    pub fn flush_handle(&self, execute: Execute, fh: u64) -> Result<(), libc::c_int> {
+        self.flush_where(execute, |h, _| h == fh).ok();
+        match self.state.lock().unwrap().errors.remove(&fh) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Send whatever was written to the file `ino`, through any handle, to
+    /// the log before it is read, and report whether that succeeded.
+    pub fn flush_file(&self, execute: Execute, ino: u64) -> Result<(), libc::c_int> {
+        self.flush_where(execute, |_, b| b.file.ino == ino)
+    }
+
+    /// Send everything to the log, before some other change is made.
+    pub fn flush_all(&self, execute: Execute) {
+        self.flush_where(execute, |_, _| true).ok();
+    }
+
+    /// Send batches that have waited long enough to the log.
+    pub fn flush_stale(&self, execute: Execute) {
+        let max_delay = self.max_delay;
+        self.flush_where(execute, |_, b| b.since.elapsed() >= max_delay).ok();
+    }
+
+    /// Forget `fh`, which has been released.
+    pub fn forget(&self, fh: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.batches.remove(&fh);
+        state.errors.remove(&fh);
+    }
+
+    /// Send the batches `which` picks to the log, noting any failure
+    /// against its handle, and giving the first.
+    fn flush_where<F: Fn(u64, &Batch) -> bool>(&self, execute: Execute, which: F) -> Result<(), libc::c_int> {
+        let _flushing = self.flushing.lock().unwrap();
+        let mut taken: Vec<(u64, Batch)> = {
+            let mut state = self.state.lock().unwrap();
+            let batches = mem::take(&mut state.batches);
+            let (taken, kept): (HashMap<u64, Batch>, HashMap<u64, Batch>) =
+                batches.into_iter().partition(|&(h, ref b)| which(h, b));
+            state.batches = kept;
+            taken.into_iter().collect()
+        };
+        // Oldest first, which is as close as we can come to the order
+        // they were written in.
+        taken.sort_by_key(|(_, b)| b.since);
+        let mut result = Ok(());
+        for (fh, batch) in taken {
+            debug!("flushing {:#x} bytes @ {:#x} to {:?}", batch.data.len(), batch.offset, batch.path);
+            if let Err(e) = execute(&batch.into_op()) {
+                self.state.lock().unwrap().errors.entry(fh).or_insert(e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn write(path: &str, offset: u64, data: &[u8]) -> FsOp {
+        FsOp::Write { path: PathBuf::from(path), handle: Some(7), offset: offset, data: data.to_vec() }
+    }
+
+    #[test]
+    fn contiguous_writes_become_one() {
+        let log = RefCell::new(Vec::new());
+        let execute = |op: &FsOp| { log.borrow_mut().push(op.clone()); Ok(()) };
+        let batches = Batches::new(10, Duration::from_secs(60));
+        let f = Path::new("/f");
+        let one = Opened { fh: 1, handle: Some(7), ino: 10 };
+        batches.write(&execute, one, f, 0, b"abc");
+        batches.write(&execute, one, f, 3, b"def");
+        // Overwriting part of the batch, and going on past its end.
+        batches.write(&execute, one, f, 5, b"XY");
+        assert!(log.borrow().is_empty());
+        // Another handle has a batch of its own.
+        batches.write(&execute, Opened { fh: 2, handle: Some(8), ino: 10 }, f, 100, b"z");
+        assert_eq!(batches.flush_file(&execute, 11), Ok(()));
+        assert!(log.borrow().is_empty());
+        assert_eq!(batches.flush_handle(&execute, 1), Ok(()));
+        assert_eq!(*log.borrow(), vec![write("/f", 0, b"abcdeXY")]);
+
+        // A write that doesn't follow on sends the batch ahead of it.
+        batches.write(&execute, one, f, 0, b"0123");
+        batches.write(&execute, one, f, 50, b"5");
+        assert_eq!(log.borrow()[1], write("/f", 0, b"0123"));
+        // Reaching the size limit sends it at once.
+        batches.write(&execute, one, f, 51, b"6789abcdef");
+        assert_eq!(log.borrow()[2], write("/f", 50, b"56789abcdef"));
+        batches.flush_all(&execute);
+        assert_eq!(log.borrow()[3..],
+                   [FsOp::Write { path: PathBuf::from("/f"), handle: Some(8), offset: 100, data: b"z".to_vec() }]);
+    }
+
+    #[test]
+    fn reads_see_writes_through_every_name() {
+        let log = RefCell::new(Vec::new());
+        let execute = |op: &FsOp| { log.borrow_mut().push(op.clone()); Ok(()) };
+        let batches = Batches::new(1 << 20, Duration::from_secs(60));
+        batches.write(&execute, Opened { fh: 1, handle: Some(7), ino: 10 }, Path::new("/f"), 0, b"abc");
+        batches.write(&execute, Opened { fh: 2, handle: None, ino: 11 }, Path::new("/g"), 0, b"def");
+        // Whatever name it is found by now, it is the same file.
+        assert_eq!(batches.flush_file(&execute, 10), Ok(()));
+        assert_eq!(*log.borrow(), vec![write("/f", 0, b"abc")]);
+    }
+
+    #[test]
+    fn failures_are_reported_later() {
+        let execute = |_: &FsOp| Err(libc::EROFS);
+        let batches = Batches::new(1 << 20, Duration::from_millis(0));
+        let f = Path::new("/f");
+        batches.write(&execute, Opened { fh: 1, handle: None, ino: 10 }, f, 0, b"abc");
+        batches.flush_stale(&execute);
+        assert_eq!(batches.flush_handle(&execute, 2), Ok(()));
+        assert_eq!(batches.flush_handle(&execute, 1), Err(libc::EROFS));
+        // Once only.
+        assert_eq!(batches.flush_handle(&execute, 1), Ok(()));
+
+        // Sent on its way by a read, a failure is reported to the reader
+        // as well.
+        batches.write(&execute, Opened { fh: 1, handle: None, ino: 10 }, f, 0, b"abc");
+        assert_eq!(batches.flush_file(&execute, 10), Err(libc::EROFS));
+        assert_eq!(batches.flush_handle(&execute, 1), Err(libc::EROFS));
+    }
+}
@@ -0,0 +1,156 @@
+// Blob :: Large write data, kept beside the log rather than in it.
+//
+// A write bigger than BLOB_MIN is stored as a blob named by the SHA-256 of
+// its contents, in TARGET/.raftfs/blobs/HASH, and what goes into the log is
+// an FsOp::WriteBlob carrying only the hash.  That keeps the log, and the
+// messages that replicate it, small, however much is written.
+//
+// The node that proposed the write has the blob from the start.  Any other
+// node that comes to apply the entry and finds the blob missing fetches it
+// from a peer first, checking that its hash is right; until it has it, the
+// entry and everything after it wait.
+//
+// A blob is needed for as long as an entry naming it is still in the log,
+// so once the log has been compacted, every blob that no remaining entry
+// names is deleted.  A node that lagged so far behind that everybody else
+// has compacted away an entry it has yet to apply can't get the blob
+// anywhere, and waits until somebody can supply it.
+//
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::sha256::sha256;
+use super::state_machine::META_DIR;
+
+/// Writes this large or larger go to the log as blobs.
+pub const BLOB_MIN: usize = 64 << 10;
+
+/// A blob's name: the SHA-256 of its contents.
+pub type Hash = [u8; 32];
+
+pub fn hash(data: &[u8]) -> Hash {
+    sha256(data)
+}
+
+pub fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(name: &str) -> Option<Hash> {
+    if name.len() != 64 || !name.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&name[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Read back a hash written as a byte string.
+pub fn from_bytes(bytes: &[u8]) -> Option<Hash> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(bytes);
+    Some(hash)
+}
+
+/// The blobs kept in one backing directory.
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(target: &Path) -> BlobStore {
+        BlobStore { dir: target.join(META_DIR).join("blobs") }
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join(hex(hash))
+    }
+
+    pub fn has(&self, hash: &Hash) -> bool {
+        self.path(hash).is_file()
+    }
+
+    /// Store `data`, which is known to hash to `hash`, durably.
+    pub fn put(&self, hash: &Hash, data: &[u8]) -> io::Result<()> {
+        if self.has(hash) {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(hash);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(data)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    pub fn get(&self, hash: &Hash) -> io::Result<Vec<u8>> {
+        fs::read(self.path(hash))
+    }
+
+    /// Delete every blob not in `keep`, returning how many went.
+    pub fn collect(&self, keep: &HashSet<Hash>) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut deleted = 0;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            // A blob still being put counts as the blob, so that it is
+            // kept if wanted; leftovers from an interrupted put go if not.
+            let wanted = match name.to_str().and_then(|n| unhex(n.trim_end_matches(".tmp"))) {
+                Some(hash) => keep.contains(&hash),
+                None => false,
+            };
+            if !wanted {
+                fs::remove_file(entry.path())?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blobs_are_stored_and_collected() {
+        let target = ::std::env::temp_dir().join(format!("raftfs-blob-{}", ::std::process::id()));
+        fs::remove_dir_all(&target).ok();
+        let store = BlobStore::new(&target);
+        let (a, b) = (b"some data".to_vec(), vec![7; BLOB_MIN]);
+        let (ha, hb) = (hash(&a), hash(&b));
+        assert_eq!(unhex(&hex(&ha)), Some(ha));
+        assert!(!store.has(&ha));
+        store.put(&ha, &a).unwrap();
+        store.put(&hb, &b).unwrap();
+        assert_eq!(store.get(&ha).unwrap(), a);
+        assert_eq!(store.get(&hb).unwrap(), b);
+
+        let keep: HashSet<Hash> = vec![hb].into_iter().collect();
+        assert_eq!(store.collect(&keep).unwrap(), 1);
+        assert!(!store.has(&ha));
+        assert!(store.has(&hb));
+        // Whatever else turns up there goes too.
+        fs::write(store.dir.join("junk"), b"").unwrap();
+        assert_eq!(store.collect(&keep).unwrap(), 1);
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+// Cluster :: Remembers which cluster a backing directory belongs to.
+//
+// `raftfs init` and `raftfs join` write TARGET/.raftfs/config, and
+// `raftfs mount` reads it, so that mounting a node never needs to be told
+// again who it is or where to find its peers.  The file is plain text with
+// one setting per line:
+//
+//     id 2
+//     listen 10.0.0.2:7420
+//     peer 1 10.0.0.1:7420
+//
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use super::raft::NodeId;
+use super::state_machine::META_DIR;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// Our own node id.
+    pub id: NodeId,
+    /// Where we accept connections from our peers, if anywhere.
+    pub listen: Option<SocketAddr>,
+    /// Where to find the rest of the cluster, for a node that has yet to
+    /// hear the membership from raft.
+    pub peers: Vec<(NodeId, SocketAddr)>,
+}
+
+impl ClusterConfig {
+    /// Where the configuration for `target` is kept.
+    pub fn path(target: &Path) -> PathBuf {
+        target.join(META_DIR).join("config")
+    }
+
+    /// Read the configuration for `target`.  A directory that was never
+    /// set up by `init` or `join` gives a NotFound error.
+    pub fn load(target: &Path) -> io::Result<ClusterConfig> {
+        let path = ClusterConfig::path(target);
+        let mut text = String::new();
+        File::open(&path)?.read_to_string(&mut text)?;
+        parse(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("bad config in {:?}: {}", path, e))
+        })
+    }
+
+    pub fn save(&self, target: &Path) -> io::Result<()> {
+        let path = ClusterConfig::path(target);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(self.to_string().as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        File::open(target.join(META_DIR))?.sync_all()
+    }
+}
+
+impl ::std::fmt::Display for ClusterConfig {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        writeln!(f, "id {}", self.id)?;
+        if let Some(addr) = self.listen {
+            writeln!(f, "listen {}", addr)?;
+        }
+        for &(id, addr) in &self.peers {
+            writeln!(f, "peer {} {}", id, addr)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse(text: &str) -> Result<ClusterConfig, String> {
+    let mut id = None;
+    let mut listen = None;
+    let mut peers = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let bad = || format!("line {}: {:?}", n + 1, line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["id", v] => id = Some(v.parse().map_err(|_| bad())?),
+            ["listen", v] => listen = Some(v.parse().map_err(|_| bad())?),
+            ["peer", p, v] => {
+                let peer: NodeId = p.parse().map_err(|_| bad())?;
+                peers.push((peer, v.parse().map_err(|_| bad())?));
+            },
+            _ => return Err(bad()),
+        }
+    }
+    match id {
+        Some(id) => Ok(ClusterConfig { id: id, listen: listen, peers: peers }),
+        None => Err("no node id".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-cluster-{}", ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join(META_DIR)).unwrap();
+        assert_eq!(ClusterConfig::load(&dir).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let config = ClusterConfig {
+            id: 2,
+            listen: Some("10.0.0.2:7420".parse().unwrap()),
+            peers: vec![(1, "10.0.0.1:7420".parse().unwrap()),
+                        (3, "[::1]:7000".parse().unwrap())],
+        };
+        config.save(&dir).unwrap();
+        assert_eq!(ClusterConfig::load(&dir).unwrap(), config);
+    }
+
+    #[test]
+    fn nonsense_is_refused() {
+        assert!(parse("listen 10.0.0.1:7420\n").is_err());
+        assert!(parse("id 1\npeer 2\n").is_err());
+        assert!(parse("id 1\nlisten nowhere\n").is_err());
+        assert_eq!(parse("id 1\n\n").unwrap().peers, vec![]);
+    }
+}
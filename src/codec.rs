@@ -0,0 +1,171 @@
+// Codec :: The binary building blocks for everything we write to disk or
+//          send between nodes.
+//
+// Integers are big-endian and fixed width.  Byte strings (and therefore
+// paths and names) are a u32 length followed by the bytes.
+//
+
+use std::error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Written by a raftfs that speaks a format version we do not.
+    UnsupportedVersion(u8),
+    /// A tag byte naming a variant we have never heard of.
+    UnknownTag(u8),
+    /// The input ended in the middle of a value.
+    Truncated,
+    /// The input kept going after the value ended.
+    TrailingBytes(usize),
+    /// A field held a value that is out of range for it.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            DecodeError::UnknownTag(t) => write!(f, "unknown tag {}", t),
+            DecodeError::Truncated => write!(f, "truncated input"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} unexpected trailing bytes", n),
+            DecodeError::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn description(&self) -> &str {
+        "decode error"
+    }
+}
+
+pub fn put_u8(out: &mut Vec<u8>, v: u8) {
+    out.push(v);
+}
+
+pub fn put_bool(out: &mut Vec<u8>, v: bool) {
+    out.push(v as u8);
+}
+
+pub fn put_u32(out: &mut Vec<u8>, v: u32) {
+    for i in 0..4 {
+        out.push((v >> (24 - 8 * i)) as u8);
+    }
+}
+
+pub fn put_u64(out: &mut Vec<u8>, v: u64) {
+    for i in 0..8 {
+        out.push((v >> (56 - 8 * i)) as u8);
+    }
+}
+
+pub fn put_bytes(out: &mut Vec<u8>, v: &[u8]) {
+    put_u32(out, v.len() as u32);
+    out.extend_from_slice(v);
+}
+
+pub fn put_os(out: &mut Vec<u8>, v: &OsStr) {
+    put_bytes(out, v.as_bytes());
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data: data }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < n {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        self.take(1).map(|b| b[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("boolean")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.take(4).map(|b| b.iter().fold(0, |v, &x| (v << 8) | x as u32))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        self.take(8).map(|b| b.iter().fold(0, |v, &x| (v << 8) | x as u64))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let n = self.u32()? as usize;
+        self.take(n).map(|b| b.to_vec())
+    }
+
+    pub fn os(&mut self) -> Result<OsString, DecodeError> {
+        self.bytes().map(OsString::from_vec)
+    }
+
+    pub fn path(&mut self) -> Result<PathBuf, DecodeError> {
+        self.os().map(PathBuf::from)
+    }
+
+    /// Insist that everything has been consumed.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes(self.data.len()))
+        }
+    }
+}
+
+fn crc32_table() -> &'static [u32; 256] {
+    static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        table
+    })
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib), for noticing torn or corrupt records.
+pub fn crc32(data: &[u8]) -> u32 {
+    let table = crc32_table();
+    let mut crc = !0u32;
+    for &b in data {
+        crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+}
@@ -0,0 +1,589 @@
+// DiskLog :: The raft log, kept in segment files that survive a crash.
+//
+// The log lives in TARGET/.raftfs/log as a series of segment files, each
+// named for the index of its first entry.  A segment is a short header
+// followed by records:
+//
+//     u32 length of payload
+//     u32 CRC-32 of payload
+//     payload: u64 term, u64 index, u8 kind, data
+//
+// Appends are buffered by the kernel until `sync`, so any number of them
+// can share one fsync.  On startup we scan every segment; a damaged record
+// at the very end of the log is what a crash in the middle of an append
+// leaves behind, and it is quietly dropped.  So is a last segment whose
+// header never made it to disk, as a crash just after starting it leaves.
+//
+// Once entries are covered by a snapshot, the index and term of the last
+// of them go in the file `base`, and segments holding nothing after it are
+// deleted.  The base is written first, so a crash part way through leaves
+// only segments we would have deleted anyway.
+//
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use super::codec::{crc32, put_u32, put_u64, put_u8, Reader};
+use super::raft::{Entry, EntryKind, LogIndex, LogStore, Term};
+
+const SEGMENT_MAGIC: &[u8; 8] = b"raftlog2";
+const RECORD_HEADER: u64 = 8;
+
+/// Start a new segment once the current one is this big.
+const SEGMENT_SIZE: u64 = 64 << 20;
+
+struct Segment {
+    first: LogIndex,
+    path: PathBuf,
+    file: File,
+    // Where each record starts, and the term of its entry.
+    offsets: Vec<u64>,
+    terms: Vec<Term>,
+    len: u64,
+    synced: bool,
+}
+
+impl Segment {
+    fn create(dir: &Path, first: LogIndex) -> io::Result<Segment> {
+        let path = dir.join(segment_name(first));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        file.write_all_at(SEGMENT_MAGIC, 0)?;
+        Ok(Segment {
+            first: first,
+            path: path,
+            file: file,
+            offsets: Vec::new(),
+            terms: Vec::new(),
+            len: SEGMENT_MAGIC.len() as u64,
+            synced: false,
+        })
+    }
+
+    /// Read a segment back in, checking every record.  Returns whether
+    /// the segment ended in a damaged record, which has been cut off, or
+    /// None if all there is of it is part of its header.
+    fn recover(path: PathBuf, first: LogIndex) -> io::Result<Option<(Segment, bool)>> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() < SEGMENT_MAGIC.len() || (data.len() == SEGMENT_MAGIC.len() && data != SEGMENT_MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < SEGMENT_MAGIC.len() || &data[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{:?} is not a raft log segment", path)));
+        }
+        let mut seg = Segment {
+            first: first,
+            path: path,
+            file: file,
+            offsets: Vec::new(),
+            terms: Vec::new(),
+            len: SEGMENT_MAGIC.len() as u64,
+            synced: true,
+        };
+        let mut torn = false;
+        while (seg.len as usize) < data.len() {
+            match parse_record(&data[seg.len as usize..]) {
+                Some((entry, size)) if entry.index == seg.next() => {
+                    seg.offsets.push(seg.len);
+                    seg.terms.push(entry.term);
+                    seg.len += size;
+                },
+                _ => {
+                    torn = true;
+                    break;
+                },
+            }
+        }
+        if torn {
+            seg.file.set_len(seg.len)?;
+            seg.file.sync_all()?;
+        }
+        Ok(Some((seg, torn)))
+    }
+
+    /// The index the next entry in this segment would have.
+    fn next(&self) -> LogIndex {
+        self.first + self.terms.len() as LogIndex
+    }
+
+    fn read(&self, index: LogIndex) -> io::Result<Entry> {
+        let k = (index - self.first) as usize;
+        let start = self.offsets[k];
+        let end = if k + 1 < self.offsets.len() { self.offsets[k + 1] } else { self.len };
+        let mut buf = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut buf, start)?;
+        match parse_record(&buf) {
+            Some((entry, _)) if entry.index == index => Ok(entry),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("damaged record for entry {} in {:?}", index, self.path))),
+        }
+    }
+}
+
+fn segment_name(first: LogIndex) -> String {
+    format!("{:020}.seg", first)
+}
+
+fn encode_record(entry: &Entry, out: &mut Vec<u8>) {
+    let mut payload = Vec::with_capacity(17 + entry.data.len());
+    put_u64(&mut payload, entry.term);
+    put_u64(&mut payload, entry.index);
+    put_u8(&mut payload, entry.kind.tag());
+    payload.extend_from_slice(&entry.data);
+    put_u32(out, payload.len() as u32);
+    put_u32(out, crc32(&payload));
+    out.extend_from_slice(&payload);
+}
+
+/// Parse the record at the start of `data`, returning it and its size.
+fn parse_record(data: &[u8]) -> Option<(Entry, u64)> {
+    let mut r = Reader::new(data);
+    let len = r.u32().ok()? as usize;
+    let crc = r.u32().ok()?;
+    let payload = data.get(RECORD_HEADER as usize..RECORD_HEADER as usize + len)?;
+    if len < 17 || crc32(payload) != crc {
+        return None;
+    }
+    let mut r = Reader::new(payload);
+    let term = r.u64().ok()?;
+    let index = r.u64().ok()?;
+    let kind = EntryKind::from_tag(r.u8().ok()?).ok()?;
+    let entry = Entry { term: term, index: index, kind: kind, data: payload[17..].to_vec() };
+    Some((entry, RECORD_HEADER + len as u64))
+}
+
+fn read_base(path: &Path) -> io::Result<(LogIndex, Term)> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+    let mut r = Reader::new(&data);
+    let parsed = (r.u64(), r.u64(), r.u32());
+    match parsed {
+        (Ok(index), Ok(term), Ok(crc)) if r.finish().is_ok() && crc32(&data[..16]) == crc => {
+            Ok((index, term))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                format!("damaged raft log base {:?}", path))),
+    }
+}
+
+fn write_base(dir: &Path, base: (LogIndex, Term)) -> io::Result<()> {
+    let mut data = Vec::new();
+    put_u64(&mut data, base.0);
+    put_u64(&mut data, base.1);
+    let crc = crc32(&data);
+    put_u32(&mut data, crc);
+    let tmp = dir.join("base.tmp");
+    {
+        let f = File::create(&tmp)?;
+        f.write_all_at(&data, 0)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, dir.join("base"))?;
+    File::open(dir)?.sync_all()
+}
+
+pub struct DiskLog {
+    dir: PathBuf,
+    // The entry just before the log starts.
+    base: (LogIndex, Term),
+    segments: Vec<Segment>,
+    segment_size: u64,
+    // Segments have been created or removed since the directory was synced.
+    dir_changed: bool,
+}
+
+impl DiskLog {
+    pub fn open(dir: PathBuf) -> io::Result<DiskLog> {
+        DiskLog::open_with_segment_size(dir, SEGMENT_SIZE)
+    }
+
+    pub fn open_with_segment_size(dir: PathBuf, segment_size: u64) -> io::Result<DiskLog> {
+        fs::create_dir_all(&dir)?;
+        let mut found = Vec::new();
+        for e in fs::read_dir(&dir)? {
+            let path = e?.path();
+            if path.extension().map(|x| x == "seg") != Some(true) {
+                continue;
+            }
+            let first = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok());
+            match first {
+                Some(first) => found.push((first, path)),
+                None => warn!("ignoring stray file {:?} in raft log", path),
+            }
+        }
+        found.sort();
+
+        let base = read_base(&dir.join("base"))?;
+        let mut log = DiskLog {
+            dir: dir,
+            base: base,
+            segments: Vec::new(),
+            segment_size: segment_size,
+            dir_changed: false,
+        };
+        let count = found.len();
+        for (i, (first, path)) in found.into_iter().enumerate() {
+            if let Some(prev) = log.segments.last() {
+                if prev.next() != first {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("raft log has a gap before {:?}", path)));
+                }
+            }
+            let (seg, torn) = match Segment::recover(path.clone(), first)? {
+                Some(recovered) => recovered,
+                None if i + 1 < count => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{:?} is not a raft log segment", path)));
+                },
+                None => {
+                    warn!("dropped {:?}, whose header was torn", path);
+                    fs::remove_file(&path)?;
+                    log.dir_changed = true;
+                    break;
+                },
+            };
+            if torn {
+                if i + 1 < count {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("raft log segment {:?} is damaged", seg.path)));
+                }
+                warn!("dropped a torn record at the end of {:?}", seg.path);
+            }
+            log.segments.push(seg);
+        }
+        log.drop_covered()?;
+        if let Some(first) = log.segments.first() {
+            if first.first > log.base.0 + 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("raft log has a gap before {:?}", first.path)));
+            }
+        }
+        info!("raft log in {:?} holds entries {} through {}",
+              log.dir, log.base.0 + 1, log.last_index());
+        Ok(log)
+    }
+
+    /// Delete the segments that hold nothing after the base.
+    fn drop_covered(&mut self) -> io::Result<()> {
+        while self.segments.first().map(|s| s.next() <= self.base.0 + 1) == Some(true) {
+            let seg = self.segments.remove(0);
+            fs::remove_file(&seg.path)?;
+            self.dir_changed = true;
+        }
+        Ok(())
+    }
+
+    fn find(&self, index: LogIndex) -> Option<&Segment> {
+        if index <= self.base.0 || index > self.last_index() {
+            return None;
+        }
+        let k = match self.segments.binary_search_by_key(&index, |s| s.first) {
+            Ok(k) => k,
+            Err(k) => k - 1,
+        };
+        self.segments.get(k)
+    }
+}
+
+impl LogStore for DiskLog {
+    fn last_index(&self) -> LogIndex {
+        self.segments.last().map(|s| s.next() - 1).unwrap_or(self.base.0)
+    }
+
+    fn term(&self, index: LogIndex) -> io::Result<Option<Term>> {
+        if index == self.base.0 && index > 0 {
+            return Ok(Some(self.base.1));
+        }
+        Ok(self.find(index).map(|s| s.terms[(index - s.first) as usize]))
+    }
+
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> io::Result<Vec<Entry>> {
+        let mut out = Vec::new();
+        for index in lo..hi + 1 {
+            match self.find(index) {
+                Some(seg) => out.push(seg.read(index)?),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("no entry {} in raft log", index)));
+                },
+            }
+        }
+        Ok(out)
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        for entry in entries {
+            if entry.index != self.last_index() + 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("appending entry {} after {}",
+                                                  entry.index, self.last_index())));
+            }
+            let full = self.segments.last().map(|s| s.len >= self.segment_size).unwrap_or(true);
+            if full {
+                let seg = Segment::create(&self.dir, entry.index)?;
+                self.segments.push(seg);
+                self.dir_changed = true;
+            }
+            let seg = self.segments.last_mut().unwrap();
+            let mut buf = Vec::new();
+            encode_record(entry, &mut buf);
+            seg.file.write_all_at(&buf, seg.len)?;
+            seg.offsets.push(seg.len);
+            seg.terms.push(entry.term);
+            seg.len += buf.len() as u64;
+            seg.synced = false;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, index: LogIndex) -> io::Result<()> {
+        while let Some(seg) = self.segments.pop() {
+            if seg.first < index {
+                self.segments.push(seg);
+                break;
+            }
+            fs::remove_file(&seg.path)?;
+            self.dir_changed = true;
+        }
+        if let Some(seg) = self.segments.last_mut() {
+            if index < seg.next() {
+                let k = (index - seg.first) as usize;
+                seg.len = seg.offsets[k];
+                seg.offsets.truncate(k);
+                seg.terms.truncate(k);
+                seg.file.set_len(seg.len)?;
+                seg.synced = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for seg in self.segments.iter_mut().filter(|s| !s.synced) {
+            seg.file.sync_data()?;
+            seg.synced = true;
+        }
+        if self.dir_changed {
+            File::open(&self.dir)?.sync_all()?;
+            self.dir_changed = false;
+        }
+        Ok(())
+    }
+
+    fn base(&self) -> (LogIndex, Term) {
+        self.base
+    }
+
+    fn compact(&mut self, index: LogIndex, term: Term) -> io::Result<()> {
+        if index <= self.base.0 {
+            return Ok(());
+        }
+        let keep = self.term(index)? == Some(term);
+        write_base(&self.dir, (index, term))?;
+        self.base = (index, term);
+        if !keep {
+            for seg in self.segments.drain(..) {
+                fs::remove_file(&seg.path)?;
+            }
+            self.dir_changed = true;
+        }
+        self.drop_covered()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-log-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn entry(term: Term, index: LogIndex) -> Entry {
+        Entry {
+            term: term,
+            index: index,
+            kind: EntryKind::Normal,
+            data: format!("entry {}", index).into_bytes(),
+        }
+    }
+
+    fn fill(log: &mut DiskLog, term: Term, lo: LogIndex, hi: LogIndex) {
+        let entries: Vec<Entry> = (lo..hi + 1).map(|i| entry(term, i)).collect();
+        log.append(&entries).unwrap();
+        log.sync().unwrap();
+    }
+
+    #[test]
+    fn survives_reopen_across_segments() {
+        let dir = tempdir("reopen");
+        {
+            let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+            fill(&mut log, 1, 1, 20);
+            assert!(log.segments.len() > 1);
+        }
+        let log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+        assert_eq!(log.last_index(), 20);
+        assert_eq!(log.term(7).unwrap(), Some(1));
+        assert_eq!(log.term(21).unwrap(), None);
+        assert_eq!(log.entries(5, 12).unwrap(), (5..13).map(|i| entry(1, i)).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn truncate_from_anywhere() {
+        let dir = tempdir("truncate");
+        for &cut in &[1, 2, 9, 10, 11, 20] {
+            fs::remove_dir_all(&dir).ok();
+            {
+                let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+                fill(&mut log, 1, 1, 20);
+                log.truncate(cut).unwrap();
+                assert_eq!(log.last_index(), cut - 1);
+                fill(&mut log, 2, cut, cut + 4);
+            }
+            let log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+            assert_eq!(log.last_index(), cut + 4);
+            if cut > 1 {
+                assert_eq!(log.entries(cut - 1, cut - 1).unwrap(), vec![entry(1, cut - 1)]);
+            }
+            assert_eq!(log.entries(cut, cut + 4).unwrap(),
+                       (cut..cut + 5).map(|i| entry(2, i)).collect::<Vec<_>>());
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn drops_torn_final_record() {
+        let dir = tempdir("torn");
+        let last_segment;
+        {
+            let mut log = DiskLog::open(dir.clone()).unwrap();
+            fill(&mut log, 3, 1, 5);
+            last_segment = log.segments.last().unwrap().path.clone();
+        }
+        // Chop the last record in half, as a crash mid-write would.
+        let len = fs::metadata(&last_segment).unwrap().len();
+        OpenOptions::new().write(true).open(&last_segment).unwrap().set_len(len - 3).unwrap();
+        {
+            let mut log = DiskLog::open(dir.clone()).unwrap();
+            assert_eq!(log.last_index(), 4);
+            fill(&mut log, 4, 5, 6);
+        }
+        let log = DiskLog::open(dir.clone()).unwrap();
+        assert_eq!(log.entries(4, 6).unwrap(), vec![entry(3, 4), entry(4, 5), entry(4, 6)]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn drops_torn_final_segment_header() {
+        let dir = tempdir("tornheader");
+        {
+            let mut log = DiskLog::open_with_segment_size(dir.clone(), 1).unwrap();
+            fill(&mut log, 1, 1, 3);
+        }
+        // A crash just after the log moved on to a new segment.
+        let torn = dir.join(segment_name(4));
+        for header in &[&b""[..], &b"raft"[..], &[0; 8][..]] {
+            fs::write(&torn, header).unwrap();
+            let log = DiskLog::open(dir.clone()).unwrap();
+            assert_eq!(log.last_index(), 3);
+            assert!(!torn.exists());
+        }
+        // Anywhere but at the end, it is real damage.
+        fs::write(dir.join(segment_name(2)), b"raft").unwrap();
+        assert!(DiskLog::open(dir.clone()).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn drops_corrupt_final_record() {
+        let dir = tempdir("corrupt");
+        let last_segment;
+        {
+            let mut log = DiskLog::open(dir.clone()).unwrap();
+            fill(&mut log, 1, 1, 3);
+            last_segment = log.segments.last().unwrap().path.clone();
+        }
+        let len = fs::metadata(&last_segment).unwrap().len();
+        OpenOptions::new().write(true).open(&last_segment).unwrap()
+            .write_all_at(b"X", len - 1).unwrap();
+        let log = DiskLog::open(dir.clone()).unwrap();
+        assert_eq!(log.last_index(), 2);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn damage_before_the_end_is_an_error() {
+        let dir = tempdir("damaged");
+        let first_segment;
+        {
+            let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+            fill(&mut log, 1, 1, 20);
+            first_segment = log.segments[0].path.clone();
+        }
+        OpenOptions::new().write(true).open(&first_segment).unwrap()
+            .write_all_at(b"XXXX", SEGMENT_MAGIC.len() as u64 + 10).unwrap();
+        assert!(DiskLog::open_with_segment_size(dir.clone(), 100).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn appends_must_be_contiguous() {
+        let dir = tempdir("gap");
+        let mut log = DiskLog::open(dir.clone()).unwrap();
+        fill(&mut log, 1, 1, 2);
+        assert!(log.append(&[entry(1, 4)]).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn compaction_survives_reopen() {
+        let dir = tempdir("compact");
+        {
+            let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+            fill(&mut log, 1, 1, 20);
+            log.compact(12, 1).unwrap();
+            assert_eq!(log.base(), (12, 1));
+            assert_eq!(log.term(12).unwrap(), Some(1));
+            assert_eq!(log.term(11).unwrap(), None);
+            assert!(log.entries(12, 13).is_err());
+        }
+        let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+        assert_eq!(log.base(), (12, 1));
+        assert_eq!(log.last_index(), 20);
+        assert_eq!(log.entries(13, 20).unwrap(), (13..21).map(|i| entry(1, i)).collect::<Vec<_>>());
+        assert!(log.segments[0].first <= 13);
+        fill(&mut log, 1, 21, 22);
+        assert_eq!(log.last_index(), 22);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn snapshot_from_elsewhere_replaces_log() {
+        let dir = tempdir("install");
+        {
+            let mut log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+            fill(&mut log, 1, 1, 5);
+            // The leader's entry 30 is from a term we never saw.
+            log.compact(30, 2).unwrap();
+            assert_eq!(log.last_index(), 30);
+            fill(&mut log, 2, 31, 32);
+        }
+        let log = DiskLog::open_with_segment_size(dir.clone(), 100).unwrap();
+        assert_eq!(log.base(), (30, 2));
+        assert_eq!(log.term(30).unwrap(), Some(2));
+        assert_eq!(log.entries(31, 32).unwrap(), vec![entry(2, 31), entry(2, 32)]);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,441 @@
+// FsOp :: A filesystem mutation, as recorded in the replicated log.
+//
+// Every call that changes the filesystem is turned into an FsOp, appended
+// to the raft log, and only carried out (by the StateMachine) once it has
+// been committed.  Paths are the ones FUSE hands us, relative to the root
+// of the mount.
+//
+// The encoding is what ends up in the log on disk and on the wire, so it
+// must stay readable by every raftfs in a cluster.  It starts with a
+// version byte; any change to the layout below needs a new version.
+//
+// What goes into the log is actually a Proposal, which wraps the FsOp with
+// where it came from.  A node that is not sure its proposal made it into
+// the log proposes it again, and the state machine uses this to apply it
+// only the once.
+//
+// Writes go to a file as it was opened, not to whatever has its name when
+// they are applied, since it may have been renamed, unlinked or made
+// read-only since.  So opening a file for writing is an op too, which
+// gives the open file a handle, and every node keeps the file it opened
+// under that handle until the handle is released.  A write names the
+// handle as well as the path, which is where it goes on a node that has
+// lost track of the handle by restarting.
+//
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use super::blob::{self, Hash};
+use super::codec::{put_bool, put_bytes, put_os, put_u32, put_u64, put_u8, DecodeError, Reader};
+use super::raft::NodeId;
+
+use time::Timespec;
+
+/// The version of the encoding written by `FsOp::encode`.  Version 1,
+/// which had no handles, can still be read.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Names a file one node's user has open for writing, among the others
+/// that node has open.
+pub type Handle = u64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsOp {
+    Mknod { parent: PathBuf, name: OsString, mode: u32, rdev: u32 },
+    Mkdir { parent: PathBuf, name: OsString, mode: u32 },
+    Unlink { parent: PathBuf, name: OsString },
+    Rmdir { parent: PathBuf, name: OsString },
+    Symlink { parent: PathBuf, name: OsString, target: PathBuf },
+    Rename { parent: PathBuf, name: OsString, newparent: PathBuf, newname: OsString },
+    Link { path: PathBuf, newparent: PathBuf, newname: OsString },
+    /// Also opens the new file as `handle`, if there is one.
+    Create { parent: PathBuf, name: OsString, mode: u32, flags: u32, handle: Option<Handle> },
+    /// Writes to the file open as `handle`, or failing that, at `path`.
+    Write { path: PathBuf, handle: Option<Handle>, offset: u64, data: Vec<u8> },
+    Truncate { path: PathBuf, size: u64 },
+    Chmod { path: PathBuf, mode: u32 },
+    Chown { path: PathBuf, uid: Option<u32>, gid: Option<u32> },
+    Utimens { path: PathBuf, atime: Option<Timespec>, mtime: Option<Timespec> },
+    SetXattr { path: PathBuf, name: OsString, value: Vec<u8>, flags: u32, position: u32 },
+    RemoveXattr { path: PathBuf, name: OsString },
+    /// A Write whose data is the blob named `hash`.
+    WriteBlob { path: PathBuf, handle: Option<Handle>, offset: u64, hash: Hash },
+    /// Changes nothing, but every node summarizes its tree just after
+    /// applying it, so that the replicas can be compared.
+    Checkpoint,
+    /// Open the file at `path` for writing, as `handle`.
+    Open { path: PathBuf, flags: u32, handle: Handle },
+    /// The file open as `handle` has been closed.
+    Release { handle: Handle },
+}
+
+impl FsOp {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![FORMAT_VERSION];
+        match *self {
+            FsOp::Mknod { ref parent, ref name, mode, rdev } => {
+                put_u8(&mut out, 1);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+                put_u32(&mut out, mode);
+                put_u32(&mut out, rdev);
+            },
+            FsOp::Mkdir { ref parent, ref name, mode } => {
+                put_u8(&mut out, 2);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+                put_u32(&mut out, mode);
+            },
+            FsOp::Unlink { ref parent, ref name } => {
+                put_u8(&mut out, 3);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+            },
+            FsOp::Rmdir { ref parent, ref name } => {
+                put_u8(&mut out, 4);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+            },
+            FsOp::Symlink { ref parent, ref name, ref target } => {
+                put_u8(&mut out, 5);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+                put_os(&mut out, target.as_os_str());
+            },
+            FsOp::Rename { ref parent, ref name, ref newparent, ref newname } => {
+                put_u8(&mut out, 6);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+                put_os(&mut out, newparent.as_os_str());
+                put_os(&mut out, newname);
+            },
+            FsOp::Link { ref path, ref newparent, ref newname } => {
+                put_u8(&mut out, 7);
+                put_os(&mut out, path.as_os_str());
+                put_os(&mut out, newparent.as_os_str());
+                put_os(&mut out, newname);
+            },
+            FsOp::Create { ref parent, ref name, mode, flags, handle } => {
+                put_u8(&mut out, 8);
+                put_os(&mut out, parent.as_os_str());
+                put_os(&mut out, name);
+                put_u32(&mut out, mode);
+                put_u32(&mut out, flags);
+                put_handle(&mut out, handle);
+            },
+            FsOp::Write { ref path, handle, offset, ref data } => {
+                put_u8(&mut out, 9);
+                put_os(&mut out, path.as_os_str());
+                put_handle(&mut out, handle);
+                put_u64(&mut out, offset);
+                put_bytes(&mut out, data);
+            },
+            FsOp::Truncate { ref path, size } => {
+                put_u8(&mut out, 10);
+                put_os(&mut out, path.as_os_str());
+                put_u64(&mut out, size);
+            },
+            FsOp::Chmod { ref path, mode } => {
+                put_u8(&mut out, 11);
+                put_os(&mut out, path.as_os_str());
+                put_u32(&mut out, mode);
+            },
+            FsOp::Chown { ref path, uid, gid } => {
+                put_u8(&mut out, 12);
+                put_os(&mut out, path.as_os_str());
+                put_id(&mut out, uid);
+                put_id(&mut out, gid);
+            },
+            FsOp::Utimens { ref path, atime, mtime } => {
+                put_u8(&mut out, 13);
+                put_os(&mut out, path.as_os_str());
+                put_time(&mut out, atime);
+                put_time(&mut out, mtime);
+            },
+            FsOp::SetXattr { ref path, ref name, ref value, flags, position } => {
+                put_u8(&mut out, 14);
+                put_os(&mut out, path.as_os_str());
+                put_os(&mut out, name);
+                put_bytes(&mut out, value);
+                put_u32(&mut out, flags);
+                put_u32(&mut out, position);
+            },
+            FsOp::RemoveXattr { ref path, ref name } => {
+                put_u8(&mut out, 15);
+                put_os(&mut out, path.as_os_str());
+                put_os(&mut out, name);
+            },
+            FsOp::WriteBlob { ref path, handle, offset, ref hash } => {
+                put_u8(&mut out, 16);
+                put_os(&mut out, path.as_os_str());
+                put_handle(&mut out, handle);
+                put_u64(&mut out, offset);
+                put_bytes(&mut out, hash);
+            },
+            FsOp::Checkpoint => put_u8(&mut out, 17),
+            FsOp::Open { ref path, flags, handle } => {
+                put_u8(&mut out, 18);
+                put_os(&mut out, path.as_os_str());
+                put_u32(&mut out, flags);
+                put_u64(&mut out, handle);
+            },
+            FsOp::Release { handle } => {
+                put_u8(&mut out, 19);
+                put_u64(&mut out, handle);
+            },
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<FsOp, DecodeError> {
+        let mut r = Reader::new(data);
+        let version = r.u8()?;
+        if version != FORMAT_VERSION && version != 1 {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let handle = |r: &mut Reader| if version == 1 { Ok(None) } else { get_handle(r) };
+        let op = match r.u8()? {
+            1 => FsOp::Mknod { parent: r.path()?, name: r.os()?, mode: r.u32()?, rdev: r.u32()? },
+            2 => FsOp::Mkdir { parent: r.path()?, name: r.os()?, mode: r.u32()? },
+            3 => FsOp::Unlink { parent: r.path()?, name: r.os()? },
+            4 => FsOp::Rmdir { parent: r.path()?, name: r.os()? },
+            5 => FsOp::Symlink { parent: r.path()?, name: r.os()?, target: r.path()? },
+            6 => FsOp::Rename {
+                parent: r.path()?, name: r.os()?, newparent: r.path()?, newname: r.os()?,
+            },
+            7 => FsOp::Link { path: r.path()?, newparent: r.path()?, newname: r.os()? },
+            8 => FsOp::Create {
+                parent: r.path()?, name: r.os()?, mode: r.u32()?, flags: r.u32()?, handle: handle(&mut r)?,
+            },
+            9 => FsOp::Write { path: r.path()?, handle: handle(&mut r)?, offset: r.u64()?, data: r.bytes()? },
+            10 => FsOp::Truncate { path: r.path()?, size: r.u64()? },
+            11 => FsOp::Chmod { path: r.path()?, mode: r.u32()? },
+            12 => FsOp::Chown { path: r.path()?, uid: get_id(&mut r)?, gid: get_id(&mut r)? },
+            13 => FsOp::Utimens { path: r.path()?, atime: get_time(&mut r)?, mtime: get_time(&mut r)? },
+            14 => FsOp::SetXattr {
+                path: r.path()?, name: r.os()?, value: r.bytes()?, flags: r.u32()?, position: r.u32()?,
+            },
+            15 => FsOp::RemoveXattr { path: r.path()?, name: r.os()? },
+            16 => FsOp::WriteBlob {
+                path: r.path()?,
+                handle: handle(&mut r)?,
+                offset: r.u64()?,
+                hash: blob::from_bytes(&r.bytes()?).ok_or(DecodeError::Invalid("hash"))?,
+            },
+            17 => FsOp::Checkpoint,
+            18 => FsOp::Open { path: r.path()?, flags: r.u32()?, handle: r.u64()? },
+            19 => FsOp::Release { handle: r.u64()? },
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        };
+        r.finish()?;
+        Ok(op)
+    }
+}
+
+/// An FsOp as proposed to raft by one of the nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    /// The node whose user asked for the op.
+    pub origin: NodeId,
+    /// Tells apart the times that node has been started.
+    pub boot: u64,
+    /// Counts the ops proposed by the node since it started.
+    pub serial: u64,
+    /// The node has had the result of every op with this serial or less.
+    pub floor: u64,
+    pub op: FsOp,
+}
+
+impl Proposal {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u64(&mut out, self.origin);
+        put_u64(&mut out, self.boot);
+        put_u64(&mut out, self.serial);
+        put_u64(&mut out, self.floor);
+        put_bytes(&mut out, &self.op.encode());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Proposal, DecodeError> {
+        let mut r = Reader::new(data);
+        let proposal = Proposal {
+            origin: r.u64()?,
+            boot: r.u64()?,
+            serial: r.u64()?,
+            floor: r.u64()?,
+            op: FsOp::decode(&r.bytes()?)?,
+        };
+        r.finish()?;
+        Ok(proposal)
+    }
+}
+
+fn put_id(out: &mut Vec<u8>, v: Option<u32>) {
+    match v {
+        None => put_bool(out, false),
+        Some(id) => {
+            put_bool(out, true);
+            put_u32(out, id);
+        },
+    }
+}
+
+fn get_id(r: &mut Reader) -> Result<Option<u32>, DecodeError> {
+    if r.bool()? {
+        Ok(Some(r.u32()?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn put_handle(out: &mut Vec<u8>, v: Option<Handle>) {
+    match v {
+        None => put_bool(out, false),
+        Some(handle) => {
+            put_bool(out, true);
+            put_u64(out, handle);
+        },
+    }
+}
+
+fn get_handle(r: &mut Reader) -> Result<Option<Handle>, DecodeError> {
+    if r.bool()? {
+        Ok(Some(r.u64()?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn put_time(out: &mut Vec<u8>, v: Option<Timespec>) {
+    match v {
+        None => put_bool(out, false),
+        Some(t) => {
+            put_bool(out, true);
+            put_u64(out, t.sec as u64);
+            put_u32(out, t.nsec as u32);
+        },
+    }
+}
+
+fn get_time(r: &mut Reader) -> Result<Option<Timespec>, DecodeError> {
+    if !r.bool()? {
+        return Ok(None);
+    }
+    let sec = r.u64()? as i64;
+    let nsec = r.u32()?;
+    if nsec >= 1_000_000_000 {
+        return Err(DecodeError::Invalid("nanoseconds"));
+    }
+    Ok(Some(Timespec { sec: sec, nsec: nsec as i32 }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn all_ops() -> Vec<FsOp> {
+        let t = Timespec { sec: 1489000000, nsec: 999999999 };
+        vec![
+            FsOp::Mknod { parent: PathBuf::from("/dev"), name: OsString::from("null"),
+                          mode: 0o20666, rdev: 259 },
+            FsOp::Mkdir { parent: PathBuf::from("/"), name: OsString::from("dir"), mode: 0o755 },
+            FsOp::Unlink { parent: PathBuf::from("/a"), name: OsString::from("b") },
+            FsOp::Rmdir { parent: PathBuf::from("/a"), name: OsString::from("c") },
+            FsOp::Symlink { parent: PathBuf::from("/"), name: OsString::from("ln"),
+                            target: PathBuf::from("../elsewhere") },
+            FsOp::Rename { parent: PathBuf::from("/old/dir"), name: OsString::from("x"),
+                           newparent: PathBuf::from("/new"), newname: OsString::from("y") },
+            FsOp::Link { path: PathBuf::from("/x"), newparent: PathBuf::from("/d"),
+                         newname: OsString::from("x2") },
+            FsOp::Create { parent: PathBuf::from("/"), name: OsString::from("f"),
+                           mode: 0o644, flags: 0o100001, handle: Some(3) },
+            FsOp::Create { parent: PathBuf::from("/"), name: OsString::from("f"),
+                           mode: 0o444, flags: 0o100000, handle: None },
+            FsOp::Write { path: PathBuf::from("/f"), handle: Some(1 << 33), offset: 1 << 40,
+                          data: b"hello\0world".to_vec() },
+            FsOp::Write { path: PathBuf::from("/f"), handle: None, offset: 0, data: Vec::new() },
+            FsOp::Truncate { path: PathBuf::from("/f"), size: 12345 },
+            FsOp::Chmod { path: PathBuf::from("/f"), mode: 0o4755 },
+            FsOp::Chown { path: PathBuf::from("/f"), uid: Some(1000), gid: None },
+            FsOp::Chown { path: PathBuf::from("/f"), uid: None, gid: Some(u32::MAX) },
+            FsOp::Utimens { path: PathBuf::from("/f"), atime: None, mtime: Some(t) },
+            FsOp::Utimens { path: PathBuf::from("/f"), atime: Some(Timespec { sec: -1, nsec: 0 }),
+                            mtime: None },
+            FsOp::SetXattr { path: PathBuf::from("/f"), name: OsString::from("user.x"),
+                             value: vec![0, 255, 7], flags: 2, position: 0 },
+            FsOp::RemoveXattr { path: PathBuf::from("/f"), name: OsString::from("user.x") },
+            FsOp::WriteBlob { path: PathBuf::from("/f"), handle: Some(3), offset: 1 << 20, hash: [0xab; 32] },
+            FsOp::Checkpoint,
+            FsOp::Open { path: PathBuf::from("/f"), flags: 0o2, handle: 3 },
+            FsOp::Release { handle: 3 },
+            // Names need not be valid UTF-8.
+            FsOp::Unlink { parent: PathBuf::from("/"), name: OsString::from_vec(vec![0xff, 0xfe]) },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for op in all_ops() {
+            let bytes = op.encode();
+            assert_eq!(bytes[0], FORMAT_VERSION);
+            assert_eq!(FsOp::decode(&bytes), Ok(op));
+        }
+    }
+
+    #[test]
+    fn proposals_round_trip() {
+        for op in all_ops() {
+            let p = Proposal { origin: 3, boot: 1 << 50, serial: 17, floor: 15, op: op };
+            assert_eq!(Proposal::decode(&p.encode()), Ok(p));
+        }
+        assert_eq!(Proposal::decode(&[0; 32]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn layout_is_stable() {
+        let op = FsOp::Mkdir { parent: PathBuf::from("/"), name: OsString::from("d"), mode: 0o755 };
+        assert_eq!(op.encode(),
+                   vec![2, 2,
+                        0, 0, 0, 1, b'/',
+                        0, 0, 0, 1, b'd',
+                        0, 0, 0x01, 0xed]);
+    }
+
+    #[test]
+    fn reads_writes_without_handles() {
+        let version1 = [1, 9,
+                        0, 0, 0, 2, b'/', b'f',
+                        0, 0, 0, 0, 0, 0, 0, 7,
+                        0, 0, 0, 2, b'h', b'i'];
+        assert_eq!(FsOp::decode(&version1),
+                   Ok(FsOp::Write { path: PathBuf::from("/f"), handle: None, offset: 7, data: b"hi".to_vec() }));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = all_ops()[0].encode();
+        bytes[0] = FORMAT_VERSION + 1;
+        assert_eq!(FsOp::decode(&bytes), Err(DecodeError::UnsupportedVersion(FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn rejects_unknown_op() {
+        assert_eq!(FsOp::decode(&[FORMAT_VERSION, 200]), Err(DecodeError::UnknownTag(200)));
+    }
+
+    #[test]
+    fn rejects_truncated_and_padded_input() {
+        for op in all_ops() {
+            let bytes = op.encode();
+            for n in 0..bytes.len() {
+                assert_eq!(FsOp::decode(&bytes[..n]), Err(DecodeError::Truncated));
+            }
+            let mut padded = bytes.clone();
+            padded.push(0);
+            assert_eq!(FsOp::decode(&padded), Err(DecodeError::TrailingBytes(1)));
+        }
+    }
+}
@@ -0,0 +1,227 @@
+// HardState :: Keeps raft's current term and vote on disk.
+//
+// A node that forgets whom it voted for can vote twice in the same term
+// and so help elect two leaders.  We therefore save the term and vote
+// before raft sends anything that depends on them.  Each save writes a
+// fresh file, syncs it, and renames it over the old one, so after a crash
+// we find either the old state or the new one and never a mixture.
+//
+// The file is a format version byte, the term, the vote, the membership
+// as of the start of the log, and a CRC-32 of everything before it.
+//
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use super::codec::{self, Reader};
+use super::raft::{HardState, HardStateStore, Membership};
+
+const FORMAT_VERSION: u8 = 2;
+
+pub struct HardStateFile {
+    path: PathBuf,
+    state: HardState,
+}
+
+impl HardStateFile {
+    /// Open the hard state kept at `path`.  A missing file means a node
+    /// that has never voted; a damaged one is an error, since guessing
+    /// could make us vote twice.
+    pub fn open(path: PathBuf) -> io::Result<HardStateFile> {
+        let state = match File::open(&path) {
+            Ok(mut f) => {
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf)?;
+                decode(&buf).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("bad raft hard state in {:?}: {}", path, e))
+                })?
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+        debug!("raft hard state in {:?} is {:?}", path, state);
+        Ok(HardStateFile { path: path, state: state })
+    }
+}
+
+impl HardStateStore for HardStateFile {
+    fn load(&self) -> HardState {
+        self.state.clone()
+    }
+
+    fn save(&mut self, state: &HardState) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(&encode(state))?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.state = state.clone();
+        Ok(())
+    }
+}
+
+fn encode(state: &HardState) -> Vec<u8> {
+    let mut out = Vec::new();
+    codec::put_u8(&mut out, FORMAT_VERSION);
+    codec::put_u64(&mut out, state.term);
+    codec::put_bool(&mut out, state.voted_for.is_some());
+    codec::put_u64(&mut out, state.voted_for.unwrap_or(0));
+    codec::put_bytes(&mut out, &state.membership.encode());
+    let crc = codec::crc32(&out);
+    codec::put_u32(&mut out, crc);
+    out
+}
+
+fn decode(data: &[u8]) -> Result<HardState, codec::DecodeError> {
+    if data.len() < 4 {
+        return Err(codec::DecodeError::Truncated);
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    let mut r = Reader::new(crc);
+    if r.u32()? != codec::crc32(body) {
+        return Err(codec::DecodeError::Invalid("checksum"));
+    }
+    let mut r = Reader::new(body);
+    let version = r.u8()?;
+    if version != FORMAT_VERSION {
+        return Err(codec::DecodeError::UnsupportedVersion(version));
+    }
+    let term = r.u64()?;
+    let voted = r.bool()?;
+    let candidate = r.u64()?;
+    let membership = Membership::decode(&r.bytes()?)?;
+    r.finish()?;
+    Ok(HardState {
+        term: term,
+        voted_for: if voted { Some(candidate) } else { None },
+        membership: membership,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::libc_extras::libc;
+    use super::super::raft::{Clock, Config, Member, MemLog, MemSnapshots, Message, NodeId, Raft, Rpc,
+                             Term, Transport};
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-hs-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    struct Frozen;
+    impl Clock for Frozen {
+        fn now(&self) -> u64 {
+            0
+        }
+    }
+
+    /// Dies the moment raft tries to say anything.
+    struct Crash;
+    impl Transport for Crash {
+        fn send(&mut self, _msg: Message) {
+            unsafe { libc::_exit(0) }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Sent(Arc<Mutex<Vec<Message>>>);
+    impl Transport for Sent {
+        fn send(&mut self, msg: Message) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    fn vote_request(from: NodeId, term: Term) -> Message {
+        Message {
+            from: from,
+            to: 1,
+            term: term,
+            rpc: Rpc::RequestVote { last_log_index: 0, last_log_term: 0, transfer: false },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempdir("round-trip");
+        let path = dir.join("hard_state");
+        assert_eq!(HardStateFile::open(path.clone()).unwrap().load(), HardState::default());
+        let members = vec![Member { id: 3, addr: "10.0.0.3:7420".to_string() },
+                           Member { id: 4, addr: String::new() }];
+        let state = HardState {
+            term: 7,
+            voted_for: Some(3),
+            membership: Membership { index: 12, members: members },
+        };
+        HardStateFile::open(path.clone()).unwrap().save(&state).unwrap();
+        assert_eq!(HardStateFile::open(path.clone()).unwrap().load(), state);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn damage_is_an_error() {
+        let dir = tempdir("damage");
+        let path = dir.join("hard_state");
+        HardStateFile::open(path.clone()).unwrap()
+            .save(&HardState { term: 2, ..HardState::default() }).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[8] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(HardStateFile::open(path.clone()).is_err());
+        fs::write(&path, &data[..5]).unwrap();
+        assert!(HardStateFile::open(path).is_err());
+    }
+
+    #[test]
+    fn vote_survives_crash_before_reply() {
+        let dir = tempdir("crash");
+        let path = dir.join("hard_state");
+        let new_raft = |transport: Box<dyn Transport>| {
+            Raft::new(1, Box::new(MemLog::new()),
+                      Box::new(HardStateFile::open(path.clone()).unwrap()),
+                      Box::new(MemSnapshots::new()), transport, Box::new(Frozen),
+                      Config::default())
+        };
+
+        // Vote for node 2 in a child that is killed on its way to replying.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let mut raft = new_raft(Box::new(Crash));
+            raft.step(vote_request(2, 5));
+            raft.flush();
+            // Raft never tried to reply.
+            unsafe { libc::_exit(1) }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        // The vote outlived the process, so node 3 cannot have one too,
+        // but node 2 can hear it again.
+        assert_eq!(HardStateFile::open(path.clone()).unwrap().load(),
+                   HardState { term: 5, voted_for: Some(2), ..HardState::default() });
+        let sent = Sent(Arc::new(Mutex::new(Vec::new())));
+        let mut raft = new_raft(Box::new(sent.clone()));
+        assert_eq!(raft.term(), 5);
+        raft.step(vote_request(3, 5));
+        raft.step(vote_request(2, 5));
+        raft.flush();
+        let replies: Vec<Rpc> = sent.0.lock().unwrap().drain(..).map(|m| m.rpc).collect();
+        assert_eq!(replies, vec![Rpc::RequestVoteReply { granted: false },
+                                 Rpc::RequestVoteReply { granted: true }]);
+    }
+}
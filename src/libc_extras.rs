@@ -11,9 +11,6 @@ pub mod libc {
 
     // stuff missing from the libc crate.
     extern "system" {
-        // Specified by POSIX.1-2008; not sure why this is missing.
-        pub fn fchown(fd: c_int, uid: uid_t, gid: gid_t) -> c_int;
-
         // On Mac OS X, off_t is always 64 bits.
         // https://developer.apple.com/library/mac/documentation/Darwin/Conceptual/64bitPorting/transition/transition.html
         #[cfg(target_os = "macos")]
@@ -72,6 +69,7 @@ pub mod libc {
         }
     }
 
+    #[cfg(target_os = "macos")]
     pub const UTIME_OMIT: time_t = ((11 << 30) - 21);
 
     // Mac OS X does not support futimens; map it to futimes with lower precision.
@@ -5,6 +5,13 @@
 
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 extern crate libc;
 extern crate time;
@@ -14,9 +21,31 @@ extern crate log;
 
 extern crate fuse_mt;
 
+mod admin;
+mod archive;
+mod batch;
+mod blob;
+mod cluster;
+mod codec;
+mod disk_log;
+mod fsop;
+mod hard_state;
 mod libc_extras;
 mod libc_wrappers;
+mod merkle;
+mod node;
+mod raft;
 mod raftfs;
+mod sha256;
+#[cfg(test)]
+mod sim;
+mod snapshot;
+mod state_machine;
+mod tcp;
+
+use admin::{Request, Response};
+use cluster::ClusterConfig;
+use raft::{HardStateStore, LogStore, NodeId, Role};
 
 struct ConsoleLogger;
 
@@ -32,23 +61,374 @@ impl log::Log for ConsoleLogger {
 }
 
 fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+    let command = args.get(1).map(|c| c.to_string_lossy().into_owned()).unwrap_or_default();
+    // Only a mounted node has much to say; the other commands report
+    // through their output.
+    let level = if command == "mount" {
+        log::LogLevelFilter::Debug
+    } else {
+        log::LogLevelFilter::Warn
+    };
     log::set_logger(|max_log_level| {
-        max_log_level.set(log::LogLevelFilter::Debug);
+        max_log_level.set(level);
         Box::new(ConsoleLogger)
     }).unwrap();
 
-    let args: Vec<OsString> = env::args_os().collect();
+    let options = Options::parse(&args[::std::cmp::min(2, args.len())..]);
+    match command.as_ref() {
+        "init" => init(options),
+        "join" => join(options),
+        "mount" => mount(options),
+        "remove" => remove(options),
+        "transfer-leader" => transfer_leader(options),
+        "status" => status(options),
+        "verify" => verify(options),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    let me = env::args().next().unwrap();
+    eprintln!("usage: {} init <target> [--id N] [--listen ADDR]", me);
+    eprintln!("       {} join <target> <peer address> --listen ADDR [--id N]", me);
+    eprintln!("       {} mount <target> <mountpoint> [--consistency local|lease|linearizable]", me);
+    eprintln!("             [--quorum-wait SECONDS] [--compact-every ENTRIES]");
+    eprintln!("             [--verify-every SECONDS]");
+    eprintln!("       {} remove <target> <node>", me);
+    eprintln!("       {} transfer-leader [<target>] <node>", me);
+    eprintln!("       {} status <target>", me);
+    eprintln!("       {} verify <target>", me);
+    ::std::process::exit(-1);
+}
+
+fn die<T: Display>(why: T) -> ! {
+    eprintln!("{}", why);
+    ::std::process::exit(1);
+}
+
+struct Options {
+    positional: Vec<OsString>,
+    id: Option<NodeId>,
+    listen: Option<SocketAddr>,
+    consistency: Option<node::Consistency>,
+    quorum_wait: Option<u64>,
+    compact_every: Option<u64>,
+    verify_every: Option<u64>,
+}
+
+impl Options {
+    fn parse(args: &[OsString]) -> Options {
+        let mut options = Options {
+            positional: Vec::new(),
+            id: None,
+            listen: None,
+            consistency: None,
+            quorum_wait: None,
+            compact_every: None,
+            verify_every: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().map(|v| v.to_string_lossy().into_owned())
+                .unwrap_or_else(|| usage());
+            match arg.to_str() {
+                Some("--id") => options.id = Some(value().parse().unwrap_or_else(|_| usage())),
+                Some("--listen") => {
+                    options.listen = Some(value().parse().unwrap_or_else(|_| usage()))
+                },
+                Some("--consistency") => {
+                    options.consistency = Some(value().parse().unwrap_or_else(|e| die(e)))
+                },
+                Some("--quorum-wait") => {
+                    options.quorum_wait = Some(value().parse().unwrap_or_else(|_| usage()))
+                },
+                Some("--compact-every") => {
+                    options.compact_every = Some(value().parse().unwrap_or_else(|_| usage()))
+                },
+                Some("--verify-every") => {
+                    options.verify_every = Some(value().parse().unwrap_or_else(|_| usage()))
+                },
+                Some(a) if a.starts_with("--") => usage(),
+                _ => options.positional.push(arg.clone()),
+            }
+        }
+        options
+    }
+
+    /// The positional arguments, which must number exactly `n`.
+    fn positional(&self, n: usize) -> &[OsString] {
+        if self.positional.len() != n {
+            usage();
+        }
+        &self.positional
+    }
+}
 
-    if args.len() != 3 {
-        println!("usage: {} <target> <mountpoint>", &env::args().next().unwrap());
-        ::std::process::exit(-1);
+/// Refuse to set up `target` a second time.
+fn check_uninitialized(target: &PathBuf) {
+    if !target.is_dir() {
+        die(format!("{:?} is not a directory", target));
+    }
+    if ClusterConfig::path(target).exists() {
+        die(format!("{:?} already belongs to a cluster", target));
     }
+}
+
+fn open_log(target: &PathBuf) -> disk_log::DiskLog {
+    disk_log::DiskLog::open(target.join(state_machine::META_DIR).join("log")).unwrap_or_else(|e| {
+        die(format!("unable to open raft log in {:?}: {}", target, e))
+    })
+}
+
+fn open_hard_state(target: &PathBuf) -> hard_state::HardStateFile {
+    let path = target.join(state_machine::META_DIR).join("hard_state");
+    hard_state::HardStateFile::open(path).unwrap_or_else(|e| {
+        die(format!("unable to open raft state in {:?}: {}", target, e))
+    })
+}
+
+fn open_state_machine(target: &PathBuf) -> state_machine::StateMachine {
+    state_machine::StateMachine::open(target.clone()).unwrap_or_else(|e| {
+        die(format!("unable to open {:?}: {}", target, e))
+    })
+}
 
-    let filesystem = raftfs::RaftFS {
-        target: args[1].clone(),
+/// Start a new cluster of one, around whatever `target` already holds.
+fn init(options: Options) {
+    let target = PathBuf::from(&options.positional(1)[0]);
+    check_uninitialized(&target);
+    let mut sm = open_state_machine(&target);
+    let mut log = open_log(&target);
+    let mut hard_state = open_hard_state(&target);
+    let config = ClusterConfig {
+        id: options.id.unwrap_or(1),
+        listen: options.listen,
+        peers: Vec::new(),
     };
+    if log.last_index() == 0 {
+        // Entry 1 stands for the directory as we found it, so nodes that
+        // join later are sent a snapshot of it rather than an empty log.
+        let me = raft::Member {
+            id: config.id,
+            addr: config.listen.map(|a| a.to_string()).unwrap_or_default(),
+        };
+        let state = raft::HardState {
+            term: 1,
+            voted_for: None,
+            membership: raft::Membership { index: 1, members: vec![me] },
+        };
+        let founded = hard_state.save(&state).and_then(|_| log.compact(1, 1));
+        if let Err(e) = founded {
+            die(format!("unable to start a cluster in {:?}: {}", target, e));
+        }
+        sm.skip(1);
+    }
+    if let Err(e) = config.save(&target) {
+        die(format!("unable to save cluster config in {:?}: {}", target, e));
+    }
+    println!("{:?} is now node {} of a new cluster", target, config.id);
+}
+
+/// Ask an existing cluster to take us in.  Whatever `target` holds will be
+/// replaced by the cluster's files the first time it is mounted.
+fn join(options: Options) {
+    let (target, peer) = {
+        let args = options.positional(2);
+        (PathBuf::from(&args[0]), args[1].to_string_lossy().into_owned())
+    };
+    let listen = options.listen.unwrap_or_else(|| usage());
+    check_uninitialized(&target);
+    fs::create_dir_all(target.join(state_machine::META_DIR)).unwrap_or_else(|e| die(e));
+    if open_log(&target).last_index() > 0 {
+        die(format!("{:?} holds the log of another cluster", target));
+    }
+    let addr = peer.to_socket_addrs().ok().and_then(|mut a| a.next()).unwrap_or_else(|| {
+        die(format!("unable to find {}", peer))
+    });
+    let request = Request::Join { id: options.id, addr: listen };
+    match ask_leader(admin::ask(&addr, &request), &request) {
+        Response::Joined { id, peers } => {
+            // Until we hear otherwise from the leader, these are who we
+            // answer to.
+            let config = ClusterConfig { id: id, listen: Some(listen), peers: peers };
+            if let Err(e) = config.save(&target) {
+                die(format!("unable to save cluster config in {:?}: {}", target, e));
+            }
+            println!("{:?} is now node {} of the cluster at {}; mount it soon, since the cluster \
+                      counts on it from now on", target, id, peer);
+        },
+        r => die(format!("unexpected answer: {:?}", r)),
+    }
+}
+
+/// Take a node out of the cluster that `target` belongs to.
+fn remove(options: Options) {
+    let (target, id) = {
+        let args = options.positional(2);
+        let id: NodeId = args[1].to_string_lossy().parse().unwrap_or_else(|_| usage());
+        (PathBuf::from(&args[0]), id)
+    };
+    let request = Request::Remove { id: id };
+    match ask_leader(admin::ask_local(&target, &request), &request) {
+        Response::Removed => println!("node {} is on its way out of the cluster", id),
+        r => die(format!("unexpected answer: {:?}", r)),
+    }
+}
+
+fn transfer_leader(options: Options) {
+    // Run from inside the target, it need not be named.
+    let (target, id) = match options.positional.len() {
+        1 => (env::current_dir().unwrap_or_else(|e| die(e)), &options.positional[0]),
+        _ => {
+            let args = options.positional(2);
+            (PathBuf::from(&args[0]), &args[1])
+        },
+    };
+    let id: NodeId = id.to_string_lossy().parse().unwrap_or_else(|_| usage());
+    let request = Request::Transfer { id: id };
+    match ask_leader(admin::ask_local(&target, &request), &request) {
+        Response::Transferring => println!("handing leadership to node {}", id),
+        r => die(format!("unexpected answer: {:?}", r)),
+    }
+}
+
+/// Follow redirects from followers until the leader gives `response`.
+fn ask_leader(mut response: io::Result<Response>, request: &Request) -> Response {
+    for _ in 0..5 {
+        match response {
+            Ok(Response::Redirect(Some(leader))) => response = admin::ask(&leader, request),
+            Ok(Response::Redirect(None)) => die("the cluster has no leader; try again later"),
+            Ok(Response::Refused(why)) => die(format!("refused: {}", why)),
+            Ok(r) => return r,
+            Err(e) => die(format!("unable to ask the cluster: {}", e)),
+        }
+    }
+    die("unable to find the leader");
+}
+
+fn mount(options: Options) {
+    let (target, mountpoint) = {
+        let args = options.positional(2);
+        (PathBuf::from(&args[0]), args[1].clone())
+    };
+    let cluster = ClusterConfig::load(&target).unwrap_or_else(|e| {
+        die(format!("unable to read the cluster config for {:?} ({}); use init or join first",
+                    target, e))
+    });
+    let sm = open_state_machine(&target);
+    let log = open_log(&target);
+    let hard_state = open_hard_state(&target);
+    let snapshots = archive::ArchiveStore::open(target.clone()).unwrap_or_else(|e| {
+        die(format!("unable to open raft snapshots in {:?}: {}", target, e))
+    });
+    match snapshot::copy_mode(&target) {
+        Ok(mode) => info!("snapshots will save files by {}", mode),
+        Err(e) => warn!("unable to tell how snapshots can save files in {:?}: {}", target, e),
+    }
+    // A node that nobody can reach is a cluster of one for good, and needs
+    // no network.
+    let transport: Box<dyn raft::Transport> = if cluster.listen.is_none() && cluster.peers.is_empty() {
+        Box::new(node::NoNetwork)
+    } else {
+        Box::new(tcp::TcpTransport::new(&cluster.peers))
+    };
+    let listener = cluster.listen.map(|addr| tcp::Listener::bind(&addr).unwrap_or_else(|e| {
+        die(format!("unable to listen on {}: {}", addr, e))
+    }));
+    let mut raft_config = raft::Config::default();
+    if let Some(every) = options.compact_every {
+        raft_config.compact_every = every;
+    }
+    let raft = raft::Raft::new(cluster.id, Box::new(log), Box::new(hard_state), Box::new(snapshots), transport,
+                               Box::new(raft::SystemClock), raft_config);
+    let config = node::Config {
+        consistency: options.consistency.unwrap_or(node::Consistency::Local),
+        quorum_wait: Duration::from_secs(options.quorum_wait.unwrap_or(0)),
+        status_file: Some(target.join(state_machine::META_DIR).join("status")),
+        verify_every: options.verify_every.map(Duration::from_secs),
+    };
+    let node = node::Node::start(sm, raft, config);
+    let handle = node.handle();
+    let responder = Arc::new(admin::Responder::new(handle.clone()));
+    if let Err(e) = admin::serve_local(&target, responder.clone()) {
+        warn!("unable to answer status requests: {}", e);
+    }
+    if let Some(listener) = listener {
+        match listener.local_addr() {
+            Ok(addr) => info!("listening for peers on {}", addr),
+            Err(e) => warn!("unable to tell where we are listening: {}", e),
+        }
+        listener.serve(move |msg| handle.deliver(msg),
+                       move |request| responder.answer(request));
+    }
+    let filesystem = raftfs::RaftFS::new(target.into_os_string(), node);
 
     let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
 
-    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), &args[2], &fuse_args).unwrap();
+    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), &mountpoint, &fuse_args).unwrap();
+}
+
+fn status(options: Options) {
+    let target = PathBuf::from(&options.positional(1)[0]);
+    let status = match admin::ask_local(&target, &Request::Status) {
+        Ok(Response::Status(status)) => status,
+        Ok(r) => die(format!("unexpected answer: {:?}", r)),
+        Err(e) => {
+            // Nobody is running, so say what we can from the disk.
+            let cluster = ClusterConfig::load(&target).unwrap_or_else(|e| {
+                die(format!("{:?} does not belong to a cluster: {}", target, e))
+            });
+            let hard_state = open_hard_state(&target).load();
+            println!("node {} is not running: {}", cluster.id, e);
+            println!("term: {}", hard_state.term);
+            println!("applied: {}", open_state_machine(&target).applied());
+            println!("members as of the last snapshot:");
+            print_members(&hard_state.membership.members, None);
+            return;
+        },
+    };
+    let role = match status.role {
+        Role::Follower => "follower",
+        Role::Candidate => "candidate",
+        Role::Leader => "leader",
+    };
+    println!("node {} is {}", status.id, role);
+    println!("term: {}", status.term);
+    println!("writes: {}", if status.quorum { "accepted" } else { "refused (no quorum)" });
+    println!("commit index: {}", status.commit_index);
+    println!("applied: {}", status.applied);
+    println!("members:");
+    print_members(&status.members, status.leader);
+}
+
+/// Check that every replica in the cluster holds the same as this one.
+fn verify(options: Options) {
+    let target = PathBuf::from(&options.positional(1)[0]);
+    match admin::ask_local(&target, &Request::Verify) {
+        Ok(Response::Verified { index, ref report }) if report.is_empty() => {
+            println!("every replica agrees as of entry {}", index);
+        },
+        Ok(Response::Verified { index, report }) => {
+            println!("replicas differ as of entry {}:", index);
+            for line in report {
+                println!("    {}", line);
+            }
+            ::std::process::exit(1);
+        },
+        Ok(Response::Refused(why)) => die(why),
+        Ok(r) => die(format!("unexpected answer: {:?}", r)),
+        Err(e) => die(format!("unable to ask the node running on {:?}: {}", target, e)),
+    }
+}
+
+fn print_members(members: &[raft::Member], leader: Option<NodeId>) {
+    if members.is_empty() {
+        println!("    none known");
+    }
+    for m in members {
+        let addr = if m.addr.is_empty() { "no address" } else { &m.addr };
+        let lead = if Some(m.id) == leader { " (leader)" } else { "" };
+        println!("    {} at {}{}", m.id, addr, lead);
+    }
 }
@@ -0,0 +1,311 @@
+// Merkle :: A hash tree over the backing directory, to compare replicas by.
+//
+// Every node applies the same log to the same tree, so at the same applied
+// index every replica ought to hold the same data.  To check, each node
+// summarizes its tree at that index.  Everything in it gets a hash of what
+// it is: its type and mode, owner, extended attributes, and its contents or
+// link target.  A directory's hash covers the names and hashes of what it
+// holds as well, so two replicas whose root hashes agree hold the same
+// data, and where they differ, following the hashes that differ down from
+// the root leads straight to what is different, without either side
+// sending its whole summary.
+//
+// Times are left out, since a change is applied at a different moment on
+// each node, and so are the attributes of the root, which every node gave
+// its backing directory for itself.  So is our own bookkeeping.
+//
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::blob::{self, Hash};
+use super::codec::{put_bool, put_bytes, put_os, put_u32, put_u64, DecodeError, Reader};
+use super::libc_extras::libc;
+use super::libc_wrappers;
+use super::sha256::{sha256, Sha256};
+use super::state_machine::is_metadata;
+
+/// What a summary says about one thing in the tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    /// Covers the thing and, for a directory, everything in it.
+    pub hash: Hash,
+    /// Covers the thing itself: its attributes and contents.
+    pub own: Hash,
+    /// What a directory holds, by name, in order; None for anything else.
+    pub children: Option<Vec<(OsString, Hash)>>,
+}
+
+impl Item {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        put_bytes(out, &self.hash);
+        put_bytes(out, &self.own);
+        put_bool(out, self.children.is_some());
+        if let Some(ref children) = self.children {
+            put_u32(out, children.len() as u32);
+            for (name, hash) in children {
+                put_os(out, name);
+                put_bytes(out, hash);
+            }
+        }
+    }
+
+    pub fn decode(r: &mut Reader) -> Result<Item, DecodeError> {
+        let hash = get_hash(r)?;
+        let own = get_hash(r)?;
+        let children = if r.bool()? {
+            let mut children = Vec::new();
+            for _ in 0..r.u32()? {
+                children.push((r.os()?, get_hash(r)?));
+            }
+            Some(children)
+        } else {
+            None
+        };
+        Ok(Item { hash: hash, own: own, children: children })
+    }
+}
+
+fn get_hash(r: &mut Reader) -> Result<Hash, DecodeError> {
+    blob::from_bytes(&r.bytes()?).ok_or(DecodeError::Invalid("hash"))
+}
+
+/// The hashes of everything in a tree, by path within the mount.
+#[derive(Debug, Default)]
+pub struct Summary {
+    items: HashMap<PathBuf, Item>,
+}
+
+impl Summary {
+    pub fn get(&self, path: &Path) -> Option<&Item> {
+        self.items.get(path)
+    }
+}
+
+/// Summarize the tree in the backing directory `target`.
+pub fn summarize(target: &Path) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    add(target, Path::new("/"), &mut summary)?;
+    Ok(summary)
+}
+
+/// Add `partial` and everything under it to `summary`, returning its hash.
+fn add(target: &Path, partial: &Path, summary: &mut Summary) -> io::Result<Hash> {
+    let real = target.join(partial.strip_prefix("/").unwrap());
+    let meta = fs::symlink_metadata(&real)?;
+    let mut own = Vec::new();
+    if partial != Path::new("/") {
+        put_u32(&mut own, meta.mode());
+        put_u32(&mut own, meta.uid());
+        put_u32(&mut own, meta.gid());
+        put_u64(&mut own, meta.rdev());
+        put_xattrs(&mut own, &real)?;
+    }
+    let children = if meta.is_dir() {
+        let mut names = fs::read_dir(&real)?.map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<OsString>>>()?;
+        names.sort();
+        let mut children = Vec::new();
+        for name in names {
+            let path = partial.join(&name);
+            if !is_metadata(&path) {
+                let hash = add(target, &path, summary)?;
+                children.push((name, hash));
+            }
+        }
+        Some(children)
+    } else {
+        if meta.file_type().is_symlink() {
+            put_os(&mut own, fs::read_link(&real)?.as_os_str());
+        } else if meta.is_file() {
+            put_bytes(&mut own, &hash_file(&real)?);
+        }
+        None
+    };
+    let own = sha256(&own);
+    let mut all = own.to_vec();
+    if let Some(ref children) = children {
+        put_u32(&mut all, children.len() as u32);
+        for (name, hash) in children {
+            put_os(&mut all, name);
+            put_bytes(&mut all, hash);
+        }
+    }
+    let hash = sha256(&all);
+    summary.items.insert(partial.to_owned(), Item { hash: hash, own: own, children: children });
+    Ok(hash)
+}
+
+fn hash_file(real: &Path) -> io::Result<Hash> {
+    let mut f = File::open(real)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        match f.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// Add the extended attributes of `real`, in order of name.
+fn put_xattrs(out: &mut Vec<u8>, real: &Path) -> io::Result<()> {
+    let errno = |e| io::Error::from_raw_os_error(e);
+    let path = real.as_os_str().to_owned();
+    let mut names = match libc_wrappers::llistxattr(path.clone(), &mut []) {
+        Ok(size) => vec![0; size],
+        Err(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(errno(e)),
+    };
+    let size = libc_wrappers::llistxattr(path.clone(), &mut names).map_err(errno)?;
+    let mut names: Vec<&[u8]> = names[..size].split(|&b| b == 0).filter(|n| !n.is_empty()).collect();
+    names.sort();
+    put_u32(out, names.len() as u32);
+    for name in names {
+        let name = OsString::from(::std::ffi::OsStr::from_bytes(name));
+        let size = libc_wrappers::lgetxattr(path.clone(), name.clone(), &mut []).map_err(errno)?;
+        let mut value = vec![0; size];
+        let size = libc_wrappers::lgetxattr(path.clone(), name.clone(), &mut value).map_err(errno)?;
+        put_os(out, &name);
+        put_bytes(out, &value[..size]);
+    }
+    Ok(())
+}
+
+/// A way in which another replica differs from ours.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// It has something else at this path.
+    Differs(PathBuf),
+    /// It lacks something we have.
+    Missing(PathBuf),
+    /// It has something we don't.
+    Extra(PathBuf),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Difference::Differs(ref p) => write!(f, "{:?} differs", p),
+            Difference::Missing(ref p) => write!(f, "{:?} is missing", p),
+            Difference::Extra(ref p) => write!(f, "{:?} should not be there", p),
+        }
+    }
+}
+
+/// Compare `ours` with another replica's summary, which `theirs` gives
+/// us an item at a time, and report the differences.  Only the items
+/// whose hashes differ from ours are asked for.
+pub fn compare<F>(ours: &Summary, mut theirs: F) -> io::Result<Vec<Difference>>
+    where F: FnMut(&Path) -> io::Result<Item>
+{
+    let mut differences = Vec::new();
+    let root = theirs(Path::new("/"))?;
+    descend(ours, Path::new("/"), &root, &mut theirs, &mut differences)?;
+    Ok(differences)
+}
+
+fn descend<F>(ours: &Summary, path: &Path, their: &Item, theirs: &mut F,
+              differences: &mut Vec<Difference>) -> io::Result<()>
+    where F: FnMut(&Path) -> io::Result<Item>
+{
+    let our = &ours.items[path];
+    if our.hash == their.hash {
+        return Ok(());
+    }
+    if our.own != their.own {
+        differences.push(Difference::Differs(path.to_owned()));
+    }
+    if let (Some(ours_in), Some(theirs_in)) = (&our.children, &their.children) {
+        let theirs_by_name: HashMap<&OsString, &Hash> =
+            theirs_in.iter().map(|(name, hash)| (name, hash)).collect();
+        for (name, hash) in ours_in {
+            let child = path.join(name);
+            match theirs_by_name.get(name) {
+                None => differences.push(Difference::Missing(child)),
+                Some(&h) if h != hash => {
+                    let item = theirs(&child)?;
+                    descend(ours, &child, &item, theirs, differences)?;
+                },
+                Some(_) => (),
+            }
+        }
+        let ours_by_name: HashMap<&OsString, &Hash> =
+            ours_in.iter().map(|(name, hash)| (name, hash)).collect();
+        for (name, _) in theirs_in {
+            if !ours_by_name.contains_key(name) {
+                differences.push(Difference::Extra(path.join(name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use super::super::state_machine::META_DIR;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-merkle-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join(META_DIR)).unwrap();
+        dir
+    }
+
+    fn populate(dir: &Path) {
+        fs::create_dir_all(dir.join("d/e")).unwrap();
+        fs::write(dir.join("d/e/f"), b"contents").unwrap();
+        fs::write(dir.join("d/g"), b"more").unwrap();
+        fs::write(dir.join("h"), b"").unwrap();
+        ::std::os::unix::fs::symlink("d/g", dir.join("ln")).unwrap();
+    }
+
+    /// The differences between `a` and `b`, and how many items of b's
+    /// it took to find them.
+    fn differences(a: &Path, b: &Path) -> (Vec<Difference>, usize) {
+        let (ours, theirs) = (summarize(a).unwrap(), summarize(b).unwrap());
+        let mut asked = 0;
+        let differences = compare(&ours, |p| {
+            asked += 1;
+            Ok(theirs.get(p).unwrap().clone())
+        }).unwrap();
+        (differences, asked)
+    }
+
+    #[test]
+    fn differences_are_found() {
+        let (a, b) = (tempdir("a"), tempdir("b"));
+        populate(&a);
+        populate(&b);
+        // Our own files don't count, and neither do times.
+        fs::write(a.join(META_DIR).join("applied"), b"7\n").unwrap();
+        assert_eq!(differences(&a, &b), (vec![], 1));
+
+        let mut item = Vec::new();
+        summarize(&a).unwrap().get(Path::new("/d")).unwrap().encode(&mut item);
+        let mut r = Reader::new(&item);
+        assert_eq!(Item::decode(&mut r).as_ref().ok(), summarize(&b).unwrap().get(Path::new("/d")));
+        r.finish().unwrap();
+
+        fs::write(b.join("d/e/f"), b"altered!").unwrap();
+        fs::set_permissions(b.join("h"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::remove_file(b.join("d/g")).unwrap();
+        fs::write(b.join("d/x"), b"").unwrap();
+        // Only the items on the way to the differences are asked for.
+        assert_eq!(differences(&a, &b), (vec![Difference::Differs(PathBuf::from("/d/e/f")),
+                                              Difference::Missing(PathBuf::from("/d/g")),
+                                              Difference::Extra(PathBuf::from("/d/x")),
+                                              Difference::Differs(PathBuf::from("/h"))],
+                                         5));
+        fs::remove_dir_all(&a).unwrap();
+        fs::remove_dir_all(&b).unwrap();
+    }
+}
@@ -0,0 +1,1038 @@
+// Node :: Runs raft in the background and applies whatever it commits.
+//
+// FUSE calls that change the filesystem hand their FsOp to `execute`, which
+// proposes it to raft and then waits until the entry has been committed and
+// applied here before returning its result.  On a follower the proposal is
+// forwarded to the leader, and waiting for it to be applied here means the
+// user can read their own write straight away.  If the leader changes, or
+// takes too long, we propose it again; the state machine's sessions see
+// to it that it is only applied once.  The background thread syncs the log
+// once for everything proposed since it last woke up.  Messages from other
+// nodes are handed to a `Handle`, and the background thread feeds them to
+// raft.
+//
+// Calls that only look at the filesystem pass through `read_barrier`
+// first.  What that waits for depends on the consistency the node was
+// mounted with: nothing at all, or until we have applied everything the
+// leader says was committed when we asked.
+//
+// A node cut off from the majority cannot commit anything, so while raft
+// says we have no quorum the filesystem is read-only: changes fail with
+// EROFS, either at once or after waiting a while for the cluster to come
+// back.  Whether we are writable is kept in TARGET/.raftfs/status for
+// anybody who wonders why.
+//
+// Large writes are stored as blobs before they are proposed, and the log
+// only names them.  A committed entry whose blob we lack is put off, along
+// with everything after it, while a thread fetches the blob from the node
+// that proposed it or, failing that, from the other members.  Whenever the
+// log has been compacted we delete the blobs it no longer names.
+//
+// To check that the replicas agree, we put a checkpoint in the log.  Every
+// node stops applying entries once it has applied the checkpoint, until it
+// has summarized its tree, and then whoever asked compares its summary
+// with everybody else's.  The leader does this every so often by itself,
+// if asked to, and reports any differences in the log.
+//
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::admin::{self, Request, Response};
+use super::blob::{self, BlobStore, Hash, BLOB_MIN};
+use super::fsop::{self, FsOp, Proposal};
+use super::libc_extras::libc;
+use super::merkle::{self, Item, Summary};
+use super::raft::{ChangeError, EntryKind, LogIndex, Member, Message, NodeId, Raft, Role, Term,
+                  Transport};
+use super::state_machine::StateMachine;
+
+/// How often the background thread drives raft's timers.
+const TICK: u64 = 10;
+
+/// How long a proposer waits for somebody to become leader.
+const LEADER_WAIT: u64 = 5;
+
+/// How many milliseconds a proposal may go uncommitted before we suspect
+/// it was lost on its way to the leader, and propose it again.
+const RESUBMIT: u64 = 1000;
+
+/// How many milliseconds a reader waits for the leader to answer before
+/// asking again.
+const READ_RETRY: u64 = 500;
+
+/// How many milliseconds a leader that is shutting down waits for a
+/// follower to take over.  Raft gives up on a handover well before this.
+const HANDOFF_WAIT: u64 = 1000;
+
+/// How many milliseconds to wait before asking around again for a blob
+/// that nobody could give us.
+const FETCH_RETRY: u64 = 1000;
+
+/// How many times we ask around for a blob before deciding that everybody
+/// has applied its write and deleted it, so that only the leader's
+/// snapshot can get us past it.
+const FETCH_TRIES: usize = 3;
+
+/// How many seconds a verification waits for the other nodes to catch up
+/// with its checkpoint.
+const VERIFY_WAIT: u64 = 60;
+
+/// How many summaries of our tree we keep for others to compare with.
+const SUMMARIES_KEPT: usize = 4;
+
+/// How closely reads follow writes made elsewhere in the cluster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// Read whatever this replica has, which may be behind the leader.
+    Local,
+    /// See every write committed before the read, trusting the leader's
+    /// lease rather than asking a majority each time.
+    Lease,
+    /// See every write committed before the read, without relying on
+    /// clocks.
+    Linearizable,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub consistency: Consistency,
+    /// How long a change waits for a quorum before failing with EROFS.
+    pub quorum_wait: Duration,
+    /// Where to keep a note of whether we are writable, if anywhere.
+    pub status_file: Option<PathBuf>,
+    /// How often the leader checks that every replica agrees, if ever.
+    pub verify_every: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            consistency: Consistency::Local,
+            quorum_wait: Duration::from_secs(0),
+            status_file: None,
+            verify_every: None,
+        }
+    }
+}
+
+impl FromStr for Consistency {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Consistency, String> {
+        match s {
+            "local" => Ok(Consistency::Local),
+            "lease" => Ok(Consistency::Lease),
+            "linearizable" => Ok(Consistency::Linearizable),
+            _ => Err(format!("unknown consistency {:?}", s)),
+        }
+    }
+}
+
+/// A transport for a cluster of one, which never has anybody to talk to.
+pub struct NoNetwork;
+
+impl Transport for NoNetwork {
+    fn send(&mut self, msg: Message) {
+        warn!("no network to send {:?}", msg);
+    }
+}
+
+struct State {
+    raft: Raft,
+    sm: StateMachine,
+    // Our session: when we started, the last serial we gave a proposal,
+    // the proposals somebody is still waiting on, and their results once
+    // they are applied.
+    boot: u64,
+    serial: u64,
+    pending: BTreeSet<u64>,
+    results: HashMap<u64, Result<(), libc::c_int>>,
+    // Reads asked of raft that somebody is still waiting on, and the
+    // answers that have come in.
+    next_read: u64,
+    reads: HashSet<u64>,
+    read_answers: HashMap<u64, Option<LogIndex>>,
+    blobs: BlobStore,
+    // Blobs stored for proposals that may not be in the log yet, once for
+    // each proposal.
+    offloaded: Vec<Hash>,
+    // Blobs being fetched, and those still to be, with the entry that
+    // needs each and where to ask.
+    fetching: HashSet<Hash>,
+    wanted: Vec<(Hash, LogIndex, Vec<SocketAddr>)>,
+    // The snapshot index when we last deleted the blobs nobody needs.
+    collected: LogIndex,
+    // The last checkpoint we applied, whether we have yet to start
+    // summarizing the tree as of it, and whether we are still at it, which
+    // holds up applying anything more.  Then the summaries themselves.
+    last_checkpoint: LogIndex,
+    checkpoint: Option<LogIndex>,
+    summarizing: bool,
+    summaries: BTreeMap<LogIndex, Arc<Summary>>,
+    // Messages from other nodes that raft has yet to see.
+    inbox: Vec<Message>,
+    shutdown: bool,
+}
+
+impl State {
+    fn status(&self) -> Status {
+        Status {
+            id: self.raft.id(),
+            role: self.raft.role(),
+            term: self.raft.term(),
+            leader: self.raft.leader(),
+            quorum: self.raft.has_quorum(),
+            commit_index: self.raft.commit_index(),
+            applied: self.sm.applied(),
+            members: self.raft.membership().members.clone(),
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        if self.summarizing {
+            return;
+        }
+        if let Some(index) = self.raft.take_installed() {
+            if let Err(e) = self.sm.install(index) {
+                // Carrying on would apply later entries to the wrong tree.
+                error!("unable to load snapshot through {}: {}", index, e);
+                panic!("unable to load snapshot through {}: {}", index, e);
+            }
+        }
+        for e in self.raft.take_committed() {
+            if e.kind != EntryKind::Normal || e.data.is_empty() {
+                // Raft's own business, such as a new leader's empty entry.
+                self.sm.skip(e.index);
+                continue;
+            }
+            match Proposal::decode(&e.data) {
+                Ok(p) => {
+                    if let FsOp::WriteBlob { hash, .. } = p.op {
+                        if !self.blobs.has(&hash) {
+                            // This, and everything after it, has to wait.
+                            self.raft.defer_from(e.index);
+                            self.want_blob(hash, e.index, p.origin);
+                            break;
+                        }
+                    }
+                    let result = self.sm.apply_proposal(e.index, &p);
+                    let ours = p.origin == self.raft.id() && p.boot == self.boot;
+                    if ours && self.pending.contains(&p.serial) {
+                        self.results.insert(p.serial, result);
+                    }
+                    if p.op == FsOp::Checkpoint {
+                        // Nothing more until the tree has been summarized.
+                        self.last_checkpoint = e.index;
+                        self.checkpoint = Some(e.index);
+                        self.summarizing = true;
+                        self.raft.defer_from(e.index + 1);
+                        break;
+                    }
+                },
+                Err(err) => {
+                    error!("unable to decode log entry {}: {}", e.index, err);
+                    self.sm.skip(e.index);
+                },
+            }
+        }
+    }
+
+    /// Arrange to fetch blob `hash`, which `origin` proposed for the entry
+    /// at `index`, unless we are already at it.
+    fn want_blob(&mut self, hash: Hash, index: LogIndex, origin: NodeId) {
+        if !self.fetching.insert(hash) {
+            return;
+        }
+        // The node that proposed it is sure to have it, so it goes first.
+        let me = self.raft.id();
+        let mut members: Vec<&Member> = self.raft.membership().members.iter()
+            .filter(|m| m.id != me).collect();
+        members.sort_by_key(|m| m.id != origin);
+        let from = members.iter().filter_map(|m| m.addr.parse().ok()).collect();
+        debug!("fetching blob {} from {:?}", blob::hex(&hash), from);
+        self.wanted.push((hash, index, from));
+    }
+
+    /// Delete the blobs that no entry left in the log names, and that no
+    /// proposal of ours is waiting on.
+    fn collect_blobs(&mut self) {
+        let mut keep: HashSet<Hash> = self.offloaded.iter().cloned().collect();
+        for e in self.raft.log_entries() {
+            if e.kind != EntryKind::Normal || e.data.is_empty() {
+                continue;
+            }
+            if let Ok(Proposal { op: FsOp::WriteBlob { hash, .. }, .. }) = Proposal::decode(&e.data) {
+                keep.insert(hash);
+            }
+        }
+        match self.blobs.collect(&keep) {
+            Ok(0) => (),
+            Ok(n) => info!("deleted {} blobs that the log no longer needs", n),
+            Err(e) => warn!("unable to delete unneeded blobs: {}", e),
+        }
+        self.collected = self.raft.snapshot_index();
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when there is work for the background thread.
+    wake: Condvar,
+    // Signalled when the background thread has made progress.
+    changed: Condvar,
+    config: Config,
+}
+
+impl Shared {
+    /// Replicate `op` as a proposal of ours, and apply it.
+    fn propose(&self, op: &FsOp) -> Result<(), libc::c_int> {
+        let mut state = self.wait_for_quorum(self.state.lock().unwrap())?;
+
+        state.serial += 1;
+        let serial = state.serial;
+        state.pending.insert(serial);
+        // Everything below our oldest unfinished proposal has been answered,
+        // so the cluster may forget those results.
+        let floor = *state.pending.iter().next().unwrap() - 1;
+        let proposal = Proposal {
+            origin: state.raft.id(),
+            boot: state.boot,
+            serial: serial,
+            floor: floor,
+            op: op.clone(),
+        };
+        let data = proposal.encode();
+
+        // Sending it again is harmless, since it is applied only once, so
+        // we do whenever it may have gone astray.
+        let mut sent_to = None;
+        let mut sent_at = Instant::now() - Duration::from_millis(RESUBMIT);
+        let patience = ::std::cmp::max(self.config.quorum_wait, Duration::from_secs(LEADER_WAIT));
+        let mut lost = None;
+        let result = loop {
+            if let Some(result) = state.results.remove(&serial) {
+                break result;
+            }
+            let leader = state.raft.leader();
+            if leader.is_some() && (leader != sent_to
+                                    || sent_at.elapsed() > Duration::from_millis(RESUBMIT)) {
+                sent_to = state.raft.submit(data.clone());
+                sent_at = Instant::now();
+                self.wake.notify_one();
+            }
+            if state.raft.has_quorum() {
+                lost = None;
+            } else {
+                let since = *lost.get_or_insert_with(Instant::now);
+                if since.elapsed() > patience {
+                    // It may yet be committed, if we were wrong about
+                    // having lost touch, but we can't wait to find out.
+                    error!("lost quorum while committing {:?}", op);
+                    break Err(libc::EIO);
+                }
+            }
+            state = self.changed
+                .wait_timeout(state, Duration::from_millis(100)).unwrap().0;
+        };
+        state.pending.remove(&serial);
+        result
+    }
+
+    /// Wait as long as we are willing to for a quorum, failing with EROFS
+    /// if there isn't one.
+    fn wait_for_quorum<'a>(&self, mut state: MutexGuard<'a, State>)
+                           -> Result<MutexGuard<'a, State>, libc::c_int> {
+        let deadline = Instant::now() + self.config.quorum_wait;
+        loop {
+            if state.raft.has_quorum() {
+                return Ok(state);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                debug!("no quorum, so we are read-only");
+                return Err(libc::EROFS);
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Put a checkpoint in the log, and compare every other member's tree
+    /// as of it with ours, returning its index and what differs.
+    fn verify(&self) -> Result<(LogIndex, Vec<String>), libc::c_int> {
+        self.propose(&FsOp::Checkpoint)?;
+        let (index, ours, members) = {
+            let mut state = self.state.lock().unwrap();
+            // Ours, or one that came after it, which will do as well.
+            let index = state.last_checkpoint;
+            while state.summarizing {
+                state = self.changed.wait(state).unwrap();
+            }
+            let ours = match state.summaries.get(&index) {
+                Some(summary) => summary.clone(),
+                None => {
+                    error!("no summary of our tree as of {} to compare with", index);
+                    return Err(libc::EIO);
+                },
+            };
+            let me = state.raft.id();
+            let members: Vec<Member> = state.raft.membership().members.iter()
+                .filter(|m| m.id != me).cloned().collect();
+            (index, ours, members)
+        };
+        let mut report = Vec::new();
+        for m in members {
+            let compared = m.addr.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "it has no address"))
+                .and_then(|addr| compare_with(&ours, index, &addr));
+            match compared {
+                Ok(differences) => {
+                    report.extend(differences.iter().map(|d| format!("node {}: {}", m.id, d)));
+                },
+                Err(e) => report.push(format!("node {}: unable to compare: {}", m.id, e)),
+            }
+        }
+        Ok((index, report))
+    }
+}
+
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+impl Node {
+    /// Start driving `raft`, applying committed entries to `sm`.
+    pub fn start(mut sm: StateMachine, mut raft: Raft, config: Config) -> Node {
+        if sm.applied() > raft.last_index() {
+            // Our log does not reach what we applied, so the recorded
+            // index refers to some other log.  Start over from this one.
+            warn!("log ends at {} but {} entries were applied; rewinding",
+                  raft.last_index(), sm.applied());
+            sm.rewind(raft.last_index());
+        }
+        if sm.applied() < raft.snapshot_index() {
+            // We stopped after receiving a snapshot but before loading it.
+            if let Err(e) = sm.install(raft.snapshot_index()) {
+                error!("unable to load snapshot through {}: {}", raft.snapshot_index(), e);
+                panic!("unable to load snapshot through {}: {}", raft.snapshot_index(), e);
+            }
+        }
+        raft.set_applied(sm.applied());
+        let blobs = BlobStore::new(sm.target());
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                raft: raft,
+                sm: sm,
+                // Later than any boot before, as long as the clock is.
+                boot: SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+                    .unwrap_or(0),
+                serial: 0,
+                pending: BTreeSet::new(),
+                results: HashMap::new(),
+                next_read: 0,
+                reads: HashSet::new(),
+                read_answers: HashMap::new(),
+                blobs: blobs,
+                offloaded: Vec::new(),
+                fetching: HashSet::new(),
+                wanted: Vec::new(),
+                collected: 0,
+                last_checkpoint: 0,
+                checkpoint: None,
+                summarizing: false,
+                summaries: BTreeMap::new(),
+                inbox: Vec::new(),
+                shutdown: false,
+            }),
+            wake: Condvar::new(),
+            changed: Condvar::new(),
+            config: config,
+        });
+        let driver = shared.clone();
+        let status_file = shared.config.status_file.clone();
+        thread::spawn(move || {
+            let mut state = driver.state.lock().unwrap();
+            let mut noted = None;
+            loop {
+                state = driver.wake.wait_timeout(state, Duration::from_millis(TICK)).unwrap().0;
+                if state.shutdown {
+                    return;
+                }
+                for msg in mem::take(&mut state.inbox) {
+                    state.raft.step(msg);
+                }
+                state.raft.tick();
+                state.raft.flush();
+                state.apply_committed();
+                for (hash, index, from) in mem::take(&mut state.wanted) {
+                    let shared = driver.clone();
+                    let blobs = state.blobs.clone();
+                    thread::spawn(move || fetch_blob(&shared, &blobs, hash, index, &from));
+                }
+                if state.raft.snapshot_index() > state.collected {
+                    state.collect_blobs();
+                }
+                if let Some(index) = state.checkpoint.take() {
+                    let shared = driver.clone();
+                    let target = state.sm.target().to_owned();
+                    thread::spawn(move || summarize(&shared, &target, index));
+                }
+                for (id, index) in state.raft.take_reads() {
+                    if state.reads.remove(&id) {
+                        state.read_answers.insert(id, index);
+                    }
+                }
+                if let Some(ref path) = status_file {
+                    let now = (state.raft.has_quorum(), state.raft.role(), state.raft.term(),
+                               state.raft.leader());
+                    if noted != Some(now) {
+                        if noted.map(|n| n.0) != Some(now.0) {
+                            info!("now {}", if now.0 { "read-write" } else { "read-only" });
+                        }
+                        if let Err(e) = write_status(path, &state.status()) {
+                            warn!("unable to write {:?}: {}", path, e);
+                        }
+                        noted = Some(now);
+                    }
+                }
+                driver.changed.notify_all();
+            }
+        });
+        if let Some(every) = shared.config.verify_every {
+            let verifier = shared.clone();
+            thread::spawn(move || verify_every(&verifier, every));
+        }
+        Node { shared: shared }
+    }
+
+    /// Somewhere for the transport to put incoming messages, and for
+    /// anybody else to find out how we are doing.
+    pub fn handle(&self) -> Handle {
+        Handle { shared: self.shared.clone() }
+    }
+
+    /// Replicate `op` and apply it, returning the result of applying it.
+    pub fn execute(&self, op: &FsOp) -> Result<(), libc::c_int> {
+        match *op {
+            FsOp::Write { ref path, handle, offset, ref data } if data.len() >= BLOB_MIN => {
+                self.execute_blob(path, handle, offset, data)
+            },
+            _ => self.shared.propose(op),
+        }
+    }
+
+    /// Store `data` as a blob, and replicate a write of it.
+    fn execute_blob(&self, path: &Path, handle: Option<fsop::Handle>, offset: u64, data: &[u8])
+                    -> Result<(), libc::c_int> {
+        let hash = blob::hash(data);
+        // Until the write is in our log, only this keeps the blob from
+        // being deleted.
+        let blobs = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.offloaded.push(hash);
+            state.blobs.clone()
+        };
+        let op = FsOp::WriteBlob { path: path.to_owned(), handle: handle, offset: offset, hash: hash };
+        let result = match blobs.put(&hash, data) {
+            Ok(()) => self.shared.propose(&op),
+            Err(e) => {
+                error!("unable to store blob for {:#x} bytes @ {:#x} to {:?}: {}",
+                       data.len(), offset, path, e);
+                Err(libc::EIO)
+            },
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        let i = state.offloaded.iter().position(|h| *h == hash).unwrap();
+        state.offloaded.swap_remove(i);
+        result
+    }
+
+    /// Our own copy of the file we opened as `handle`, by an Open or
+    /// Create that has been applied, if it is still open.
+    pub fn file(&self, handle: fsop::Handle) -> Option<io::Result<File>> {
+        let state = self.shared.state.lock().unwrap();
+        state.sm.open_file(state.raft.id(), state.boot, handle).map(|f| f.try_clone())
+    }
+
+    /// Whether a change made now could be committed, waiting for a quorum
+    /// as `execute` would.
+    pub fn writable(&self) -> Result<(), libc::c_int> {
+        self.shared.wait_for_quorum(self.shared.state.lock().unwrap()).map(|_| ())
+    }
+
+    /// Wait until a read here would see what our consistency promises.
+    pub fn read_barrier(&self) -> Result<(), libc::c_int> {
+        let lease = match self.shared.config.consistency {
+            Consistency::Local => return Ok(()),
+            Consistency::Lease => true,
+            Consistency::Linearizable => false,
+        };
+        let mut state = self.shared.state.lock().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(LEADER_WAIT);
+        let index = loop {
+            let id = state.next_read;
+            state.next_read += 1;
+            state.reads.insert(id);
+            state.raft.read_index(id, lease);
+            self.shared.wake.notify_one();
+
+            let retry = Instant::now() + Duration::from_millis(READ_RETRY);
+            let answer = loop {
+                if let Some(answer) = state.read_answers.remove(&id) {
+                    break answer;
+                }
+                let now = Instant::now();
+                if now >= retry {
+                    // The question or its answer got lost.
+                    state.reads.remove(&id);
+                    break None;
+                }
+                state = self.shared.changed.wait_timeout(state, retry - now).unwrap().0;
+            };
+            if let Some(index) = answer {
+                break index;
+            }
+            if Instant::now() > deadline {
+                error!("no leader to confirm a read (last heard of {:?})", state.raft.leader());
+                return Err(libc::EIO);
+            }
+            state = self.shared.changed
+                .wait_timeout(state, Duration::from_millis(100)).unwrap().0;
+        };
+        // Applying can be held up, by a blob that can't be had or a tree
+        // still being summarized, but no longer than a proposal would be.
+        let patience = ::std::cmp::max(self.shared.config.quorum_wait, Duration::from_secs(LEADER_WAIT));
+        let deadline = Instant::now() + patience;
+        while state.sm.applied() < index {
+            let now = Instant::now();
+            if now >= deadline {
+                error!("gave up waiting to apply through {} for a read (applied {})",
+                       index, state.sm.applied());
+                return Err(libc::EIO);
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        Ok(())
+    }
+
+    /// If we are the leader, hand over to the follower furthest along and
+    /// wait a little for it to take over, so that the rest of the cluster
+    /// need not wait out an election timeout once we are gone.
+    pub fn step_down(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let to = match state.raft.transfer_leadership(None) {
+            Ok(to) => to,
+            Err(_) => return,
+        };
+        self.shared.wake.notify_one();
+        let deadline = Instant::now() + Duration::from_millis(HANDOFF_WAIT);
+        while state.raft.role() == Role::Leader && Instant::now() < deadline {
+            state = self.shared.changed
+                .wait_timeout(state, Duration::from_millis(100)).unwrap().0;
+        }
+        if state.raft.role() == Role::Leader {
+            warn!("node {} did not take over as leader in time", to);
+        } else {
+            info!("handed leadership to node {}", to);
+        }
+    }
+}
+
+/// What `raftfs status` reports about a running node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub id: NodeId,
+    pub role: Role,
+    pub term: Term,
+    pub leader: Option<NodeId>,
+    /// Whether we can commit changes, or are read-only for now.
+    pub quorum: bool,
+    pub commit_index: LogIndex,
+    pub applied: LogIndex,
+    pub members: Vec<Member>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    pub fn deliver(&self, msg: Message) {
+        self.shared.state.lock().unwrap().inbox.push(msg);
+        self.shared.wake.notify_one();
+    }
+
+    pub fn status(&self) -> Status {
+        self.shared.state.lock().unwrap().status()
+    }
+
+    /// Propose adding the node at `addr` to the cluster, as `id` if that
+    /// is given, returning the id it gets and the new membership.  This
+    /// does not wait for the change to be committed.  Asking again for a
+    /// node that is already a member just says so, which lets somebody
+    /// whose answer got lost ask again.
+    pub fn add_member(&self, id: Option<NodeId>, addr: String)
+                      -> Result<(NodeId, Vec<Member>), ChangeError> {
+        let mut state = self.shared.state.lock().unwrap();
+        let members = state.raft.membership().members.clone();
+        if let Some(m) = members.iter().find(|m| m.addr == addr && id.unwrap_or(m.id) == m.id) {
+            return Ok((m.id, members.clone()));
+        }
+        let id = id.unwrap_or_else(|| members.iter().map(|m| m.id).max().unwrap_or(0) + 1);
+        state.raft.add_member(Member { id: id, addr: addr })?;
+        self.shared.wake.notify_one();
+        Ok((id, state.raft.membership().members.clone()))
+    }
+
+    /// Propose removing node `id` from the cluster, without waiting for
+    /// the change to be committed.
+    pub fn remove_member(&self, id: NodeId) -> Result<(), ChangeError> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.raft.remove_member(id)?;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Hand leadership to node `id`, without waiting for it to take over.
+    pub fn transfer_leadership(&self, id: NodeId) -> Result<(), ChangeError> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.raft.transfer_leadership(Some(id))?;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Check that every replica agrees with ours, returning the index of
+    /// the checkpoint we compared them at and what differs.
+    pub fn verify(&self) -> Result<(LogIndex, Vec<String>), libc::c_int> {
+        self.shared.verify()
+    }
+
+    /// What our summary as of checkpoint `index` says about `path`.
+    pub fn summary(&self, index: LogIndex, path: &Path) -> Result<Item, String> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.summarizing && state.last_checkpoint == index {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        match state.summaries.get(&index) {
+            Some(summary) => summary.get(path).cloned()
+                .ok_or_else(|| format!("nothing at {:?} as of {}", path, index)),
+            None => Err(format!("no summary as of {}", index)),
+        }
+    }
+
+    /// The contents of blob `hash`, if we have it.
+    pub fn blob(&self, hash: &Hash) -> Option<Vec<u8>> {
+        let blobs = self.shared.state.lock().unwrap().blobs.clone();
+        blobs.get(hash).ok()
+    }
+}
+
+/// Fetch blob `hash`, which the entry at `index` needs, from the first of
+/// the nodes at `from` that has it, asking until one does, we shut down or
+/// we give up and ask for a snapshot, and wake the background thread so
+/// that it can carry on applying entries.
+fn fetch_blob(shared: &Shared, blobs: &BlobStore, hash: Hash, index: LogIndex, from: &[SocketAddr]) {
+    let name = blob::hex(&hash);
+    let fetched = |addr: &SocketAddr| {
+        let data = match admin::ask(addr, &Request::Blob { hash: hash }) {
+            Ok(Response::Blob(data)) => data,
+            Ok(response) => {
+                debug!("{} has no blob {}: {:?}", addr, name, response);
+                return false;
+            },
+            Err(e) => {
+                debug!("unable to ask {} for blob {}: {}", addr, name, e);
+                return false;
+            },
+        };
+        if blob::hash(&data) != hash {
+            warn!("{} sent a corrupt copy of blob {}", addr, name);
+            return false;
+        }
+        match blobs.put(&hash, &data) {
+            Ok(()) => {
+                debug!("fetched blob {} from {}", name, addr);
+                true
+            },
+            Err(e) => {
+                error!("unable to store blob {}: {}", name, e);
+                false
+            },
+        }
+    };
+    let mut tries = 1;
+    while !from.iter().any(&fetched) {
+        let mut state = shared.state.lock().unwrap();
+        if state.shutdown {
+            return;
+        }
+        if state.sm.applied() >= index {
+            // A snapshot got us past it.
+            break;
+        }
+        if tries == FETCH_TRIES {
+            warn!("nobody has blob {} any more; asking for a snapshot instead", name);
+            state.raft.request_snapshot();
+            break;
+        }
+        drop(state);
+        warn!("nobody could give us blob {}; asking again", name);
+        thread::sleep(Duration::from_millis(FETCH_RETRY));
+        tries += 1;
+    }
+    // If we still need it, we will be back.
+    shared.state.lock().unwrap().fetching.remove(&hash);
+    shared.wake.notify_one();
+}
+
+/// Summarize the tree in `target`, which is as of checkpoint `index`, and
+/// let the background thread carry on applying entries.
+fn summarize(shared: &Shared, target: &Path, index: LogIndex) {
+    let summary = merkle::summarize(target);
+    let mut state = shared.state.lock().unwrap();
+    match summary {
+        Ok(summary) => {
+            state.summaries.insert(index, Arc::new(summary));
+            while state.summaries.len() > SUMMARIES_KEPT {
+                let oldest = *state.summaries.keys().next().unwrap();
+                state.summaries.remove(&oldest);
+            }
+        },
+        Err(e) => error!("unable to summarize {:?} as of {}: {}", target, index, e),
+    }
+    state.summarizing = false;
+    shared.wake.notify_one();
+}
+
+/// Compare `ours` with the summary as of `index` of the node at `addr`,
+/// once it has got that far.
+fn compare_with(ours: &Summary, index: LogIndex, addr: &SocketAddr)
+                -> io::Result<Vec<merkle::Difference>> {
+    let refused = io::Error::other;
+    let deadline = Instant::now() + Duration::from_secs(VERIFY_WAIT);
+    loop {
+        match admin::ask(addr, &Request::Status)? {
+            Response::Status(ref status) if status.applied >= index => break,
+            Response::Status(_) if Instant::now() < deadline => (),
+            Response::Status(_) => return Err(refused(format!("it hasn't reached {}", index))),
+            r => return Err(refused(format!("unexpected answer: {:?}", r))),
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    merkle::compare(ours, |path| {
+        match admin::ask(addr, &Request::Summary { index: index, path: path.to_owned() })? {
+            Response::Summary(item) => Ok(item),
+            Response::Refused(why) => Err(refused(why)),
+            r => Err(refused(format!("unexpected answer: {:?}", r))),
+        }
+    })
+}
+
+/// Check that the replicas agree every `every`, while we are the leader,
+/// until we shut down.
+fn verify_every(shared: &Shared, every: Duration) {
+    loop {
+        thread::sleep(every);
+        {
+            let state = shared.state.lock().unwrap();
+            if state.shutdown {
+                return;
+            }
+            if state.raft.role() != Role::Leader {
+                // It only takes one of us.
+                continue;
+            }
+        }
+        match shared.verify() {
+            Ok((index, ref report)) if report.is_empty() => {
+                info!("every replica agrees as of {}", index);
+            },
+            Ok((index, report)) => {
+                for line in report {
+                    error!("replicas differ as of {}: {}", index, line);
+                }
+            },
+            Err(e) => warn!("unable to verify the replicas: {}", io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Note down whether we are writable, and why.  This is rewritten whenever
+/// that changes, so it leaves out the indices, which change all the time.
+fn write_status(path: &Path, status: &Status) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        writeln!(f, "{}", if status.quorum { "read-write" } else { "read-only" })?;
+        writeln!(f, "node {}", status.id)?;
+        writeln!(f, "role {}", format!("{:?}", status.role).to_lowercase())?;
+        writeln!(f, "term {}", status.term)?;
+        match status.leader {
+            Some(leader) => writeln!(f, "leader {}", leader)?,
+            None => writeln!(f, "leader none")?,
+        }
+    }
+    fs::rename(&tmp, path)
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::admin::Responder;
+    use super::super::archive::ArchiveStore;
+    use super::super::raft::{Config as RaftConfig, HardState, HardStateStore, MemHardState, MemLog,
+                             Membership, SystemClock};
+    use super::super::tcp::{Listener, TcpTransport};
+    use std::ffi::OsString;
+
+    fn wait_for<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Start `n` nodes talking over loopback, each with a backing
+    /// directory of its own.
+    fn cluster(name: &str, n: usize, config: RaftConfig) -> (Vec<Node>, Vec<PathBuf>) {
+        let listeners: Vec<Listener> = (0..n).map(|_| {
+            Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        }).collect();
+        let members: Vec<Member> = listeners.iter().enumerate().map(|(i, l)| {
+            Member { id: i as NodeId + 1, addr: l.local_addr().unwrap().to_string() }
+        }).collect();
+        let membership = Membership { index: 0, members: members.clone() };
+        let mut targets = Vec::new();
+        let nodes = listeners.into_iter().zip(&members).map(|(listener, m)| {
+            let target = ::std::env::temp_dir()
+                .join(format!("raftfs-node-{}-{}-{}", name, m.id, ::std::process::id()));
+            fs::remove_dir_all(&target).ok();
+            fs::create_dir_all(&target).unwrap();
+            let mut hard_state = MemHardState::new();
+            hard_state.save(&HardState { membership: membership.clone(), ..HardState::default() })
+                .unwrap();
+            let raft = Raft::new(m.id, Box::new(MemLog::new()), Box::new(hard_state),
+                                 Box::new(ArchiveStore::open(target.clone()).unwrap()),
+                                 Box::new(TcpTransport::new(&[])),
+                                 Box::new(SystemClock), config.clone());
+            let node = Node::start(StateMachine::open(target.clone()).unwrap(), raft,
+                                   Config { quorum_wait: Duration::from_secs(5), ..Config::default() });
+            let handle = node.handle();
+            let responder = Responder::new(node.handle());
+            listener.serve(move |msg| handle.deliver(msg), move |request| responder.answer(request));
+            targets.push(target);
+            node
+        }).collect();
+        (nodes, targets)
+    }
+
+    fn create(name: &str) -> FsOp {
+        FsOp::Create { parent: PathBuf::from("/"), name: OsString::from(name),
+                       mode: 0o644, flags: libc::O_WRONLY as u32, handle: None }
+    }
+
+    #[test]
+    fn large_writes_travel_as_blobs() {
+        let config = RaftConfig { compact_every: 4, ..RaftConfig::default() };
+        let (nodes, targets) = cluster("blobs", 2, config);
+        assert_eq!(nodes[0].execute(&create("f")), Ok(()));
+        let data: Vec<u8> = (0..BLOB_MIN + 1).map(|i| (i % 251) as u8).collect();
+        let write = FsOp::Write { path: PathBuf::from("/f"), handle: None, offset: 0, data: data.clone() };
+        assert_eq!(nodes[0].execute(&write), Ok(()));
+        assert_eq!(fs::read(targets[0].join("f")).unwrap(), data);
+
+        // The other node had to fetch the blob to apply the write.
+        let hash = blob::hash(&data);
+        wait_for(|| fs::read(targets[1].join("f")).ok() == Some(data.clone()));
+        assert!(BlobStore::new(&targets[1]).has(&hash));
+        {
+            let state = nodes[1].shared.state.lock().unwrap();
+            assert!(state.raft.log_entries().iter().all(|e| e.data.len() < 1024));
+        }
+
+        // Once the log has been compacted past it, nobody keeps it.
+        for _ in 0..8 {
+            let chmod = FsOp::Chmod { path: PathBuf::from("/f"), mode: 0o600 };
+            assert_eq!(nodes[0].execute(&chmod), Ok(()));
+        }
+        wait_for(|| targets.iter().all(|t| !BlobStore::new(t).has(&hash)));
+        drop(nodes);
+        for target in targets {
+            fs::remove_dir_all(&target).ok();
+        }
+    }
+
+    #[test]
+    fn slow_followers_catch_up_without_the_blob() {
+        let config = RaftConfig { compact_every: 4, ..RaftConfig::default() };
+        let (nodes, targets) = cluster("slow", 3, config);
+        assert_eq!(nodes[0].execute(&create("f")), Ok(()));
+        let leader = nodes[0].shared.state.lock().unwrap().raft.leader().unwrap() as usize - 1;
+        let slow = (leader + 1) % 3;
+
+        // The slow node takes the entries but applies nothing, so it only
+        // wants the blob once the others have let it go.
+        nodes[slow].shared.state.lock().unwrap().summarizing = true;
+        let data: Vec<u8> = (0..BLOB_MIN + 1).map(|i| (i % 251) as u8).collect();
+        let write = FsOp::Write { path: PathBuf::from("/f"), handle: None, offset: 0, data: data.clone() };
+        assert_eq!(nodes[leader].execute(&write), Ok(()));
+        for _ in 0..8 {
+            let chmod = FsOp::Chmod { path: PathBuf::from("/f"), mode: 0o600 };
+            assert_eq!(nodes[leader].execute(&chmod), Ok(()));
+        }
+        let hash = blob::hash(&data);
+        wait_for(|| targets.iter().all(|t| !BlobStore::new(t).has(&hash)));
+
+        // Nobody has the blob now, so it has to make do with a snapshot.
+        {
+            let mut state = nodes[slow].shared.state.lock().unwrap();
+            state.summarizing = false;
+            nodes[slow].shared.wake.notify_one();
+        }
+        wait_for(|| fs::read(targets[slow].join("f")).ok() == Some(data.clone()));
+        drop(nodes);
+        for target in targets {
+            fs::remove_dir_all(&target).ok();
+        }
+    }
+
+    #[test]
+    fn differing_replicas_are_found() {
+        let (nodes, targets) = cluster("verify", 3, RaftConfig::default());
+        let handle = nodes[1].handle();
+        assert_eq!(nodes[0].execute(&create("f")), Ok(()));
+        let write = FsOp::Write { path: PathBuf::from("/f"), handle: None, offset: 0, data: b"same".to_vec() };
+        assert_eq!(nodes[0].execute(&write), Ok(()));
+        let (first, report) = handle.verify().unwrap();
+        assert_eq!(report, Vec::<String>::new());
+
+        // Going behind raft's back makes a replica differ.
+        fs::write(targets[2].join("f"), b"different").unwrap();
+        fs::write(targets[0].join("g"), b"").unwrap();
+        let (second, report) = handle.verify().unwrap();
+        assert!(second > first);
+        assert_eq!(report, vec!["node 1: \"/g\" should not be there".to_string(),
+                                "node 3: \"/f\" differs".to_string()]);
+        drop(nodes);
+        for target in targets {
+            fs::remove_dir_all(&target).ok();
+        }
+    }
+}
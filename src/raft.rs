@@ -0,0 +1,2301 @@
+// Raft :: Leader election and log replication for keeping the mirrors coherent.
+//
+// The consensus core knows nothing about filesystems or sockets.  It is
+// driven by calling `tick` and `step`, and it talks to the outside world
+// only through a `Transport` and a `Clock`, so that a whole cluster can be
+// run deterministically inside a single process.
+//
+// Membership changes one server at a time, as in section 4.1 of Ongaro's
+// thesis.  A membership entry takes effect as soon as it is in a node's
+// log, committed or not, and a leader proposes a new one only once the
+// last has been committed.  Any majority of the old membership then
+// overlaps any majority of the new one, so there cannot be two leaders in
+// a term.  The membership as of the start of the log is saved with the
+// hard state, since compaction throws away the entry that set it.
+//
+// Reads that must see every committed write ask the leader for a read
+// index, as in section 6.4 of the thesis.  The leader numbers its rounds
+// of AppendEntries, and answers once a majority has replied to a round
+// sent after the question arrived, which shows nobody else was leader in
+// the meantime.  The same replies give the leader a lease: a node that
+// has heard from a leader within the election timeout ignores requests
+// for votes, so until slightly less than that has passed since the round a
+// majority answered, nobody else can be elected and the leader may answer
+// without a round.  That last relies on clocks running at about the same
+// rate.
+//
+// A node whose election timer runs out first asks for pre-votes, as in
+// section 9.6 of the thesis: would the others vote for it in the next
+// term?  Nobody changes anything to answer, and only with a majority's
+// yes does it raise its term and stand for real, so a node that cannot
+// reach a majority never disrupts the rest.  The leader, for its part,
+// steps down once it has gone an election timeout without hearing from a
+// majority (check-quorum), so that it cannot hold on to clients it can
+// no longer serve.
+//
+// A leader can hand over to a chosen follower, as in section 3.10 of the
+// thesis: it stops taking proposals, brings the follower up to date and
+// then tells it to start an election at once.  Its request for votes says
+// so, so that nodes answer it even though they have heard from a leader.
+//
+// Every node folds what it has applied into a snapshot once enough entries
+// have piled up, and forgets the log the snapshot covers when it is ready.
+// A follower that needs entries from before that point gets the snapshot.
+//
+// Copyright (c) 2017 by David Roundy
+//
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+
+use super::codec::{self, DecodeError, Reader};
+
+use time;
+
+pub type NodeId = u64;
+pub type Term = u64;
+pub type LogIndex = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Data for the state machine, which is opaque to raft.  A new
+    /// leader's entry has no data at all.
+    Normal,
+    /// A new membership for the cluster, as encoded by
+    /// `Membership::encode`.
+    Membership,
+}
+
+impl EntryKind {
+    pub fn tag(self) -> u8 {
+        match self {
+            EntryKind::Normal => 0,
+            EntryKind::Membership => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<EntryKind, DecodeError> {
+        match tag {
+            0 => Ok(EntryKind::Normal),
+            1 => Ok(EntryKind::Membership),
+            t => Err(DecodeError::UnknownTag(t)),
+        }
+    }
+}
+
+/// A single entry in the replicated log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub term: Term,
+    pub index: LogIndex,
+    pub kind: EntryKind,
+    pub data: Vec<u8>,
+}
+
+/// A member of the cluster.  Raft only cares about the id; the address is
+/// for the transport.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub id: NodeId,
+    pub addr: String,
+}
+
+/// Who is in the cluster, as set by the membership entry at `index`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Membership {
+    pub index: LogIndex,
+    pub members: Vec<Member>,
+}
+
+impl Membership {
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.members.iter().any(|m| m.id == id)
+    }
+
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.members.iter().map(|m| m.id).collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        codec::put_u64(&mut out, self.index);
+        codec::put_u32(&mut out, self.members.len() as u32);
+        for m in &self.members {
+            codec::put_u64(&mut out, m.id);
+            codec::put_bytes(&mut out, m.addr.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Membership, DecodeError> {
+        let mut r = Reader::new(data);
+        let index = r.u64()?;
+        let mut members = Vec::new();
+        for _ in 0..r.u32()? {
+            let id = r.u64()?;
+            let addr = String::from_utf8(r.bytes()?).map_err(|_| DecodeError::Invalid("address"))?;
+            members.push(Member { id: id, addr: addr });
+        }
+        r.finish()?;
+        Ok(Membership { index: index, members: members })
+    }
+}
+
+/// Why a membership change was not proposed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeError {
+    /// Only the leader can change the membership; this is who we think
+    /// that is.
+    NotLeader(Option<NodeId>),
+    /// The last change is not committed yet, or this leader has yet to
+    /// commit anything in its term.  Try again shortly.
+    Busy,
+    /// The node is already a member, or is not one to remove or hand
+    /// leadership to.
+    NoChange,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rpc {
+    RequestVote {
+        last_log_index: LogIndex,
+        last_log_term: Term,
+        // The leader asked us to stand, so this should be answered even
+        // by nodes that have heard from it lately.
+        transfer: bool,
+    },
+    RequestVoteReply {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry>,
+        leader_commit: LogIndex,
+        // Which of the leader's rounds this belongs to.
+        seq: u64,
+    },
+    AppendEntriesReply {
+        success: bool,
+        // On success, the index of the last entry the follower now
+        // shares with the leader.  On failure, a hint for where the
+        // leader should back up to.
+        match_index: LogIndex,
+        // The seq of the AppendEntries this answers.
+        seq: u64,
+    },
+    /// A piece of the leader's snapshot, for a follower that is too far
+    /// behind to be caught up from the log.
+    InstallSnapshot {
+        last_index: LogIndex,
+        last_term: Term,
+        // The membership as of last_index.
+        membership: Membership,
+        offset: u64,
+        data: Vec<u8>,
+        done: bool,
+    },
+    InstallSnapshotReply {
+        last_index: LogIndex,
+        // How much of the snapshot the follower now has, which is where
+        // the next piece should start.
+        offset: u64,
+        // The follower has all of it, and wants entries that follow it.
+        done: bool,
+    },
+    /// What must a read wait for?  Sent to the leader, which may answer
+    /// from its lease if `lease` is set.
+    ReadIndex {
+        id: u64,
+        lease: bool,
+    },
+    /// Reads after `index` is applied see every write committed before
+    /// the question was asked.  None if we asked somebody who is not the
+    /// leader.
+    ReadIndexReply {
+        id: u64,
+        index: Option<LogIndex>,
+    },
+    /// Please append this to the log, for a node that is not the leader.
+    Propose {
+        data: Vec<u8>,
+    },
+    /// The leader is handing over to us: start an election right away.
+    TimeoutNow,
+    /// Would you vote for us in the term this is sent with?  Sent before
+    /// starting an election, without changing our own term.
+    PreVote {
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    /// Sent with the term asked about if granted, and otherwise with our
+    /// own term.
+    PreVoteReply {
+        granted: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: Term,
+    pub rpc: Rpc,
+}
+
+/// Where the log is kept.  Indices start at 1.
+pub trait LogStore: Send {
+    /// The index of the last entry, or 0 if there are none.
+    fn last_index(&self) -> LogIndex;
+    /// The term of the entry at `index`, if we have it.
+    fn term(&self, index: LogIndex) -> io::Result<Option<Term>>;
+    /// The entries from `lo` through `hi`, inclusive.
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> io::Result<Vec<Entry>>;
+    /// Add entries, which must follow on from `last_index`.  They need not
+    /// be durable until `sync` is called.
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()>;
+    /// Discard the entry at `index` and everything after it.
+    fn truncate(&mut self, index: LogIndex) -> io::Result<()>;
+    /// Make everything appended so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+    /// The index and term of the last entry covered by a snapshot rather
+    /// than kept in the log, or (0, 0).  The log starts just after it.
+    fn base(&self) -> (LogIndex, Term);
+    /// Forget the entries through `index`, which a snapshot now covers;
+    /// the entry at `index` had `term`.  Later entries are kept only if
+    /// the log agrees with the snapshot about `index`.  This must be
+    /// durable by the time it returns.
+    fn compact(&mut self, index: LogIndex, term: Term) -> io::Result<()>;
+}
+
+/// A log that lives only as long as the process does.
+#[cfg(test)]
+pub struct MemLog {
+    base: (LogIndex, Term),
+    entries: Vec<Entry>,
+}
+
+#[cfg(test)]
+impl MemLog {
+    pub fn new() -> MemLog {
+        MemLog { base: (0, 0), entries: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl LogStore for MemLog {
+    fn last_index(&self) -> LogIndex {
+        self.base.0 + self.entries.len() as LogIndex
+    }
+    fn term(&self, index: LogIndex) -> io::Result<Option<Term>> {
+        if index == 0 || index < self.base.0 {
+            return Ok(None);
+        }
+        if index == self.base.0 {
+            return Ok(Some(self.base.1));
+        }
+        Ok(self.entries.get((index - self.base.0) as usize - 1).map(|e| e.term))
+    }
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> io::Result<Vec<Entry>> {
+        assert!(lo > self.base.0);
+        Ok(self.entries[(lo - self.base.0) as usize - 1..(hi - self.base.0) as usize].to_vec())
+    }
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+    fn truncate(&mut self, index: LogIndex) -> io::Result<()> {
+        self.entries.truncate((index - self.base.0) as usize - 1);
+        Ok(())
+    }
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn base(&self) -> (LogIndex, Term) {
+        self.base
+    }
+    fn compact(&mut self, index: LogIndex, term: Term) -> io::Result<()> {
+        if index <= self.base.0 {
+            return Ok(());
+        }
+        if self.term(index)? == Some(term) {
+            let k = (index - self.base.0) as usize;
+            self.entries.drain(..k);
+        } else {
+            self.entries.clear();
+        }
+        self.base = (index, term);
+        Ok(())
+    }
+}
+
+/// What raft must remember across a restart besides the log.  It has to
+/// be on disk before we tell anybody about it, or we could vote twice in
+/// one term.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HardState {
+    pub term: Term,
+    pub voted_for: Option<NodeId>,
+    /// The membership as of the start of the log.  Later changes are in
+    /// the log itself.
+    pub membership: Membership,
+}
+
+pub trait HardStateStore: Send {
+    /// What was last saved, or the default if nothing ever was.
+    fn load(&self) -> HardState;
+    /// Durably replace what is stored.
+    fn save(&mut self, state: &HardState) -> io::Result<()>;
+}
+
+/// Hard state that lives only as long as the process does.
+#[cfg(test)]
+pub struct MemHardState(HardState);
+
+#[cfg(test)]
+impl MemHardState {
+    pub fn new() -> MemHardState {
+        MemHardState(HardState::default())
+    }
+
+    /// Hard state for a node of a cluster that starts out as `members`.
+    pub fn with_members(members: &[NodeId]) -> MemHardState {
+        let members = members.iter().map(|&id| Member { id: id, addr: String::new() }).collect();
+        MemHardState(HardState {
+            membership: Membership { index: 0, members: members },
+            ..HardState::default()
+        })
+    }
+}
+
+#[cfg(test)]
+impl HardStateStore for MemHardState {
+    fn load(&self) -> HardState {
+        self.0.clone()
+    }
+    fn save(&mut self, state: &HardState) -> io::Result<()> {
+        self.0 = state.clone();
+        Ok(())
+    }
+}
+
+/// Where snapshots of the state machine are kept: for a leader to send to
+/// followers that need entries it no longer has in its log, and for a
+/// follower to receive them into.  A snapshot is just bytes to raft.
+pub trait SnapshotStore: Send {
+    /// The last index and term, and the size, of the snapshot we can send.
+    fn current(&mut self) -> Option<(LogIndex, Term, u64)>;
+    /// Start taking a snapshot of the state machine as it is now, with
+    /// everything through `index` applied.  It becomes current when it is
+    /// ready.  This is ignored while another is being taken.
+    fn take(&mut self, index: LogIndex, term: Term);
+    /// Part of the current snapshot.
+    fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+    /// Store part of the snapshot through `index` that a leader is sending
+    /// us, returning how much of it we now have.  A piece that does not
+    /// follow on from what we have is ignored, except that offset 0 always
+    /// starts over.
+    fn receive(&mut self, index: LogIndex, offset: u64, data: &[u8]) -> io::Result<u64>;
+    /// All of the snapshot has been received: make it durable and current.
+    fn finish(&mut self, index: LogIndex, term: Term) -> io::Result<()>;
+}
+
+/// Snapshots that live only as long as the process does.  What it takes
+/// a snapshot of is whatever `state` holds at the time.
+#[cfg(test)]
+pub struct MemSnapshots {
+    pub state: Vec<u8>,
+    current: Option<(LogIndex, Term, Vec<u8>)>,
+    incoming: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MemSnapshots {
+    pub fn new() -> MemSnapshots {
+        MemSnapshots { state: Vec::new(), current: None, incoming: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl SnapshotStore for MemSnapshots {
+    fn current(&mut self) -> Option<(LogIndex, Term, u64)> {
+        self.current.as_ref().map(|&(index, term, ref data)| (index, term, data.len() as u64))
+    }
+    fn take(&mut self, index: LogIndex, term: Term) {
+        self.current = Some((index, term, self.state.clone()));
+    }
+    fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let data = &self.current.as_ref().unwrap().2;
+        Ok(data[offset as usize..offset as usize + len].to_vec())
+    }
+    fn receive(&mut self, _index: LogIndex, offset: u64, data: &[u8]) -> io::Result<u64> {
+        if offset == 0 {
+            self.incoming.clear();
+        }
+        if offset == self.incoming.len() as u64 {
+            self.incoming.extend_from_slice(data);
+        }
+        Ok(self.incoming.len() as u64)
+    }
+    fn finish(&mut self, index: LogIndex, term: Term) -> io::Result<()> {
+        self.state = mem::take(&mut self.incoming);
+        self.current = Some((index, term, self.state.clone()));
+        Ok(())
+    }
+}
+
+/// Whatever carries messages between nodes.  Delivery may be lossy,
+/// delayed and out of order; raft copes with all of that.
+pub trait Transport: Send {
+    fn send(&mut self, msg: Message);
+    /// Everybody we may need to send to, which changes with the
+    /// membership.
+    fn set_peers(&mut self, _peers: &[Member]) {}
+}
+
+/// A monotonic clock in milliseconds.
+pub trait Clock: Send {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        time::precise_time_ns() / 1_000_000
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Minimum election timeout in milliseconds.  The actual timeout is
+    /// randomized between this and twice this.
+    pub election_timeout: u64,
+    /// How often a leader sends AppendEntries when there is nothing new.
+    pub heartbeat_interval: u64,
+    /// The most entries we put into a single AppendEntries.
+    pub max_entries_per_message: usize,
+    /// The most snapshot bytes we put into a single InstallSnapshot.
+    pub max_snapshot_chunk: usize,
+    /// How many applied entries the log may hold beyond the last snapshot
+    /// before we take another and compact the log, or 0 never to.
+    pub compact_every: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            election_timeout: 300,
+            heartbeat_interval: 50,
+            max_entries_per_message: 64,
+            max_snapshot_chunk: 1 << 20,
+            compact_every: 10000,
+        }
+    }
+}
+
+struct PendingRead {
+    from: NodeId,
+    id: u64,
+    // The round a majority must answer.
+    seq: u64,
+}
+
+pub struct Raft {
+    id: NodeId,
+    // The membership as of the start of the log, and then one for each
+    // membership entry in the log.  The last is the one in effect.
+    memberships: Vec<Membership>,
+    config: Config,
+    transport: Box<dyn Transport>,
+    clock: Box<dyn Clock>,
+    // Messages wait here until the log has been synced.
+    outbox: Vec<Message>,
+
+    role: Role,
+    term: Term,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+
+    log: Box<dyn LogStore>,
+    hard_state: Box<dyn HardStateStore>,
+    // What hard_state currently holds.
+    saved: HardState,
+    snapshots: Box<dyn SnapshotStore>,
+    // A snapshot we have received, which the state machine must load
+    // before applying anything else.
+    installed: Option<LogIndex>,
+    // We have committed entries that can't be applied, and need the
+    // leader's snapshot to get past them.
+    want_snapshot: bool,
+    // The last index known to be on disk.
+    stable_index: LogIndex,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    // Where this leader's first entry went; it may not change the
+    // membership until that is committed.
+    term_start: LogIndex,
+
+    next_index: HashMap<NodeId, LogIndex>,
+    match_index: HashMap<NodeId, LogIndex>,
+    // Peers whose logs are known to match ours up to next_index.  We send
+    // these new entries without waiting to hear about the previous ones.
+    replicating: HashSet<NodeId>,
+    // How much of which snapshot each peer that needs one has so far.
+    snapshot_progress: HashMap<NodeId, (LogIndex, u64)>,
+    votes: HashSet<NodeId>,
+    // We are a follower asking for pre-votes, which go in `votes`.
+    pre_voting: bool,
+    // Who we are handing leadership to, and when we give up trying.
+    transfer: Option<(NodeId, u64)>,
+
+    // The number of the leader's latest round of AppendEntries, with when
+    // each round that might yet extend the lease was sent.
+    seq: u64,
+    rounds: VecDeque<(u64, u64)>,
+    // The latest round each peer has answered.
+    acked: HashMap<NodeId, u64>,
+    // Until when no other leader can be elected.
+    lease_until: u64,
+    // Rounds sent before this can't extend the lease, since we may have
+    // told somebody to take over meanwhile.
+    lease_from: u64,
+    // When the latest round a majority answered was sent.
+    quorum_at: u64,
+    // Reads waiting for a majority to answer a round.
+    reads: Vec<PendingRead>,
+    // Answers to our own reads, for take_reads.
+    read_answers: Vec<(u64, Option<LogIndex>)>,
+    // When we last heard from the leader.
+    heard_at: u64,
+
+    election_deadline: u64,
+    heartbeat_deadline: u64,
+    rng: u64,
+}
+
+impl Raft {
+    /// Create a follower.  Who else is in the cluster is found in the
+    /// hard state and the log; a node that is not yet a member waits to
+    /// hear from a leader.
+    pub fn new(id: NodeId, log: Box<dyn LogStore>, hard_state: Box<dyn HardStateStore>,
+               snapshots: Box<dyn SnapshotStore>, transport: Box<dyn Transport>,
+               clock: Box<dyn Clock>, config: Config) -> Raft {
+        let stable_index = log.last_index();
+        // Everything a snapshot covers was committed and applied.
+        let base = log.base().0;
+        let saved = hard_state.load();
+        let mut raft = Raft {
+            id: id,
+            memberships: vec![saved.membership.clone()],
+            config: config,
+            transport: transport,
+            clock: clock,
+            outbox: Vec::new(),
+            role: Role::Follower,
+            term: 0,
+            voted_for: saved.voted_for,
+            leader: None,
+            log: log,
+            hard_state: hard_state,
+            saved: saved.clone(),
+            snapshots: snapshots,
+            installed: None,
+            want_snapshot: false,
+            stable_index: stable_index,
+            commit_index: base,
+            last_applied: base,
+            term_start: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            replicating: HashSet::new(),
+            snapshot_progress: HashMap::new(),
+            votes: HashSet::new(),
+            pre_voting: false,
+            transfer: None,
+            seq: 0,
+            rounds: VecDeque::new(),
+            acked: HashMap::new(),
+            lease_until: 0,
+            lease_from: 0,
+            quorum_at: 0,
+            reads: Vec::new(),
+            read_answers: Vec::new(),
+            heard_at: 0,
+            election_deadline: 0,
+            heartbeat_deadline: 0,
+            // Any odd constant will do; mixing in the id keeps nodes
+            // from timing out in lockstep.
+            rng: 0x9e3779b97f4a7c15 ^ id.wrapping_mul(0xbf58476d1ce4e5b9),
+        };
+        raft.term = saved.term;
+        if raft.last_term() > raft.term {
+            // Only possible if the hard state was lost; we can at least
+            // be sure the term is no earlier than the log says.
+            warn!("raft {}: log reaches term {} but hard state says {}",
+                  id, raft.last_term(), raft.term);
+            raft.term = raft.last_term();
+            raft.voted_for = None;
+        }
+        // We save the membership at the start of the log before
+        // compacting it, so it may be ahead of the log's base.
+        let from = cmp::max(base, raft.memberships[0].index) + 1;
+        let last = raft.last_index();
+        for e in raft.entries(from, last) {
+            raft.note_membership(&e);
+        }
+        raft.membership_changed();
+        raft.reset_election_timer();
+        raft
+    }
+
+    pub fn id(&self) -> NodeId { self.id }
+    pub fn role(&self) -> Role { self.role }
+    pub fn term(&self) -> Term { self.term }
+    pub fn leader(&self) -> Option<NodeId> { self.leader }
+    pub fn commit_index(&self) -> LogIndex { self.commit_index }
+
+    /// Can we expect what we propose to be committed?  A leader can if a
+    /// majority has answered it within the election timeout, and a
+    /// follower can if it has heard from its leader in that time.
+    pub fn has_quorum(&self) -> bool {
+        match self.role {
+            Role::Leader => self.clock.now() < self.quorum_at + self.config.election_timeout,
+            _ => self.leader_is_alive(),
+        }
+    }
+
+    /// The membership in effect, which may not be committed yet.
+    pub fn membership(&self) -> &Membership {
+        self.memberships.last().unwrap()
+    }
+
+    /// Every member but us.
+    pub fn peers(&self) -> Vec<NodeId> {
+        self.membership().ids().into_iter().filter(|&p| p != self.id).collect()
+    }
+
+    pub fn last_index(&self) -> LogIndex {
+        self.log.last_index()
+    }
+
+    /// The last index covered by a snapshot instead of the log.
+    pub fn snapshot_index(&self) -> LogIndex {
+        self.log.base().0
+    }
+
+    fn last_term(&self) -> Term {
+        self.term_at(self.last_index())
+    }
+
+    fn term_at(&self, index: LogIndex) -> Term {
+        if index == 0 {
+            return 0;
+        }
+        match self.log.term(index) {
+            Ok(Some(term)) => term,
+            Ok(None) => panic!("raft {}: no entry {} in the log", self.id, index),
+            Err(e) => self.fatal("read log", e),
+        }
+    }
+
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> Vec<Entry> {
+        if lo > hi {
+            return Vec::new();
+        }
+        self.log.entries(lo, hi).unwrap_or_else(|e| self.fatal("read log", e))
+    }
+
+    fn append(&mut self, entries: &[Entry]) {
+        if let Err(e) = self.log.append(entries) {
+            self.fatal("append to log", e);
+        }
+        let before = self.memberships.len();
+        for e in entries {
+            self.note_membership(e);
+        }
+        if self.memberships.len() != before {
+            self.membership_changed();
+        }
+    }
+
+    fn note_membership(&mut self, e: &Entry) {
+        if e.kind == EntryKind::Membership {
+            let membership = Membership::decode(&e.data).unwrap_or_else(|err| {
+                self.fatal("read log", io::Error::new(io::ErrorKind::InvalidData, err))
+            });
+            self.memberships.push(membership);
+        }
+    }
+
+    /// The membership in effect once the log reached `index`.
+    fn membership_at(&self, index: LogIndex) -> Membership {
+        self.memberships.iter().rev().find(|m| m.index <= index)
+            .unwrap_or(&self.memberships[0]).clone()
+    }
+
+    fn membership_changed(&mut self) {
+        debug!("raft {}: members are now {:?}", self.id, self.membership().ids());
+        if self.membership().members.is_empty() {
+            // We have yet to hear who is in the cluster, so leave the
+            // transport however it was set up.
+            return;
+        }
+        let id = self.id;
+        let peers: Vec<Member> = self.membership().members.iter()
+            .filter(|m| m.id != id).cloned().collect();
+        self.transport.set_peers(&peers);
+    }
+
+    /// We cannot keep our promises without a working log, so there is
+    /// nothing sensible to do but stop.
+    fn fatal(&self, what: &str, e: io::Error) -> ! {
+        error!("raft {}: unable to {}: {}", self.id, what, e);
+        panic!("raft {}: unable to {}: {}", self.id, what, e);
+    }
+
+    fn quorum(&self) -> usize {
+        self.membership().members.len() / 2 + 1
+    }
+
+    fn random(&mut self) -> u64 {
+        // xorshift64*, which is plenty for jittering timeouts.
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn reset_election_timer(&mut self) {
+        let jitter = self.random() % cmp::max(self.config.election_timeout, 1);
+        self.election_deadline = self.clock.now() + self.config.election_timeout + jitter;
+    }
+
+    fn send(&mut self, to: NodeId, rpc: Rpc) {
+        let term = self.term;
+        self.send_with_term(to, term, rpc);
+    }
+
+    fn send_with_term(&mut self, to: NodeId, term: Term, rpc: Rpc) {
+        let msg = Message {
+            from: self.id,
+            to: to,
+            term: term,
+            rpc: rpc,
+        };
+        self.outbox.push(msg);
+    }
+
+    /// Make the log and hard state durable.
+    fn persist(&mut self) {
+        if self.stable_index != self.last_index() || !self.outbox.is_empty() {
+            if let Err(e) = self.log.sync() {
+                self.fatal("sync log", e);
+            }
+            self.stable_index = self.last_index();
+        }
+        self.save_hard_state();
+    }
+
+    fn save_hard_state(&mut self) {
+        let state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            membership: self.memberships[0].clone(),
+        };
+        if state != self.saved {
+            if let Err(e) = self.hard_state.save(&state) {
+                self.fatal("save hard state", e);
+            }
+            self.saved = state;
+        }
+    }
+
+    /// Persist everything and then send whatever messages are waiting.
+    /// Everything appended since the last flush shares a single sync, so
+    /// call this once after handling a batch of work rather than after
+    /// each step.
+    pub fn flush(&mut self) {
+        if self.role == Role::Leader && self.reads.iter().any(|r| r.seq > self.seq) {
+            // Start the round that reads are waiting on, once for all of
+            // them.
+            self.broadcast_append();
+        }
+        self.persist();
+        if self.role == Role::Leader {
+            self.advance_commit();
+            self.confirm_reads();
+        }
+        for msg in mem::take(&mut self.outbox) {
+            self.transport.send(msg);
+        }
+    }
+
+    /// Advance timers.  Call this regularly; how often only affects how
+    /// precisely the timeouts are honored.
+    pub fn tick(&mut self) {
+        let now = self.clock.now();
+        match self.role {
+            Role::Leader => {
+                if !self.has_quorum() {
+                    info!("raft {}: stepping down, having lost touch with a majority", self.id);
+                    let term = self.term;
+                    self.become_follower(term, None);
+                    self.reset_election_timer();
+                    return;
+                }
+                if now >= self.heartbeat_deadline {
+                    self.broadcast_append();
+                }
+                if let Some((to, deadline)) = self.transfer {
+                    if now >= deadline {
+                        info!("raft {}: gave up handing leadership to {}", self.id, to);
+                        self.transfer = None;
+                        self.lease_from = now;
+                    }
+                }
+            },
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    if self.membership().contains(self.id) {
+                        self.start_pre_vote();
+                    } else {
+                        // Not our place to lead; wait to hear from a leader.
+                        self.reset_election_timer();
+                    }
+                }
+            },
+        }
+        self.maybe_compact();
+    }
+
+    /// Take a snapshot if the log has grown long enough since the last,
+    /// and compact the log once it is ready.  The caller applies what
+    /// `take_committed` hands back before the next `tick`, so the state
+    /// machine is as of `last_applied` here.
+    fn maybe_compact(&mut self) {
+        let every = self.config.compact_every;
+        if every == 0 || self.installed.is_some() {
+            return;
+        }
+        let base = self.snapshot_index();
+        match self.snapshots.current() {
+            Some((index, term, _)) if index >= base + every && index <= self.last_applied => {
+                info!("raft {}: compacting the log through {}", self.id, index);
+                let membership = self.membership_at(index);
+                self.compact_log(index, term, membership);
+            },
+            _ if self.last_applied >= base + every => {
+                let applied = self.last_applied;
+                let term = self.term_at(applied);
+                self.snapshots.take(applied, term);
+            },
+            _ => (),
+        }
+    }
+
+    /// Append `data` to the log if we are the leader, returning the index
+    /// it will be committed at.  Otherwise return whoever we believe the
+    /// leader to be.
+    pub fn propose(&mut self, data: Vec<u8>) -> Result<LogIndex, Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        if let Some((to, _)) = self.transfer {
+            // Whatever we take on now, the new leader would have to finish.
+            return Err(Some(to));
+        }
+        let index = self.last_index() + 1;
+        let term = self.term;
+        self.append(&[Entry { term: term, index: index, kind: EntryKind::Normal, data: data }]);
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// Propose `data`, by way of the leader if that is somebody else,
+    /// returning who that was, or None if we know of no leader.  Nobody
+    /// says whether a forwarded proposal made it into the log, so the
+    /// caller should watch for it to be committed and submit it again if
+    /// that takes too long.
+    pub fn submit(&mut self, data: Vec<u8>) -> Option<NodeId> {
+        if self.role == Role::Leader {
+            self.propose(data).ok()?;
+            return Some(self.id);
+        }
+        let leader = self.leader?;
+        self.send(leader, Rpc::Propose { data: data });
+        Some(leader)
+    }
+
+    /// Propose adding `member` to the cluster, returning the index of the
+    /// membership entry.  The new member counts towards majorities from
+    /// then on, so it had better be started soon.
+    pub fn add_member(&mut self, member: Member) -> Result<LogIndex, ChangeError> {
+        if self.membership().contains(member.id) {
+            return Err(ChangeError::NoChange);
+        }
+        let mut members = self.membership().members.clone();
+        members.push(member);
+        self.change_membership(members)
+    }
+
+    /// Propose removing `id` from the cluster, returning the index of the
+    /// membership entry.  A leader that removes itself steps down once
+    /// the change is committed.
+    pub fn remove_member(&mut self, id: NodeId) -> Result<LogIndex, ChangeError> {
+        if !self.membership().contains(id) {
+            return Err(ChangeError::NoChange);
+        }
+        let members = self.membership().members.iter().filter(|m| m.id != id).cloned().collect();
+        self.change_membership(members)
+    }
+
+    fn change_membership(&mut self, members: Vec<Member>) -> Result<LogIndex, ChangeError> {
+        if self.role != Role::Leader {
+            return Err(ChangeError::NotLeader(self.leader));
+        }
+        if self.membership().index > self.commit_index || self.term_start > self.commit_index
+            || self.transfer.is_some() {
+            return Err(ChangeError::Busy);
+        }
+        let index = self.last_index() + 1;
+        let term = self.term;
+        let data = Membership { index: index, members: members }.encode();
+        self.append(&[Entry { term: term, index: index, kind: EntryKind::Membership, data: data }]);
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// Hand back every entry that has been committed since the last
+    /// call, in order.  The caller is expected to apply them.
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let entries = self.entries(self.last_applied + 1, self.commit_index);
+        self.last_applied = self.commit_index;
+        entries
+    }
+
+    /// The caller could not yet apply the entries from `index` on that
+    /// `take_committed` handed it, so they are handed back next time.
+    pub fn defer_from(&mut self, index: LogIndex) {
+        assert!(index > self.snapshot_index() && index <= self.last_applied + 1);
+        self.last_applied = index - 1;
+    }
+
+    /// The caller can't apply the entry after `last_applied` (what it needs
+    /// to has gone from everywhere it could be had), so ask the leader for
+    /// its snapshot to skip over it, as a follower that is too far behind
+    /// would be sent.
+    pub fn request_snapshot(&mut self) {
+        if self.role == Role::Leader {
+            warn!("raft {}: only a follower can be sent a snapshot", self.id);
+            return;
+        }
+        info!("raft {}: asking for a snapshot past {}", self.id, self.last_applied);
+        self.want_snapshot = true;
+    }
+
+    /// Every entry still in the log, rather than covered by the snapshot.
+    pub fn log_entries(&self) -> Vec<Entry> {
+        self.entries(self.snapshot_index() + 1, self.last_index())
+    }
+
+    /// If we have been sent a snapshot, the index it runs through.  The
+    /// caller must load it (it is the store's current snapshot) before
+    /// applying anything from `take_committed`.
+    pub fn take_installed(&mut self) -> Option<LogIndex> {
+        self.installed.take()
+    }
+
+    /// Ask what a read must wait for, answering through `take_reads` with
+    /// `id`.  A leader confirms it is still leader first, unless `lease`
+    /// is set and it holds a lease; anybody else asks the leader.  The
+    /// answer is None if there is no leader to ask, and may never come if
+    /// a message is lost, so the caller should ask again after a while.
+    pub fn read_index(&mut self, id: u64, lease: bool) {
+        if self.role == Role::Leader {
+            let me = self.id;
+            self.handle_read_index(me, id, lease);
+        } else if let Some(leader) = self.leader {
+            self.send(leader, Rpc::ReadIndex { id: id, lease: lease });
+        } else {
+            self.read_answers.push((id, None));
+        }
+    }
+
+    /// Hand back the answers to `read_index` that have come in since the
+    /// last call.
+    pub fn take_reads(&mut self) -> Vec<(u64, Option<LogIndex>)> {
+        mem::take(&mut self.read_answers)
+    }
+
+    /// Tell raft that everything through `index` has already been applied
+    /// (and therefore committed), as after a restart.
+    pub fn set_applied(&mut self, index: LogIndex) {
+        assert!(index <= self.last_index());
+        assert!(index >= self.snapshot_index());
+        self.last_applied = index;
+        self.commit_index = cmp::max(self.commit_index, index);
+    }
+
+    /// Hand leadership to `to`, or to whichever follower is furthest
+    /// along if that is None, returning who it goes to.  The handover is
+    /// over when we hear of a new term; meanwhile we take no proposals, and
+    /// our lease is no good.  If it has not happened within an election
+    /// timeout, we carry on.
+    pub fn transfer_leadership(&mut self, to: Option<NodeId>) -> Result<NodeId, ChangeError> {
+        if self.role != Role::Leader {
+            return Err(ChangeError::NotLeader(self.leader));
+        }
+        let to = match to {
+            Some(to) => to,
+            None => {
+                let peers = self.peers();
+                let best = peers.iter().max_by_key(|&p| (self.match_index.get(p), cmp::Reverse(*p)));
+                *best.ok_or(ChangeError::NoChange)?
+            },
+        };
+        if to == self.id || !self.membership().contains(to) {
+            return Err(ChangeError::NoChange);
+        }
+        info!("raft {}: handing leadership to {}", self.id, to);
+        self.transfer = Some((to, self.clock.now() + self.config.election_timeout));
+        // It will be elected without waiting for our lease to run out.
+        self.lease_until = 0;
+        self.continue_transfer();
+        Ok(to)
+    }
+
+    /// Tell the node we are handing over to that it can take over, if it
+    /// has everything we have; otherwise make sure it is being sent that.
+    fn continue_transfer(&mut self) {
+        if let Some((to, _)) = self.transfer {
+            if self.match_index.get(&to) == Some(&self.last_index()) {
+                self.send(to, Rpc::TimeoutNow);
+            } else if !self.replicating.contains(&to) {
+                self.send_append(to);
+            }
+        }
+    }
+
+    /// Ask whether we could win an election before starting one.
+    fn start_pre_vote(&mut self) {
+        self.role = Role::Follower;
+        self.leader = None;
+        self.pre_voting = true;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.reset_election_timer();
+        debug!("raft {}: asking for pre-votes for term {}", self.id, self.term + 1);
+        if self.votes.len() >= self.quorum() {
+            self.start_election(false);
+            return;
+        }
+        let term = self.term + 1;
+        let last_log_index = self.last_index();
+        let last_log_term = self.last_term();
+        for p in self.peers() {
+            self.send_with_term(p, term, Rpc::PreVote {
+                last_log_index: last_log_index,
+                last_log_term: last_log_term,
+            });
+        }
+    }
+
+    fn start_election(&mut self, transfer: bool) {
+        self.pre_voting = false;
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.reset_election_timer();
+        debug!("raft {}: starting election for term {}", self.id, self.term);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let last_log_index = self.last_index();
+        let last_log_term = self.last_term();
+        for p in self.peers() {
+            self.send(p, Rpc::RequestVote {
+                last_log_index: last_log_index,
+                last_log_term: last_log_term,
+                transfer: transfer,
+            });
+        }
+    }
+
+    fn become_follower(&mut self, term: Term, leader: Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.role != Role::Follower {
+            debug!("raft {}: becoming follower in term {}", self.id, term);
+        }
+        for r in mem::take(&mut self.reads) {
+            self.answer_read(r.from, r.id, None);
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.transfer = None;
+        self.pre_voting = false;
+    }
+
+    fn become_leader(&mut self) {
+        debug!("raft {}: became leader for term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next = self.last_index() + 1;
+        self.next_index.clear();
+        self.match_index.clear();
+        for p in self.peers() {
+            self.next_index.insert(p, next);
+            self.match_index.insert(p, 0);
+        }
+        self.replicating.clear();
+        self.snapshot_progress.clear();
+        self.rounds.clear();
+        self.acked.clear();
+        self.lease_until = 0;
+        self.transfer = None;
+        // A majority just voted for us.
+        self.quorum_at = self.clock.now();
+        // A leader may only count replicas for entries from its own term,
+        // so commit an empty entry right away to settle what came before.
+        let term = self.term;
+        self.term_start = next;
+        self.append(&[Entry { term: term, index: next, kind: EntryKind::Normal, data: vec![] }]);
+        self.broadcast_append();
+    }
+
+    fn broadcast_append(&mut self) {
+        let now = self.clock.now();
+        self.seq += 1;
+        // A round sent longer ago than a lease lasts is no use to anybody.
+        let lease = self.lease();
+        while let Some(&(_, sent)) = self.rounds.front() {
+            if sent + lease > now {
+                break;
+            }
+            self.rounds.pop_front();
+        }
+        self.rounds.push_back((self.seq, now));
+        for p in self.peers() {
+            self.send_append(p);
+        }
+        self.heartbeat_deadline = now + self.config.heartbeat_interval;
+    }
+
+    /// How long after a round that a majority answers the leader can be
+    /// sure nobody else is elected.  We knock a tenth off the election
+    /// timeout in case our clock runs slow.
+    fn lease(&self) -> u64 {
+        self.config.election_timeout - self.config.election_timeout / 10
+    }
+
+    /// The latest round a majority of the members have answered.
+    fn quorum_seq(&self) -> u64 {
+        let mut acked: Vec<u64> = self.membership().ids().into_iter().map(|m| {
+            if m == self.id { self.seq } else { *self.acked.get(&m).unwrap_or(&0) }
+        }).collect();
+        acked.sort_by(|a, b| b.cmp(a));
+        acked.get(self.quorum() - 1).cloned().unwrap_or(0)
+    }
+
+    /// Extend the lease and answer reads as far as the rounds that a
+    /// majority has answered allow.
+    fn confirm_reads(&mut self) {
+        let seq = self.quorum_seq();
+        while let Some(&(s, sent)) = self.rounds.front() {
+            if s > seq {
+                break;
+            }
+            if self.transfer.is_none() && sent >= self.lease_from {
+                self.lease_until = cmp::max(self.lease_until, sent + self.lease());
+            }
+            self.quorum_at = cmp::max(self.quorum_at, sent);
+            self.rounds.pop_front();
+        }
+        if self.commit_index < self.term_start {
+            // We don't know what is committed until our first entry is.
+            return;
+        }
+        let commit = self.commit_index;
+        let (ready, waiting): (Vec<PendingRead>, Vec<PendingRead>) =
+            mem::take(&mut self.reads).into_iter().partition(|r| r.seq <= seq);
+        self.reads = waiting;
+        for r in ready {
+            self.answer_read(r.from, r.id, Some(commit));
+        }
+    }
+
+    fn answer_read(&mut self, from: NodeId, id: u64, index: Option<LogIndex>) {
+        if from == self.id {
+            self.read_answers.push((id, index));
+        } else {
+            self.send(from, Rpc::ReadIndexReply { id: id, index: index });
+        }
+    }
+
+    fn handle_read_index(&mut self, from: NodeId, id: u64, lease: bool) {
+        if self.role != Role::Leader {
+            self.answer_read(from, id, None);
+            return;
+        }
+        if lease && self.transfer.is_none() && self.commit_index >= self.term_start
+            && self.clock.now() < self.lease_until
+        {
+            let commit = self.commit_index;
+            self.answer_read(from, id, Some(commit));
+            return;
+        }
+        let seq = self.seq + 1;
+        self.reads.push(PendingRead { from: from, id: id, seq: seq });
+    }
+
+    fn send_append(&mut self, to: NodeId) {
+        let next = cmp::max(*self.next_index.get(&to).unwrap_or(&1), 1);
+        if next <= self.snapshot_index() {
+            // What it needs is no longer in the log.
+            self.send_snapshot(to);
+            return;
+        }
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index);
+        let hi = cmp::min(self.last_index(),
+                          prev_log_index + self.config.max_entries_per_message as LogIndex);
+        let entries = self.entries(prev_log_index + 1, hi);
+        if !entries.is_empty() && self.replicating.contains(&to) {
+            // Assume these will arrive, and carry on from where they end.
+            // If they don't, the next reply will tell us.
+            self.next_index.insert(to, hi + 1);
+        }
+        let leader_commit = self.commit_index;
+        let seq = self.seq;
+        self.send(to, Rpc::AppendEntries {
+            prev_log_index: prev_log_index,
+            prev_log_term: prev_log_term,
+            entries: entries,
+            leader_commit: leader_commit,
+            seq: seq,
+        });
+    }
+
+    fn send_snapshot(&mut self, to: NodeId) {
+        let base = self.snapshot_index();
+        let (index, term, size) = match self.snapshots.current() {
+            Some((index, term, size)) if index >= base => (index, term, size),
+            _ => {
+                // Nothing we could follow up with entries from the log.
+                // Once a new one is ready, a heartbeat will send it.
+                let applied = self.last_applied;
+                let term = self.term_at(applied);
+                self.snapshots.take(applied, term);
+                return;
+            },
+        };
+        let offset = match self.snapshot_progress.get(&to) {
+            Some(&(i, offset)) if i == index => offset,
+            _ => 0,
+        };
+        let len = cmp::min(size - offset, self.config.max_snapshot_chunk as u64);
+        let data = self.snapshots.read(offset, len as usize)
+            .unwrap_or_else(|e| self.fatal("read snapshot", e));
+        let membership = self.membership_at(index);
+        self.send(to, Rpc::InstallSnapshot {
+            last_index: index,
+            last_term: term,
+            membership: membership,
+            offset: offset,
+            data: data,
+            done: offset + len == size,
+        });
+    }
+
+    fn advance_commit(&mut self) {
+        // Our own copy only counts once it is on disk, and only while we
+        // are a member.
+        let ours = if self.membership().contains(self.id) { self.stable_index } else { 0 };
+        let peers = self.peers();
+        let mut n = self.last_index();
+        while n > self.commit_index && self.term_at(n) == self.term {
+            let replicas = peers.iter().map(|p| *self.match_index.get(p).unwrap_or(&0))
+                .chain(Some(ours)).filter(|&m| m >= n).count();
+            if replicas >= self.quorum() {
+                self.commit_index = n;
+                break;
+            }
+            n -= 1;
+        }
+        if self.membership().index <= self.commit_index && !self.membership().contains(self.id) {
+            // We have handed over to a membership without us.
+            debug!("raft {}: no longer a member; stepping down", self.id);
+            let term = self.term;
+            self.become_follower(term, None);
+        }
+    }
+
+    /// Handle a message from another node.
+    pub fn step(&mut self, msg: Message) {
+        if msg.to != self.id {
+            return;
+        }
+        // Pre-votes are about a term that may never come, so they must
+        // not move ours.
+        match msg.rpc {
+            Rpc::PreVote { last_log_index, last_log_term } => {
+                self.handle_pre_vote(msg.from, msg.term, last_log_index, last_log_term);
+                return;
+            },
+            Rpc::PreVoteReply { granted } => {
+                self.handle_pre_vote_reply(msg.from, msg.term, granted);
+                return;
+            },
+            _ => (),
+        }
+        if let Rpc::RequestVote { transfer: false, .. } = msg.rpc {
+            if msg.term > self.term && self.leader_is_alive() {
+                // The leader may be counting on us for its lease, and
+                // this is most likely a node that was removed and has not
+                // heard.
+                debug!("raft {}: ignoring vote request from {} while we have a leader",
+                       self.id, msg.from);
+                return;
+            }
+        }
+        if msg.term > self.term {
+            let leader = match msg.rpc {
+                Rpc::AppendEntries { .. } | Rpc::InstallSnapshot { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader);
+        }
+        if msg.term < self.term {
+            // Let a stale sender know it is out of date; stale replies we
+            // simply drop.
+            match msg.rpc {
+                Rpc::RequestVote { .. } => {
+                    self.send(msg.from, Rpc::RequestVoteReply { granted: false });
+                },
+                Rpc::AppendEntries { seq, .. } => {
+                    self.send(msg.from, Rpc::AppendEntriesReply {
+                        success: false,
+                        match_index: 0,
+                        seq: seq,
+                    });
+                },
+                Rpc::ReadIndex { id, .. } => {
+                    self.send(msg.from, Rpc::ReadIndexReply { id: id, index: None });
+                },
+                Rpc::InstallSnapshot { last_index, .. } => {
+                    self.send(msg.from, Rpc::InstallSnapshotReply {
+                        last_index: last_index,
+                        offset: 0,
+                        done: false,
+                    });
+                },
+                _ => (),
+            }
+            return;
+        }
+        match msg.rpc {
+            Rpc::RequestVote { last_log_index, last_log_term, .. } => {
+                self.handle_request_vote(msg.from, last_log_index, last_log_term);
+            },
+            Rpc::RequestVoteReply { granted } => {
+                if self.role == Role::Candidate && granted && self.membership().contains(msg.from) {
+                    self.votes.insert(msg.from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            },
+            Rpc::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit, seq } => {
+                self.handle_append_entries(msg.from, prev_log_index, prev_log_term,
+                                           entries, leader_commit, seq);
+            },
+            Rpc::AppendEntriesReply { success, match_index, seq } => {
+                self.handle_append_reply(msg.from, success, match_index, seq);
+            },
+            Rpc::InstallSnapshot { last_index, last_term, membership, offset, data, done } => {
+                if let Some(size) = self.receive_snapshot(msg.from, last_index, offset, &data, done) {
+                    self.install_snapshot(msg.from, last_index, last_term, membership, size);
+                }
+            },
+            Rpc::InstallSnapshotReply { last_index, offset, done } => {
+                self.handle_snapshot_reply(msg.from, last_index, offset, done);
+            },
+            Rpc::ReadIndex { id, lease } => self.handle_read_index(msg.from, id, lease),
+            Rpc::ReadIndexReply { id, index } => self.read_answers.push((id, index)),
+            Rpc::Propose { data } => {
+                if self.propose(data).is_err() {
+                    // The sender will find out when it doesn't get
+                    // committed, and try again.
+                    debug!("raft {}: not the leader, so dropping a proposal from {}",
+                           self.id, msg.from);
+                }
+            },
+            Rpc::TimeoutNow => {
+                if self.role == Role::Follower && self.leader == Some(msg.from)
+                    && self.membership().contains(self.id) {
+                    info!("raft {}: taking over from {}", self.id, msg.from);
+                    self.start_election(true);
+                }
+            },
+            // Answered before anything else.
+            Rpc::PreVote { .. } | Rpc::PreVoteReply { .. } => (),
+        }
+    }
+
+    /// Have we heard from a leader within the election timeout?  A leader
+    /// always hears from itself.
+    fn leader_is_alive(&self) -> bool {
+        match self.role {
+            Role::Leader => true,
+            Role::Candidate => false,
+            Role::Follower => {
+                self.leader.is_some()
+                    && self.clock.now() < self.heard_at + self.config.election_timeout
+            },
+        }
+    }
+
+    /// Is a log ending as described at least as up to date as ours?
+    fn up_to_date(&self, last_log_index: LogIndex, last_log_term: Term) -> bool {
+        last_log_term > self.last_term()
+            || (last_log_term == self.last_term() && last_log_index >= self.last_index())
+    }
+
+    fn handle_pre_vote(&mut self, from: NodeId, term: Term, last_log_index: LogIndex,
+                       last_log_term: Term) {
+        let granted = term > self.term && !self.leader_is_alive()
+            && self.up_to_date(last_log_index, last_log_term);
+        let term = if granted { term } else { self.term };
+        self.send_with_term(from, term, Rpc::PreVoteReply { granted: granted });
+    }
+
+    fn handle_pre_vote_reply(&mut self, from: NodeId, term: Term, granted: bool) {
+        if !granted {
+            if term > self.term {
+                // We are behind; there is no use standing.
+                self.become_follower(term, None);
+            }
+            return;
+        }
+        if self.pre_voting && term == self.term + 1 && self.membership().contains(from) {
+            self.votes.insert(from);
+            if self.votes.len() >= self.quorum() {
+                self.start_election(false);
+            }
+        }
+    }
+
+    fn handle_request_vote(&mut self, from: NodeId, last_log_index: LogIndex, last_log_term: Term) {
+        let up_to_date = self.up_to_date(last_log_index, last_log_term);
+        let can_vote = match self.voted_for {
+            None => true,
+            Some(v) => v == from,
+        };
+        let granted = up_to_date && can_vote && self.role != Role::Leader;
+        if granted {
+            self.voted_for = Some(from);
+            self.reset_election_timer();
+        }
+        self.send(from, Rpc::RequestVoteReply { granted: granted });
+    }
+
+    fn handle_append_entries(&mut self, from: NodeId, prev_log_index: LogIndex,
+                             prev_log_term: Term, entries: Vec<Entry>,
+                             leader_commit: LogIndex, seq: u64) {
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.reset_election_timer();
+        self.heard_at = self.clock.now();
+
+        let (base, base_term) = self.log.base();
+        let (prev_log_index, prev_log_term, entries) = if prev_log_index < base {
+            // We have a snapshot covering some of these, and everything it
+            // covers was committed, so it agrees with the leader.
+            let entries = entries.into_iter().filter(|e| e.index > base).collect();
+            (base, base_term, entries)
+        } else {
+            (prev_log_index, prev_log_term, entries)
+        };
+        if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+            let hint = cmp::min(prev_log_index.saturating_sub(1), self.last_index());
+            self.send(from, Rpc::AppendEntriesReply { success: false, match_index: hint, seq: seq });
+            return;
+        }
+        if self.want_snapshot {
+            // Claiming to have nothing sends the leader back past the
+            // start of its log, so that it sends its snapshot instead.
+            self.send(from, Rpc::AppendEntriesReply { success: false, match_index: 0, seq: seq });
+            return;
+        }
+
+        let last_new = prev_log_index + entries.len() as LogIndex;
+        let mut fresh = Vec::new();
+        for e in entries {
+            if fresh.is_empty() && e.index <= self.last_index() {
+                if self.term_at(e.index) == e.term {
+                    continue;
+                }
+                // Conflicting entry: it and everything after it must go.
+                // Nothing committed can conflict, so this is safe.
+                assert!(e.index > self.commit_index,
+                        "raft {}: leader tried to overwrite committed entry {}",
+                        self.id, e.index);
+                if let Err(err) = self.log.truncate(e.index) {
+                    self.fatal("truncate log", err);
+                }
+                self.stable_index = cmp::min(self.stable_index, e.index - 1);
+                if self.memberships.len() > 1 && self.membership().index >= e.index {
+                    // Only the one uncommitted change can go this way.
+                    self.memberships.retain(|m| m.index < e.index);
+                    self.membership_changed();
+                }
+            }
+            fresh.push(e);
+        }
+        if !fresh.is_empty() {
+            self.append(&fresh);
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = cmp::min(leader_commit, last_new);
+        }
+        self.send(from, Rpc::AppendEntriesReply { success: true, match_index: last_new, seq: seq });
+    }
+
+    fn handle_append_reply(&mut self, from: NodeId, success: bool, match_index: LogIndex,
+                           seq: u64) {
+        if self.role != Role::Leader {
+            return;
+        }
+        // Any answer at all shows the peer still takes us for leader.
+        if seq > *self.acked.get(&from).unwrap_or(&0) {
+            self.acked.insert(from, seq);
+            self.confirm_reads();
+        }
+        if success {
+            let old = *self.match_index.get(&from).unwrap_or(&0);
+            if match_index > old {
+                self.match_index.insert(from, match_index);
+            }
+            let next = cmp::max(*self.next_index.get(&from).unwrap_or(&1), match_index + 1);
+            self.next_index.insert(from, next);
+            self.replicating.insert(from);
+            self.advance_commit();
+            if next <= self.last_index() {
+                self.send_append(from);
+            }
+            if self.transfer.map(|t| t.0) == Some(from) {
+                self.continue_transfer();
+            }
+        } else {
+            self.replicating.remove(&from);
+            let next = *self.next_index.get(&from).unwrap_or(&1);
+            let next = cmp::max(1, cmp::min(next.saturating_sub(1), match_index + 1));
+            self.next_index.insert(from, next);
+            self.send_append(from);
+        }
+    }
+
+    /// Store a piece of the leader's snapshot through `last_index`, giving
+    /// the size of the snapshot once we have all of it, to be installed.
+    fn receive_snapshot(&mut self, from: NodeId, last_index: LogIndex, offset: u64, data: &[u8],
+                        done: bool) -> Option<u64> {
+        if self.role != Role::Follower || self.leader != Some(from) {
+            self.become_follower(self.term, Some(from));
+        }
+        self.reset_election_timer();
+        self.heard_at = self.clock.now();
+
+        let end = offset + data.len() as u64;
+        if self.want_snapshot && last_index <= self.last_applied {
+            // It would not get us any further.
+            warn!("raft {}: the snapshot through {} is no help past {}", self.id, last_index, self.last_applied);
+            self.want_snapshot = false;
+        }
+        if last_index <= self.commit_index && !self.want_snapshot {
+            // We already have everything it covers.
+            self.send(from, Rpc::InstallSnapshotReply { last_index: last_index, offset: end, done: true });
+            return None;
+        }
+        let have = self.snapshots.receive(last_index, offset, data)
+            .unwrap_or_else(|e| self.fatal("store snapshot", e));
+        if !done || have != end {
+            self.send(from, Rpc::InstallSnapshotReply { last_index: last_index, offset: have, done: false });
+            return None;
+        }
+        Some(have)
+    }
+
+    /// Replace our state with the snapshot through `last_index`, of `size`
+    /// bytes, that we have received from `from`.
+    fn install_snapshot(&mut self, from: NodeId, last_index: LogIndex, last_term: Term,
+                        membership: Membership, size: u64) {
+        debug!("raft {}: received snapshot through {} from {}", self.id, last_index, from);
+        if let Err(e) = self.snapshots.finish(last_index, last_term) {
+            self.fatal("store snapshot", e);
+        }
+        self.compact_log(last_index, last_term, membership);
+        self.stable_index = cmp::min(self.stable_index, self.last_index());
+        // What we asked for may be behind what we know to be committed.
+        self.commit_index = cmp::max(self.commit_index, last_index);
+        self.last_applied = last_index;
+        self.want_snapshot = false;
+        self.installed = Some(last_index);
+        self.send(from, Rpc::InstallSnapshotReply { last_index: last_index, offset: size, done: true });
+    }
+
+    /// Forget the log through `index`, whose entry had `term`, leaving
+    /// `membership` as the membership at the start of the log.
+    fn compact_log(&mut self, index: LogIndex, term: Term, membership: Membership) {
+        let keep = match self.log.term(index) {
+            Ok(t) => t == Some(term),
+            Err(e) => self.fatal("read log", e),
+        };
+        let mut memberships = vec![membership];
+        if keep {
+            memberships.extend(self.memberships.iter().filter(|m| m.index > index).cloned());
+        }
+        let changed = memberships.last() != self.memberships.last();
+        self.memberships = memberships;
+        // The log will no longer tell us the membership at its start.
+        self.save_hard_state();
+        if let Err(e) = self.log.compact(index, term) {
+            self.fatal("compact log", e);
+        }
+        if changed {
+            self.membership_changed();
+        }
+    }
+
+    fn handle_snapshot_reply(&mut self, from: NodeId, last_index: LogIndex, offset: u64, done: bool) {
+        if self.role != Role::Leader {
+            return;
+        }
+        if done {
+            self.snapshot_progress.remove(&from);
+            let old = *self.match_index.get(&from).unwrap_or(&0);
+            self.match_index.insert(from, cmp::max(old, last_index));
+            let next = cmp::max(*self.next_index.get(&from).unwrap_or(&1), last_index + 1);
+            self.next_index.insert(from, next);
+            self.advance_commit();
+            self.send_append(from);
+            return;
+        }
+        // Only a reply that shows progress earns the next piece; the
+        // heartbeat takes care of pieces that went missing.
+        let progress = match self.snapshot_progress.get(&from) {
+            Some(&(i, old)) => i != last_index || offset > old,
+            None => true,
+        };
+        self.snapshot_progress.insert(from, (last_index, offset));
+        if progress {
+            self.send_append(from);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Clone)]
+    struct Net(Arc<Mutex<Vec<Message>>>);
+    impl Transport for Net {
+        fn send(&mut self, msg: Message) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    #[derive(Clone)]
+    struct FakeClock(Arc<AtomicUsize>);
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst) as u64
+        }
+    }
+
+    struct Cluster {
+        nodes: Vec<Raft>,
+        net: Net,
+        clock: FakeClock,
+        down: HashSet<NodeId>,
+    }
+
+    impl Cluster {
+        fn new(n: u64) -> Cluster {
+            Cluster::with_members(n, n)
+        }
+        /// Nodes 1 through `n`, of which only the first `members` start
+        /// out in the cluster.
+        fn with_members(n: u64, members: u64) -> Cluster {
+            let net = Net(Arc::new(Mutex::new(Vec::new())));
+            let clock = FakeClock(Arc::new(AtomicUsize::new(0)));
+            let ids: Vec<NodeId> = (1..members + 1).collect();
+            let nodes = (1..n + 1).map(|id| {
+                let hard_state = if id <= members {
+                    MemHardState::with_members(&ids)
+                } else {
+                    MemHardState::new()
+                };
+                Raft::new(id, Box::new(MemLog::new()), Box::new(hard_state),
+                          Box::new(MemSnapshots::new()), Box::new(net.clone()),
+                          Box::new(clock.clone()), Config::default())
+            }).collect();
+            Cluster { nodes: nodes, net: net, clock: clock, down: HashSet::new() }
+        }
+        fn node(&mut self, id: NodeId) -> &mut Raft {
+            &mut self.nodes[id as usize - 1]
+        }
+        /// Deliver messages until the network is quiet.
+        fn deliver(&mut self) {
+            loop {
+                let msgs: Vec<Message> = self.net.0.lock().unwrap().drain(..).collect();
+                if msgs.is_empty() {
+                    return;
+                }
+                for m in msgs {
+                    if self.down.contains(&m.from) || self.down.contains(&m.to) {
+                        continue;
+                    }
+                    let to = m.to;
+                    self.node(to).step(m);
+                    self.node(to).flush();
+                }
+            }
+        }
+        fn advance(&mut self, ms: usize) {
+            for _ in 0..ms / 10 {
+                self.clock.0.fetch_add(10, Ordering::SeqCst);
+                for n in self.nodes.iter_mut() {
+                    if !self.down.contains(&n.id()) {
+                        n.tick();
+                        n.flush();
+                    }
+                }
+                self.deliver();
+            }
+        }
+        fn leaders(&self) -> Vec<NodeId> {
+            self.nodes.iter()
+                .filter(|n| n.role() == Role::Leader && !self.down.contains(&n.id()))
+                .map(|n| n.id()).collect()
+        }
+    }
+
+    #[test]
+    fn single_node_commits_alone() {
+        let mut c = Cluster::new(1);
+        c.advance(1000);
+        assert_eq!(c.leaders(), vec![1]);
+        let i = c.node(1).propose(b"hello".to_vec()).unwrap();
+        assert!(c.node(1).commit_index() < i);
+        c.node(1).flush();
+        assert_eq!(c.node(1).commit_index(), i);
+        let data: Vec<Vec<u8>> = c.node(1).take_committed().into_iter().map(|e| e.data).collect();
+        assert_eq!(data, vec![Vec::new(), b"hello".to_vec()]);
+        assert!(c.node(1).take_committed().is_empty());
+    }
+
+    #[test]
+    fn three_nodes_elect_exactly_one_leader() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leaders = c.leaders();
+        assert_eq!(leaders.len(), 1);
+        let term = c.node(leaders[0]).term();
+        for id in 1..4 {
+            assert_eq!(c.node(id).term(), term);
+            assert_eq!(c.node(id).leader(), Some(leaders[0]));
+        }
+    }
+
+    #[test]
+    fn entries_replicate_and_commit_everywhere() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let a = c.node(leader).propose(b"a".to_vec()).unwrap();
+        let b = c.node(leader).propose(b"b".to_vec()).unwrap();
+        assert_eq!(b, a + 1);
+        c.advance(200);
+        for id in 1..4 {
+            assert_eq!(c.node(id).commit_index(), b);
+            let data: Vec<Vec<u8>> = c.node(id).take_committed().into_iter()
+                .map(|e| e.data).filter(|d| !d.is_empty()).collect();
+            assert_eq!(data, vec![b"a".to_vec(), b"b".to_vec()]);
+        }
+        // Entries that couldn't be applied yet come round again.
+        c.node(1).defer_from(b);
+        assert_eq!(c.node(1).take_committed().into_iter().map(|e| e.data).collect::<Vec<_>>(),
+                   vec![b"b".to_vec()]);
+        assert!(c.node(1).take_committed().is_empty());
+    }
+
+    #[test]
+    fn appends_are_pipelined() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let follower = if leader == 1 { 2 } else { 1 };
+        for d in &[b"a", b"b", b"c"] {
+            c.node(leader).propose(d.to_vec()).unwrap();
+        }
+        c.node(leader).flush();
+        // Each proposal went out at once, carrying only what was new.
+        let sent: Vec<(LogIndex, usize)> = c.net.0.lock().unwrap().iter()
+            .filter(|m| m.to == follower)
+            .filter_map(|m| match m.rpc {
+                Rpc::AppendEntries { prev_log_index, ref entries, .. } => {
+                    Some((prev_log_index, entries.len()))
+                },
+                _ => None,
+            }).collect();
+        let first = sent[0].0;
+        assert_eq!(sent, vec![(first, 1), (first + 1, 1), (first + 2, 1)]);
+        c.advance(100);
+        for id in 1..4 {
+            assert_eq!(c.node(id).commit_index(), first + 3);
+        }
+    }
+
+    #[test]
+    fn follower_missing_compacted_entries_gets_snapshot() {
+        let mut c = Cluster::new(2);
+        // Node 1 founded the cluster: what it started with is in a
+        // snapshot at entry 1, and the log begins after it.
+        let mut log = MemLog::new();
+        log.append(&[Entry { term: 1, index: 1, kind: EntryKind::Normal, data: Vec::new() }])
+            .unwrap();
+        log.compact(1, 1).unwrap();
+        let mut snapshots = MemSnapshots::new();
+        snapshots.state = b"what node 1 started with".to_vec();
+        let config = Config { max_snapshot_chunk: 5, ..Config::default() };
+        c.nodes[0] = Raft::new(1, Box::new(log), Box::new(MemHardState::with_members(&[1, 2])),
+                               Box::new(snapshots), Box::new(c.net.clone()),
+                               Box::new(c.clock.clone()), config);
+        // Node 2 is new, and learns who is in the cluster with the snapshot.
+        c.nodes[1] = Raft::new(2, Box::new(MemLog::new()), Box::new(MemHardState::new()),
+                               Box::new(MemSnapshots::new()), Box::new(c.net.clone()),
+                               Box::new(c.clock.clone()), Config::default());
+        c.advance(2000);
+        assert_eq!(c.leaders(), vec![1]);
+        let i = c.node(1).propose(b"after".to_vec()).unwrap();
+        c.advance(200);
+
+        assert_eq!(c.node(2).take_installed(), Some(1));
+        let (index, term, size) = c.node(2).snapshots.current().unwrap();
+        assert_eq!((index, term), (1, 1));
+        assert_eq!(c.node(2).snapshots.read(0, size as usize).unwrap(),
+                   b"what node 1 started with".to_vec());
+        assert_eq!(c.node(2).commit_index(), i);
+        assert_eq!(c.node(2).membership().ids(), vec![1, 2]);
+        let data: Vec<Vec<u8>> = c.node(2).take_committed().into_iter()
+            .map(|e| e.data).filter(|d| !d.is_empty()).collect();
+        assert_eq!(data, vec![b"after".to_vec()]);
+    }
+
+    #[test]
+    fn applied_entries_are_compacted_away() {
+        let mut c = Cluster::new(3);
+        let config = Config { compact_every: 5, ..Config::default() };
+        for id in 1..4 {
+            c.nodes[id as usize - 1] = Raft::new(id, Box::new(MemLog::new()),
+                                                 Box::new(MemHardState::with_members(&[1, 2, 3])),
+                                                 Box::new(MemSnapshots::new()), Box::new(c.net.clone()),
+                                                 Box::new(c.clock.clone()), config.clone());
+        }
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let laggard = if leader == 3 { 2 } else { 3 };
+        c.down.insert(laggard);
+        for i in 0..12 {
+            c.node(leader).propose(format!("entry {}", i).into_bytes()).unwrap();
+            c.advance(100);
+            for id in 1..4 {
+                if id != laggard {
+                    c.node(id).take_committed();
+                }
+            }
+        }
+        c.advance(100);
+        let base = c.node(leader).snapshot_index();
+        assert!(base >= 5, "leader compacted only through {}", base);
+        assert_eq!(c.node(leader).membership().ids(), vec![1, 2, 3]);
+
+        // The laggard needs entries that are gone, so it gets a snapshot.
+        c.down.remove(&laggard);
+        c.advance(500);
+        let installed = c.node(laggard).take_installed();
+        assert!(installed >= Some(base), "installed {:?}", installed);
+        let commit = c.node(leader).commit_index();
+        assert_eq!(c.node(laggard).commit_index(), commit);
+    }
+
+    #[test]
+    fn leadership_is_handed_over() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let term = c.node(leader).term();
+        let others: Vec<NodeId> = (1..4).filter(|&id| id != leader).collect();
+        assert_eq!(c.node(others[0]).transfer_leadership(Some(others[1])),
+                   Err(ChangeError::NotLeader(Some(leader))));
+        assert_eq!(c.node(leader).transfer_leadership(Some(leader)), Err(ChangeError::NoChange));
+        assert_eq!(c.node(leader).transfer_leadership(Some(7)), Err(ChangeError::NoChange));
+
+        // The new leader must have the entry we propose just before.
+        let i = c.node(leader).propose(b"before".to_vec()).unwrap();
+        assert_eq!(c.node(leader).transfer_leadership(Some(others[1])), Ok(others[1]));
+        assert_eq!(c.node(leader).propose(b"during".to_vec()), Err(Some(others[1])));
+        c.node(leader).flush();
+        c.deliver();
+        // Well within an election timeout.
+        c.advance(100);
+        assert_eq!(c.leaders(), vec![others[1]]);
+        assert_eq!(c.node(others[1]).term(), term + 1);
+        assert!(c.node(others[1]).commit_index() > i);
+        assert_eq!(c.node(leader).leader(), Some(others[1]));
+    }
+
+    #[test]
+    fn follower_refuses_proposals() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let follower = if leader == 1 { 2 } else { 1 };
+        assert_eq!(c.node(follower).propose(b"x".to_vec()), Err(Some(leader)));
+    }
+
+    #[test]
+    fn minority_cannot_commit() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        for id in 1..4 {
+            if id != leader {
+                c.down.insert(id);
+            }
+        }
+        let i = c.node(leader).propose(b"lost".to_vec()).unwrap();
+        c.advance(200);
+        assert!(c.node(leader).commit_index() < i);
+    }
+
+    #[test]
+    fn new_leader_overwrites_uncommitted_tail() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let old = c.leaders()[0];
+        let old_term = c.node(old).term();
+        // Cut the leader off, then let it accept an entry nobody sees.
+        c.down.insert(old);
+        c.node(old).propose(b"lost".to_vec()).unwrap();
+        c.advance(2000);
+        let new = c.leaders()[0];
+        assert!(new != old);
+        assert!(c.node(new).term() > old_term);
+        c.node(new).propose(b"kept".to_vec()).unwrap();
+        c.advance(200);
+        // Heal the partition: the old leader must step down and adopt
+        // the new leader's log.
+        c.down.remove(&old);
+        c.advance(500);
+        assert_eq!(c.leaders(), vec![new]);
+        let commit = c.node(new).commit_index();
+        assert_eq!(c.node(old).commit_index(), commit);
+        let data: Vec<Vec<u8>> = c.node(old).take_committed().into_iter()
+            .map(|e| e.data).filter(|d| !d.is_empty()).collect();
+        assert_eq!(data, vec![b"kept".to_vec()]);
+    }
+
+    #[test]
+    fn stale_log_does_not_win_votes() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let lagging = if leader == 1 { 2 } else { 1 };
+        c.down.insert(lagging);
+        c.node(leader).propose(b"x".to_vec()).unwrap();
+        c.advance(200);
+        c.down.remove(&lagging);
+        // Ask the others directly for a vote on a stale log.
+        let term = c.node(lagging).term() + 5;
+        for id in 1..4 {
+            if id != lagging {
+                c.node(id).step(Message {
+                    from: lagging,
+                    to: id,
+                    term: term,
+                    rpc: Rpc::RequestVote { last_log_index: 1, last_log_term: 1, transfer: false },
+                });
+                c.node(id).flush();
+            }
+        }
+        let replies: Vec<Message> = c.net.0.lock().unwrap().drain(..).collect();
+        let granted = replies.iter().filter(|m| m.rpc == Rpc::RequestVoteReply { granted: true }).count();
+        assert_eq!(granted, 0);
+    }
+
+    #[test]
+    fn followers_of_a_live_leader_ignore_candidates() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let others: Vec<NodeId> = (1..4).filter(|&id| id != leader).collect();
+        let term = c.node(leader).term();
+        let last_log_index = c.node(leader).last_index();
+        c.node(others[0]).step(Message {
+            from: others[1],
+            to: others[0],
+            term: term + 1,
+            rpc: Rpc::RequestVote { last_log_index: last_log_index, last_log_term: term,
+                                    transfer: false },
+        });
+        c.node(others[0]).flush();
+        assert!(c.net.0.lock().unwrap().is_empty());
+        assert_eq!(c.node(others[0]).term(), term);
+    }
+
+    #[test]
+    fn cut_off_leader_knows_it_lacks_a_quorum() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let old = c.leaders()[0];
+        for id in 1..4 {
+            assert!(c.node(id).has_quorum());
+        }
+        c.down.insert(old);
+        c.advance(1000);
+        // It still thinks it leads, but knows better than to promise
+        // anything.
+        assert_eq!(c.node(old).role(), Role::Leader);
+        assert!(!c.node(old).has_quorum());
+        let new = c.leaders().into_iter().find(|&id| id != old).unwrap();
+        for id in (1..4).filter(|&id| id != old) {
+            assert!(c.node(id).has_quorum());
+            assert_eq!(c.node(id).leader(), Some(new));
+        }
+    }
+
+    #[test]
+    fn reads_wait_for_a_majority() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let i = c.node(leader).propose(b"x".to_vec()).unwrap();
+        c.advance(100);
+        c.node(leader).read_index(1, false);
+        assert!(c.node(leader).take_reads().is_empty());
+        c.node(leader).flush();
+        c.deliver();
+        assert_eq!(c.node(leader).take_reads(), vec![(1, Some(i))]);
+        // Cut off from the others, it can no longer show it is leader.
+        for id in 1..4 {
+            if id != leader {
+                c.down.insert(id);
+            }
+        }
+        c.node(leader).read_index(2, false);
+        c.advance(100);
+        assert!(c.node(leader).take_reads().is_empty());
+        // Before long it gives up leading, and the read with it.
+        c.advance(900);
+        assert_eq!(c.node(leader).role(), Role::Follower);
+        assert_eq!(c.node(leader).take_reads(), vec![(2, None)]);
+    }
+
+    #[test]
+    fn followers_ask_the_leader_what_to_read() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let follower = if leader == 1 { 2 } else { 1 };
+        let i = c.node(leader).propose(b"x".to_vec()).unwrap();
+        c.advance(100);
+        c.node(follower).read_index(7, false);
+        c.node(follower).flush();
+        c.deliver();
+        assert_eq!(c.node(follower).take_reads(), vec![(7, Some(i))]);
+    }
+
+    #[test]
+    fn leases_answer_reads_at_once() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        let commit = c.node(leader).commit_index();
+        c.node(leader).read_index(1, true);
+        assert_eq!(c.node(leader).take_reads(), vec![(1, Some(commit))]);
+        // Once the others stop answering, the lease runs out, and soon
+        // after the leader stops leading.
+        for id in 1..4 {
+            if id != leader {
+                c.down.insert(id);
+            }
+        }
+        c.advance(500);
+        c.node(leader).read_index(2, true);
+        assert_eq!(c.node(leader).take_reads(), vec![(2, None)]);
+    }
+
+    fn member(id: NodeId) -> Member {
+        Member { id: id, addr: format!("node{}", id) }
+    }
+
+    #[test]
+    fn members_are_added_one_at_a_time() {
+        let mut c = Cluster::with_members(5, 3);
+        c.advance(2000);
+        let leader = c.leaders()[0];
+        // Nodes that are not members wait to be told they are.
+        assert_eq!((c.node(4).role(), c.node(4).term()), (Role::Follower, 0));
+        let i = c.node(leader).add_member(member(4)).unwrap();
+        assert_eq!(c.node(leader).add_member(member(5)), Err(ChangeError::Busy));
+        assert_eq!(c.node(leader).add_member(member(4)), Err(ChangeError::NoChange));
+        c.advance(200);
+        for id in 1..5 {
+            assert_eq!(c.node(id).membership().ids(), vec![1, 2, 3, 4]);
+            assert!(c.node(id).commit_index() >= i);
+        }
+        assert_eq!(c.node(leader).peers().len(), 3);
+        c.node(leader).add_member(member(5)).unwrap();
+        c.advance(200);
+        // Four of five is a majority, so losing one of the first three
+        // does not stop commits.
+        let other = if leader == 1 { 2 } else { 1 };
+        c.down.insert(other);
+        let j = c.node(leader).propose(b"x".to_vec()).unwrap();
+        c.advance(200);
+        assert_eq!(c.node(5).commit_index(), j);
+        let follower = if leader == 2 { 3 } else { 2 };
+        assert_eq!(c.node(follower).add_member(member(6)), Err(ChangeError::NotLeader(Some(leader))));
+    }
+
+    #[test]
+    fn removed_leader_steps_down() {
+        let mut c = Cluster::new(3);
+        c.advance(2000);
+        let old = c.leaders()[0];
+        let i = c.node(old).remove_member(old).unwrap();
+        c.advance(100);
+        assert!(c.node(old).commit_index() >= i);
+        assert!(c.node(old).role() != Role::Leader);
+        c.advance(2000);
+        // The other two carry on without it, and it never campaigns.
+        let leaders = c.leaders();
+        assert_eq!(leaders.len(), 1);
+        assert!(leaders[0] != old);
+        let term = c.node(leaders[0]).term();
+        let j = c.node(leaders[0]).propose(b"x".to_vec()).unwrap();
+        c.advance(200);
+        for id in (1..4).filter(|&id| id != old) {
+            assert_eq!(c.node(id).commit_index(), j);
+            assert!(!c.node(id).membership().contains(old));
+        }
+        assert!(c.node(old).term() <= term);
+    }
+
+    #[test]
+    fn uncommitted_change_is_undone_with_its_entry() {
+        let mut c = Cluster::with_members(4, 3);
+        c.advance(2000);
+        let old = c.leaders()[0];
+        c.down.insert(old);
+        c.node(old).add_member(member(4)).unwrap();
+        c.advance(2000);
+        let new = c.leaders()[0];
+        c.node(new).propose(b"kept".to_vec()).unwrap();
+        c.advance(200);
+        c.down.remove(&old);
+        c.advance(500);
+        assert_eq!(c.node(old).membership().ids(), vec![1, 2, 3]);
+        assert_eq!(c.node(old).leader(), Some(new));
+    }
+
+    /// Run a cluster through a series of membership changes on a network
+    /// that loses messages and partitions, checking that no term ever has
+    /// two leaders.
+    fn churn(seed: u64) {
+        let mut c = Cluster::with_members(5, 3);
+        let mut rng = seed;
+        let mut random = move |n: u64| {
+            rng = rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (rng >> 33) % n
+        };
+        let mut leaders: HashMap<Term, NodeId> = HashMap::new();
+        for step in 0..3000 {
+            c.clock.0.fetch_add(10, Ordering::SeqCst);
+            for n in c.nodes.iter_mut() {
+                if !c.down.contains(&n.id()) {
+                    n.tick();
+                    n.flush();
+                }
+            }
+            let msgs: Vec<Message> = c.net.0.lock().unwrap().drain(..).collect();
+            for m in msgs {
+                if c.down.contains(&m.from) || c.down.contains(&m.to) || random(10) == 0 {
+                    continue;
+                }
+                let to = m.to;
+                c.node(to).step(m);
+                c.node(to).flush();
+            }
+            for n in c.nodes.iter() {
+                if n.role() == Role::Leader {
+                    let first = *leaders.entry(n.term()).or_insert(n.id());
+                    assert_eq!(first, n.id(), "two leaders in term {} (seed {})", n.term(), seed);
+                }
+            }
+            if step % 100 == 0 {
+                // Cut somebody off, or heal the network.
+                c.down.clear();
+                if random(2) == 0 {
+                    c.down.insert(random(5) + 1);
+                }
+            }
+            if step % 50 == 25 {
+                if let Some(&leader) = c.leaders().first() {
+                    let id = random(5) + 1;
+                    let _ = if c.node(leader).membership().contains(id) {
+                        if c.node(leader).membership().members.len() > 2 {
+                            c.node(leader).remove_member(id)
+                        } else {
+                            Err(ChangeError::NoChange)
+                        }
+                    } else {
+                        c.node(leader).add_member(member(id))
+                    };
+                }
+            }
+        }
+        c.down.clear();
+        c.advance(3000);
+        assert_eq!(c.leaders().len(), 1, "seed {}", seed);
+    }
+
+    #[test]
+    fn never_two_leaders_during_changes() {
+        for seed in 1..9 {
+            churn(seed);
+        }
+    }
+}
@@ -5,22 +5,42 @@
 // Copyright (c) 2016-2017 by William R. Fraser
 //
 
-use std;
 use std::ffi::{CStr, CString, OsStr, OsString};
-use std::fs::{self, File};
+use std::fs::File;
 use std::io::{self, Read, Write, Seek, SeekFrom};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
-
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::batch::{Batches, Opened};
+use super::fsop::{FsOp, Handle};
 use super::libc_extras::libc;
 use super::libc_wrappers;
+use super::node::Node;
+use super::snapshot;
+use super::state_machine::{is_metadata, META_DIR};
 
 use fuse_mt::*;
 use time::*;
 
+/// The most bytes of writes we gather into one log entry.
+const MAX_BATCH: usize = 1 << 20;
+
+/// How long writes may wait to go to the log.
+const BATCH_DELAY: u64 = 100;
+
 pub struct RaftFS {
     pub target: OsString,
+    node: Arc<Node>,
+    batches: Arc<Batches>,
+    // What the log knows each file we have open for writing as.
+    handles: Mutex<HashMap<u64, Handle>>,
+    next_handle: AtomicU64,
 }
 
 fn mode_to_filetype(mode: libc::mode_t) -> FileType {
@@ -87,122 +107,93 @@ fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
 }
 
 impl RaftFS {
-    fn mustnt_exist(&self, partial: &Path) -> Result<(), i32> {
-        let partial = partial.strip_prefix("/").unwrap();
-        println!("backup_snapshot for {:?}", partial);
-        let path = PathBuf::from(&self.target).join(partial);
-        if path.symlink_metadata().is_ok() {
-            return Err(libc::EROFS);
+    pub fn new(target: OsString, node: Node) -> RaftFS {
+        let node = Arc::new(node);
+        let batches = Arc::new(Batches::new(MAX_BATCH, Duration::from_millis(BATCH_DELAY)));
+        let weak = Arc::downgrade(&node);
+        let timer = batches.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(BATCH_DELAY));
+            let node = match weak.upgrade() {
+                Some(node) => node,
+                None => return,
+            };
+            timer.flush_stale(&|op| node.execute(op));
+        });
+        RaftFS {
+            target: target,
+            node: node,
+            batches: batches,
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
         }
-        Ok(())
     }
-    fn copy_for_backup(&self, from: &Path, to: &Path) -> Result<(), std::io::Error> {
-        if !to.exists() {
-            if let Some(par) = to.parent() {
-                if !par.exists() {
-                    self.copy_for_backup(from.parent().unwrap(), &par)?;
-                }
-            }
-            if from.is_file() {
-                std::fs::copy(from, to)?;
-            } else {
-                std::fs::create_dir(to)?;
-            }
-        }
-        Ok(())
+
+    fn new_handle(&self) -> Handle {
+        self.next_handle.fetch_add(1, Ordering::Relaxed)
     }
-    fn backup_snapshot(&self, partial: &Path) -> Result<(), std::io::Error> {
-        let partial = partial.strip_prefix("/").unwrap();
-        println!("backup_snapshot for {:?}", partial);
-        let from = PathBuf::from(&self.target).join(partial);
-        for e in std::fs::read_dir(PathBuf::from(&self.target).join(".snapshots"))? {
-            let snappath = e?.path();
-            println!("backup_snapshot: {:?} for {:?}", snappath, partial);
-            self.copy_for_backup(&from, &snappath.join(partial))?;
+
+    /// Our copy of the file the log has opened as `handle`, as a new fh.
+    /// We may have lost track of it if a snapshot was installed meanwhile.
+    fn opened(&self, handle: Handle) -> Option<u64> {
+        match self.node.file(handle) {
+            Some(Ok(file)) => Some(file.into_raw_fd() as u64),
+            Some(Err(e)) => {
+                error!("unable to copy the file opened as {}: {}", handle, e);
+                None
+            },
+            None => None,
         }
-        Ok(())
     }
-    fn whiteout_snapshot(&self, partial: &Path) -> Result<(), std::io::Error> {
-        let partial = partial.strip_prefix("/").unwrap();
-        println!("whiteout_snapshot for {:?}", partial);
-        for e in std::fs::read_dir(PathBuf::from(&self.target).join(".snapshots"))? {
-            let snappath = e?.path();
-            let real = snappath.join(partial);
-            println!("whiteout_snapshot: {:?}", real);
-            // whiteout is a socket
-            let result = unsafe {
-                let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
-                libc::mknod(path_c.as_ptr(), libc::S_IFSOCK, 0)
-            };
 
-            if -1 == result {
-                let e = io::Error::last_os_error();
-                error!("whiteout mknod error({:?}, S_IFCHR, 0): {}", real, e);
-                return Err(e)
+    /// Tell the log that `fh` is closed, if the log had it open.
+    fn release_handle(&self, path: &Path, fh: u64) {
+        let handle = self.handles.lock().unwrap().remove(&fh);
+        if let Some(handle) = handle {
+            if let Err(e) = self.node.execute(&FsOp::Release { handle: handle }) {
+                warn!("releasing {:?} as {}: {}", path, handle, io::Error::from_raw_os_error(e));
             }
         }
-        Ok(())
     }
-    fn is_in_snapshot(&self, partial: &Path) -> bool {
-        if let Ok(child) = partial.strip_prefix("/.snapshots") {
-            let mut childstuff = child.iter();
-            if let Some(_) = childstuff.next() {
-                return childstuff.next().is_some();
-            }
+
+    /// Make a change, after any writes still waiting to go to the log.
+    fn execute(&self, op: &FsOp) -> Result<(), libc::c_int> {
+        self.batches.flush_all(&|op| self.node.execute(op));
+        self.node.execute(op)
+    }
+
+    /// Send writes waiting to go to the log through `fh`, reporting any
+    /// that failed.
+    fn flush_handle(&self, fh: u64) -> Result<(), libc::c_int> {
+        self.batches.flush_handle(&|op| self.node.execute(op), fh)
+    }
+
+    /// Send writes waiting to go to the log to the file at `path`, or open
+    /// as `fh`, before it is looked at, reporting any that failed.
+    fn flush_file(&self, path: &Path, fh: Option<u64>) -> Result<(), libc::c_int> {
+        let stat = match fh {
+            Some(fh) => libc_wrappers::fstat(fh),
+            None => libc_wrappers::lstat(self.real_path(path)),
+        };
+        match stat {
+            Ok(stat) => self.batches.flush_file(&|op| self.node.execute(op), stat.st_ino),
+            // Nothing that isn't there has been written to.
+            Err(_) => Ok(()),
         }
-        false
     }
+
     fn is_snapshot(&self, partial: &Path) -> bool {
-        if let Ok(child) = partial.strip_prefix("/.snapshots") {
-            return child.iter().next().is_some();
-        }
-        false
+        snapshot::is_snapshot(partial)
     }
     fn real_path(&self, partial: &Path) -> OsString {
         println!("reading real_path {:?}", partial);
-        let partial = partial.strip_prefix("/").unwrap();
-        if let Ok(child) = partial.strip_prefix(".snapshots") {
-            let mut childstuff = child.iter();
-            if let Some(snapname) = childstuff.next() {
-                let rest = childstuff.as_path();
-                if PathBuf::from(&self.target).join(".snapshots").join(snapname).is_dir() {
-                    // The snapshot exists! Now check if the path has
-                    // a snapshot value or whiteout.
-                    match libc_wrappers::lstat(PathBuf::from(&self.target).join(partial).into_os_string()) {
-                        Ok(stat) => {
-                            let typ = mode_to_filetype(stat.st_mode);
-                            if typ == FileType::Socket {
-                                return OsString::from("this is an invalid whiteout path");
-                            }
-                            if typ == FileType::Directory {
-                                // It is not a file that has been
-                                // overridden.  Directories are joined
-                                // between the snapshot and the
-                                // "real" directory.
-                                println!("case 1 not overridden");
-                                return PathBuf::from(&self.target).join(rest)
-                                    .into_os_string();
-                            }
-                        },
-                        Err(_) => {
-                            // It is not a file that has been overridden
-                            println!("case 2 not overridden {:?}",
-                                     PathBuf::from(&self.target).join(partial));
-                            return PathBuf::from(&self.target).join(rest)
-                                .into_os_string();
-                        }
-                    }
-
-                    if !PathBuf::from(&self.target).join(partial).is_file() {
-                        // It is not a file that has been overridden
-                        println!("case 3 not overridden");
-                        return PathBuf::from(&self.target).join(rest)
-                            .into_os_string();
-                    }
-                }
-            }
-        }
-        PathBuf::from(&self.target).join(partial).into_os_string()
+        snapshot::real_path(Path::new(&self.target), partial).into_os_string()
+    }
+    /// Where to read the mode, owner, times and extended attributes of
+    /// `partial` from, which for a directory in a snapshot is not where
+    /// its listing comes from.
+    fn attr_path(&self, partial: &Path) -> OsString {
+        snapshot::attr_path(Path::new(&self.target), partial).into_os_string()
     }
     fn snap_path(&self, partial: &Path) -> OsString {
         println!("reading snap_path {:?}", partial);
@@ -211,7 +202,10 @@ impl RaftFS {
     }
 
     fn stat_real(&self, path: &Path) -> io::Result<FileAttr> {
-        let real: OsString = self.real_path(path);
+        if is_metadata(path) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        let real: OsString = self.attr_path(path);
         debug!("stat_real: {:?}", real);
 
         match libc_wrappers::lstat(real) {
@@ -237,10 +231,14 @@ impl FilesystemMT for RaftFS {
 
     fn destroy(&self, _req: RequestInfo) {
         debug!("destroy");
+        self.batches.flush_all(&|op| self.node.execute(op));
+        self.node.step_down();
     }
 
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
         debug!("getattr: {:?}", path);
+        self.flush_file(path, fh)?;
+        self.node.read_barrier()?;
 
         if let Some(fh) = fh {
             match libc_wrappers::fstat(fh) {
@@ -288,6 +286,7 @@ impl FilesystemMT for RaftFS {
 
     fn readdir(&self, _req: RequestInfo, path: &Path, infh: u64) -> ResultReaddir {
         debug!("readdir: {:?}", path);
+        self.node.read_barrier()?;
         let mut entries: Vec<DirectoryEntry> = vec![];
 
         let fh = infh & (! (1 << 63));
@@ -328,6 +327,12 @@ impl FilesystemMT for RaftFS {
                         }
                     };
 
+                    if name == OsStr::new(META_DIR) && (path == Path::new("/") || is_snap) {
+                        continue; // our own bookkeeping is not for users
+                    }
+                    if is_metadata(&path.join(&name)) {
+                        continue; // nor are the snapshots we take for ourselves
+                    }
                     if is_snap {
                         if name == OsStr::new(".snapshots") {
                             continue; // ignore any .snapshots in a snapshot
@@ -364,6 +369,38 @@ impl FilesystemMT for RaftFS {
     fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         debug!("open: {:?} flags={:#x}", path, flags);
 
+        let mut flags = flags;
+        if flags as libc::c_int & libc::O_TRUNC != 0
+            && flags as libc::c_int & libc::O_ACCMODE != libc::O_RDONLY
+        {
+            // Truncating on open changes the file, so it has to go
+            // through the log like any other truncate.
+            self.execute(&FsOp::Truncate { path: path.to_owned(), size: 0 })?;
+            flags &= !(libc::O_TRUNC as u32);
+        }
+
+        if flags as libc::c_int & libc::O_ACCMODE != libc::O_RDONLY && !self.is_snapshot(path) {
+            // Writes have to go to the file opened here, whatever becomes
+            // of its name, on every node.
+            let handle = self.new_handle();
+            self.execute(&FsOp::Open { path: path.to_owned(), flags: flags, handle: handle })?;
+            let fh = match self.opened(handle) {
+                Some(fh) => Ok(fh),
+                None => libc_wrappers::open(self.real_path(path), flags as libc::c_int),
+            };
+            return match fh {
+                Ok(fh) => {
+                    self.handles.lock().unwrap().insert(fh, handle);
+                    Ok((fh, flags))
+                },
+                Err(e) => {
+                    error!("open({:?}): {}", path, io::Error::from_raw_os_error(e));
+                    self.node.execute(&FsOp::Release { handle: handle }).ok();
+                    Err(e)
+                },
+            };
+        }
+
         let real = self.real_path(path);
         match libc_wrappers::open(real, flags as libc::c_int) {
             Ok(fh) => Ok((fh, flags)),
@@ -377,11 +414,17 @@ impl FilesystemMT for RaftFS {
 
     fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
         debug!("release: {:?}", path);
-        libc_wrappers::close(fh)
+        let flushed = self.flush_handle(fh);
+        self.batches.forget(fh);
+        self.release_handle(path, fh);
+        libc_wrappers::close(fh)?;
+        flushed
     }
 
     fn read(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, size: u32) -> ResultData {
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
+        self.flush_file(path, Some(fh))?;
+        self.node.read_barrier()?;
         let mut file = unsafe { UnmanagedFile::new(fh) };
 
         let mut data = Vec::<u8>::with_capacity(size as usize);
@@ -404,25 +447,21 @@ impl FilesystemMT for RaftFS {
 
     fn write(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
-        let mut file = unsafe { UnmanagedFile::new(fh) };
-
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            error!("seek({:?}, {}): {}", path, offset, e);
-            return Err(e.raw_os_error().unwrap());
-        }
-        let nwritten: u32 = match file.write(&data) {
-            Ok(n) => n as u32,
-            Err(e) => {
-                error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
-                return Err(e.raw_os_error().unwrap());
-            }
+        // Buffered writes may fail later, but not for want of a quorum we
+        // already know is missing.
+        self.node.writable()?;
+        let file = Opened {
+            fh: fh,
+            handle: self.handles.lock().unwrap().get(&fh).cloned(),
+            ino: libc_wrappers::fstat(fh)?.st_ino,
         };
-
-        Ok(nwritten)
+        self.batches.write(&|op| self.node.execute(op), file, path, offset, &data);
+        Ok(data.len() as u32)
     }
 
     fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
         debug!("flush: {:?}", path);
+        self.flush_handle(fh)?;
         let mut file = unsafe { UnmanagedFile::new(fh) };
 
         if let Err(e) = file.flush() {
@@ -435,6 +474,7 @@ impl FilesystemMT for RaftFS {
 
     fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
         debug!("fsync: {:?}, data={:?}", path, datasync);
+        self.flush_handle(fh)?;
         let file = unsafe { UnmanagedFile::new(fh) };
 
         if let Err(e) = if datasync {
@@ -449,111 +489,24 @@ impl FilesystemMT for RaftFS {
         Ok(())
     }
 
-    fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
-        debug!("chown: {:?} to {:#o}", path, mode);
-
-        let result = if let Some(fh) = fh {
-            unsafe { libc::fchmod(fh as libc::c_int, mode as libc::mode_t) }
-        } else {
-            let real = self.real_path(path);
-            unsafe {
-                let path_c = CString::from_vec_unchecked(real.into_vec());
-                libc::chmod(path_c.as_ptr(), mode as libc::mode_t)
-            }
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("chown({:?}, {:#o}): {}", path, mode, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            Ok(())
-        }
+    fn chmod(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+        debug!("chmod: {:?} to {:#o}", path, mode);
+        self.execute(&FsOp::Chmod { path: path.to_owned(), mode: mode })
     }
 
-    fn chown(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
-        let uid = uid.unwrap_or(::std::u32::MAX);   // docs say "-1", but uid_t is unsigned
-        let gid = gid.unwrap_or(::std::u32::MAX);   // ditto for gid_t
-        debug!("chmod: {:?} to {}:{}", path, uid, gid);
-
-        let result = if let Some(fd) = fh {
-            unsafe { libc::fchown(fd as libc::c_int, uid, gid) }
-        } else {
-            let real = self.real_path(path);
-            unsafe {
-                let path_c = CString::from_vec_unchecked(real.into_vec());
-                libc::chown(path_c.as_ptr(), uid, gid)
-            }
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("chmod({:?}, {}, {}): {}", path, uid, gid, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            Ok(())
-        }
+    fn chown(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
+        debug!("chown: {:?} to {:?}:{:?}", path, uid, gid);
+        self.execute(&FsOp::Chown { path: path.to_owned(), uid: uid, gid: gid })
     }
 
-    fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
+    fn truncate(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
         debug!("truncate: {:?} to {:#x}", path, size);
-
-        let result = if let Some(fd) = fh {
-            unsafe { libc::ftruncate64(fd as libc::c_int, size as i64) }
-        } else {
-            let real = self.real_path(path);
-            unsafe {
-                let path_c = CString::from_vec_unchecked(real.into_vec());
-                libc::truncate64(path_c.as_ptr(), size as i64)
-            }
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("truncate({:?}, {}): {}", path, size, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            Ok(())
-        }
+        self.execute(&FsOp::Truncate { path: path.to_owned(), size: size })
     }
 
-    fn utimens(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>) -> ResultEmpty {
+    fn utimens(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>) -> ResultEmpty {
         debug!("utimens: {:?}: {:?}, {:?}", path, atime, mtime);
-
-
-        fn timespec_to_libc(time: Option<Timespec>) -> libc::timespec {
-            if let Some(time) = time {
-                libc::timespec {
-                    tv_sec: time.sec as libc::time_t,
-                    tv_nsec: time.nsec as libc::time_t,
-                }
-            } else {
-                libc::timespec {
-                    tv_sec: 0,
-                    tv_nsec: libc::UTIME_OMIT,
-                }
-            }
-        }
-
-        let times = [timespec_to_libc(atime), timespec_to_libc(mtime)];
-
-        let result = if let Some(fd) = fh {
-            unsafe { libc::futimens(fd as libc::c_int, &times as *const libc::timespec) }
-        } else {
-            let real = self.real_path(path);
-            unsafe {
-                let path_c = CString::from_vec_unchecked(real.into_vec());
-                libc::utimensat(libc::AT_FDCWD, path_c.as_ptr(), &times as *const libc::timespec, libc::AT_SYMLINK_NOFOLLOW)
-            }
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("utimens({:?}, {:?}, {:?}): {}", path, atime, mtime, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            Ok(())
-        }
+        self.execute(&FsOp::Utimens { path: path.to_owned(), atime: atime, mtime: mtime })
     }
 
     fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
@@ -602,116 +555,65 @@ impl FilesystemMT for RaftFS {
     fn mknod(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, rdev: u32) -> ResultEntry {
         debug!("mknod: {:?}/{:?} (mode={:#o}, rdev={})", parent_path, name, mode, rdev);
 
-        let parent_path_name = parent_path.join(name);
-        self.mustnt_exist(&parent_path_name)?;
-        if self.is_snapshot(parent_path) {
-            return Err(libc::EROFS);
-        }
-        self.whiteout_snapshot(&parent_path_name);
+        self.execute(&FsOp::Mknod {
+            parent: parent_path.to_owned(),
+            name: name.to_owned(),
+            mode: mode,
+            rdev: rdev,
+        })?;
 
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        let result = unsafe {
-            let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
-            libc::mknod(path_c.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t)
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("mknod({:?}, {}, {}): {}", real, mode, rdev, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            match libc_wrappers::lstat(real.into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
-                Err(e) => Err(e),   // if this happens, yikes
-            }
+        match libc_wrappers::lstat(real.into_os_string()) {
+            Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+            Err(e) => Err(e),   // if this happens, yikes
         }
     }
 
     fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32) -> ResultEntry {
         debug!("mkdir {:?}/{:?} (mode={:#o})", parent_path, name, mode);
 
-        let parent_path_name = parent_path.join(name);
-        self.mustnt_exist(&parent_path_name)?;
-        if self.is_snapshot(parent_path) {
-            return Err(libc::EROFS);
-        }
-        self.whiteout_snapshot(&parent_path_name);
+        self.execute(&FsOp::Mkdir {
+            parent: parent_path.to_owned(),
+            name: name.to_owned(),
+            mode: mode,
+        })?;
 
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        let result = unsafe {
-            let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
-            libc::mkdir(path_c.as_ptr(), mode as libc::mode_t)
-        };
-
-        if -1 == result {
-            let e = io::Error::last_os_error();
-            error!("mkdir({:?}, {:#o}): {}", real, mode, e);
-            Err(e.raw_os_error().unwrap())
-        } else {
-            match libc_wrappers::lstat(real.clone().into_os_string()) {
-                Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
-                Err(e) => {
-                    error!("lstat after mkdir({:?}, {:#o}): {}", real, mode, e);
-                    Err(e)   // if this happens, yikes
-                },
-            }
+        match libc_wrappers::lstat(real.clone().into_os_string()) {
+            Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
+            Err(e) => {
+                error!("lstat after mkdir({:?}, {:#o}): {}", real, mode, e);
+                Err(e)   // if this happens, yikes
+            },
         }
     }
 
     fn unlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("unlink {:?}/{:?}", parent_path, name);
-
-        if self.is_snapshot(parent_path) {
-            return Err(libc::EROFS);
-        }
-        let parent_path_name = parent_path.join(name);
-        self.backup_snapshot(&parent_path_name);
-
-        let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        fs::remove_file(&real)
-            .map_err(|ioerr| {
-                error!("unlink({:?}): {}", real, ioerr);
-                ioerr.raw_os_error().unwrap()
-            })
+        self.execute(&FsOp::Unlink { parent: parent_path.to_owned(), name: name.to_owned() })
     }
 
     fn rmdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("rmdir: {:?}/{:?}", parent_path, name);
-
-        if self.is_snapshot(parent_path) {
-            return Err(libc::EROFS);
-        }
-
-        let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        fs::remove_dir(&real)
-            .map_err(|ioerr| {
-                error!("rmdir({:?}): {}", real, ioerr);
-                ioerr.raw_os_error().unwrap()
-            })
+        self.execute(&FsOp::Rmdir { parent: parent_path.to_owned(), name: name.to_owned() })
     }
 
     fn symlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, target: &Path) -> ResultEntry {
         debug!("symlink: {:?}/{:?} -> {:?}", parent_path, name, target);
 
-        if self.is_snapshot(parent_path) {
-            return Err(libc::EROFS);
-        }
+        self.execute(&FsOp::Symlink {
+            parent: parent_path.to_owned(),
+            name: name.to_owned(),
+            target: target.to_owned(),
+        })?;
 
         let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        match ::std::os::unix::fs::symlink(target, &real) {
-            Ok(()) => {
-                match libc_wrappers::lstat(real.clone().into_os_string()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
-                    Err(e) => {
-                        error!("lstat after symlink({:?}, {:?}): {}", real, target, e);
-                        Err(e)
-                    },
-                }
-            },
+        match libc_wrappers::lstat(real.clone().into_os_string()) {
+            Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
             Err(e) => {
-                error!("symlink({:?}, {:?}): {}", real, target, e);
-                Err(e.raw_os_error().unwrap())
-            }
+                error!("lstat after symlink({:?}, {:?}): {}", real, target, e);
+                Err(e)
+            },
         }
     }
 
@@ -720,43 +622,29 @@ impl FilesystemMT for RaftFS {
               newparent_path: &Path, newname: &OsStr) -> ResultEmpty {
         debug!("rename: {:?}/{:?} -> {:?}/{:?}",
                parent_path, name, newparent_path, newname);
-        if self.is_snapshot(parent_path) || self.is_snapshot(newparent_path) {
-            return Err(libc::EROFS);
-        }
-        self.backup_snapshot(&parent_path.join(name));
-        self.whiteout_snapshot(&newparent_path.join(newname));
-
-        let real = PathBuf::from(self.real_path(parent_path)).join(name);
-        let newreal = PathBuf::from(self.real_path(newparent_path)).join(newname);
-        fs::rename(&real, &newreal)
-            .map_err(|ioerr| {
-                error!("rename({:?}, {:?}): {}", real, newreal, ioerr);
-                ioerr.raw_os_error().unwrap()
-            })
+        self.execute(&FsOp::Rename {
+            parent: parent_path.to_owned(),
+            name: name.to_owned(),
+            newparent: newparent_path.to_owned(),
+            newname: newname.to_owned(),
+        })
     }
 
     fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
         debug!("link: {:?} -> {:?}/{:?}", path, newparent, newname);
 
-        if self.is_snapshot(newparent) {
-            return Err(libc::EROFS);
-        }
+        self.execute(&FsOp::Link {
+            path: path.to_owned(),
+            newparent: newparent.to_owned(),
+            newname: newname.to_owned(),
+        })?;
 
         let real = self.real_path(path);
-        let newreal = PathBuf::from(self.real_path(newparent)).join(newname);
-        match fs::hard_link(&real, &newreal) {
-            Ok(()) => {
-                match libc_wrappers::lstat(real.clone()) {
-                    Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
-                    Err(e) => {
-                        error!("lstat after link({:?}, {:?}): {}", real, newreal, e);
-                        Err(e)
-                    },
-                }
-            },
+        match libc_wrappers::lstat(real.clone()) {
+            Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
             Err(e) => {
-                error!("link({:?}, {:?}): {}", real, newreal, e);
-                Err(e.raw_os_error().unwrap())
+                error!("lstat after link({:?}, {:?}/{:?}): {}", real, newparent, newname, e);
+                Err(e)
             },
         }
     }
@@ -764,40 +652,52 @@ impl FilesystemMT for RaftFS {
     fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
         debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
 
-        if self.is_snapshot(parent) {
-            return Err(libc::EROFS);
-        }
-
+        let handle = self.new_handle();
+        self.execute(&FsOp::Create {
+            parent: parent.to_owned(),
+            name: name.to_owned(),
+            mode: mode,
+            flags: flags,
+            handle: Some(handle),
+        })?;
+
+        // The file now exists everywhere, and is open as it was created,
+        // which a mode that forbids writing would keep us from doing again.
         let real = PathBuf::from(self.real_path(parent)).join(name);
-        let fd = unsafe {
-            let real_c = CString::from_vec_unchecked(real.clone().into_os_string().into_vec());
-            libc::open(real_c.as_ptr(), flags as i32 | libc::O_CREAT | libc::O_EXCL, mode)
+        let fd = match self.opened(handle) {
+            Some(fd) => fd,
+            None => {
+                let open_flags = flags as libc::c_int & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+                match libc_wrappers::open(real.clone().into_os_string(), open_flags) {
+                    Ok(fd) => fd,
+                    Err(e) => {
+                        self.node.execute(&FsOp::Release { handle: handle }).ok();
+                        return Err(e);
+                    },
+                }
+            },
         };
-
-        if -1 == fd {
-            let ioerr = io::Error::last_os_error();
-            error!("create({:?}): {}", real, ioerr);
-            Err(ioerr.raw_os_error().unwrap())
-        } else {
-            match libc_wrappers::lstat(real.clone().into_os_string()) {
-                Ok(attr) => Ok(CreatedEntry {
-                    ttl: TTL,
-                    attr: stat_to_fuse(attr),
-                    fh: fd as u64,
-                    flags: flags,
-                }),
-                Err(e) => {
-                    error!("lstat after create({:?}): {}", real, io::Error::from_raw_os_error(e));
-                    Err(e)
-                },
-            }
+        self.handles.lock().unwrap().insert(fd, handle);
+        match libc_wrappers::lstat(real.clone().into_os_string()) {
+            Ok(attr) => Ok(CreatedEntry {
+                ttl: TTL,
+                attr: stat_to_fuse(attr),
+                fh: fd,
+                flags: flags,
+            }),
+            Err(e) => {
+                error!("lstat after create({:?}): {}", real, io::Error::from_raw_os_error(e));
+                self.release_handle(&real, fd);
+                libc_wrappers::close(fd).ok();
+                Err(e)
+            },
         }
     }
 
     fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
         debug!("listxattr: {:?}", path);
 
-        let real = self.real_path(path);
+        let real = self.attr_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
@@ -814,7 +714,7 @@ impl FilesystemMT for RaftFS {
     fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
         debug!("getxattr: {:?} {:?} {}", path, name, size);
 
-        let real = self.real_path(path);
+        let real = self.attr_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
@@ -830,22 +730,18 @@ impl FilesystemMT for RaftFS {
 
     fn setxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, value: &[u8], flags: u32, position: u32) -> ResultEmpty {
         debug!("setxattr: {:?} {:?} {} bytes, flags = {:#x}, pos = {}", path, name, value.len(), flags, position);
-        if self.is_snapshot(path) {
-            return Err(libc::EROFS);
-        }
-        let real = self.real_path(path);
-        libc_wrappers::lsetxattr(real, name.to_owned(), value, flags, position)
+        self.execute(&FsOp::SetXattr {
+            path: path.to_owned(),
+            name: name.to_owned(),
+            value: value.to_vec(),
+            flags: flags,
+            position: position,
+        })
     }
 
     fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("removexattr: {:?} {:?}", path, name);
-
-        if self.is_snapshot(path) {
-            return Err(libc::EROFS);
-        }
-
-        let real = self.real_path(path);
-        libc_wrappers::lremovexattr(real, name.to_owned())
+        self.execute(&FsOp::RemoveXattr { path: path.to_owned(), name: name.to_owned() })
     }
 }
 
@@ -0,0 +1,500 @@
+// Sim :: A whole cluster in one process, for tests.
+//
+// Each simulated node is a raft instance and a state machine applying to a
+// directory of its own, just as a mounted node would be, but the network
+// is a queue we control and the clock only moves when we say.  Messages
+// can be dropped, delayed (and so reordered, since each gets its own
+// delay) or cut off by a partition.  Every choice is made by a generator
+// seeded by the test, so a scenario that fails can be run again exactly.
+// A scenario ends by healing the network, letting the cluster settle and
+// checking that every replica holds byte-for-byte the same tree.
+//
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::fsop::{FsOp, Proposal};
+use super::libc_extras::libc;
+use super::raft::{ChangeError, Clock, Config, EntryKind, LogIndex, MemHardState, MemLog, MemSnapshots, Message,
+                  NodeId, Raft, Role, Transport};
+use super::state_machine::{StateMachine, META_DIR};
+
+/// How often we move the clock, in milliseconds.
+const STEP: u64 = 10;
+
+/// How badly the network behaves.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// How many messages in a thousand are lost.
+    pub drop_per_mille: u64,
+    /// The longest a message may take, in milliseconds.
+    pub max_delay: u64,
+}
+
+#[derive(Clone)]
+struct Outbox(Arc<Mutex<Vec<Message>>>);
+
+impl Transport for Outbox {
+    fn send(&mut self, msg: Message) {
+        self.0.lock().unwrap().push(msg);
+    }
+}
+
+#[derive(Clone)]
+struct SimClock(Arc<AtomicUsize>);
+
+impl Clock for SimClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) as u64
+    }
+}
+
+struct Replica {
+    raft: Raft,
+    sm: StateMachine,
+    dir: PathBuf,
+    serial: u64,
+}
+
+impl Replica {
+    fn apply_committed(&mut self) {
+        for e in self.raft.take_committed() {
+            if e.kind != EntryKind::Normal || e.data.is_empty() {
+                self.sm.skip(e.index);
+                continue;
+            }
+            let p = Proposal::decode(&e.data).expect("a proposal we made");
+            // Failures are part of the history too, and must be the same
+            // everywhere, so there is nothing to check here.
+            self.sm.apply_proposal(e.index, &p).ok();
+        }
+    }
+}
+
+pub struct Sim {
+    root: PathBuf,
+    replicas: Vec<Replica>,
+    outbox: Arc<Mutex<Vec<Message>>>,
+    // Messages on their way, with when they arrive, in the order sent.
+    in_flight: Vec<(u64, Message)>,
+    clock: Arc<AtomicUsize>,
+    rng: u64,
+    pub faults: Faults,
+    // Pairs of nodes that cannot hear each other, both ways round.
+    cut: HashSet<(NodeId, NodeId)>,
+}
+
+impl Sim {
+    /// A cluster of nodes 1 through `n`, each applying to a fresh
+    /// directory named for the test.
+    pub fn new(name: &str, n: u64, seed: u64) -> Sim {
+        let root = ::std::env::temp_dir().join(format!("raftfs-sim-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let outbox = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(AtomicUsize::new(0));
+        let ids: Vec<NodeId> = (1..n + 1).collect();
+        let replicas = ids.iter().map(|&id| {
+            let dir = root.join(format!("node-{}", id));
+            fs::create_dir_all(&dir).unwrap();
+            // Nothing is ever compacted, so nobody needs a snapshot.
+            let config = Config { compact_every: 0, ..Config::default() };
+            let raft = Raft::new(id, Box::new(MemLog::new()), Box::new(MemHardState::with_members(&ids)),
+                                 Box::new(MemSnapshots::new()), Box::new(Outbox(outbox.clone())),
+                                 Box::new(SimClock(clock.clone())), config);
+            Replica { raft: raft, sm: StateMachine::open(dir.clone()).unwrap(), dir: dir, serial: 0 }
+        }).collect();
+        Sim {
+            root: root,
+            replicas: replicas,
+            outbox: outbox,
+            in_flight: Vec::new(),
+            clock: clock,
+            // Zero would make the generator useless.
+            rng: seed | 1,
+            faults: Faults::default(),
+            cut: HashSet::new(),
+        }
+    }
+
+    /// A number below `n`, from the seeded generator.
+    pub fn random(&mut self, n: u64) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d) % n
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.load(Ordering::SeqCst) as u64
+    }
+
+    pub fn ids(&self) -> Vec<NodeId> {
+        self.replicas.iter().map(|r| r.raft.id()).collect()
+    }
+
+    fn replica(&mut self, id: NodeId) -> &mut Replica {
+        &mut self.replicas[id as usize - 1]
+    }
+
+    pub fn raft(&self, id: NodeId) -> &Raft {
+        &self.replicas[id as usize - 1].raft
+    }
+
+    pub fn leaders(&self) -> Vec<NodeId> {
+        self.replicas.iter().filter(|r| r.raft.role() == Role::Leader).map(|r| r.raft.id()).collect()
+    }
+
+    /// Split the cluster so that nodes only hear others in their group.
+    /// Anybody not in a group is cut off from everybody.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.cut.clear();
+        let group = |id: NodeId| groups.iter().position(|g| g.contains(&id));
+        for a in self.ids() {
+            for b in self.ids() {
+                if a != b && (group(a).is_none() || group(a) != group(b)) {
+                    self.cut.insert((a, b));
+                }
+            }
+        }
+    }
+
+    /// Cut node `id` off from hearing anybody, though they still hear it.
+    pub fn deafen(&mut self, id: NodeId) {
+        for other in self.ids() {
+            if other != id {
+                self.cut.insert((other, id));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// Ask node `id` to get `op` applied, as a write through its mount
+    /// would.  Returns whether it knew where to send it; the proposal may
+    /// still be lost on the way.
+    pub fn submit(&mut self, id: NodeId, op: FsOp) -> bool {
+        let replica = self.replica(id);
+        replica.serial += 1;
+        let p = Proposal { origin: id, boot: 1, serial: replica.serial, floor: 0, op: op };
+        let sent = replica.raft.submit(p.encode()).is_some();
+        replica.raft.flush();
+        sent
+    }
+
+    /// Ask node `id` to hand leadership to `to`.
+    pub fn transfer_leadership(&mut self, id: NodeId, to: NodeId) -> Result<NodeId, ChangeError> {
+        let raft = &mut self.replica(id).raft;
+        let result = raft.transfer_leadership(Some(to));
+        raft.flush();
+        result
+    }
+
+    /// What node `id` can say at once a read has to wait for, relying on
+    /// its lease if `lease`, if anything.
+    pub fn read_index(&mut self, id: NodeId, lease: bool) -> Option<LogIndex> {
+        let raft = &mut self.replica(id).raft;
+        raft.read_index(0, lease);
+        raft.flush();
+        raft.take_reads().pop().and_then(|(_, index)| index)
+    }
+
+    /// Let `ms` milliseconds go by.
+    pub fn advance(&mut self, ms: u64) {
+        for _ in 0..ms / STEP {
+            self.clock.fetch_add(STEP as usize, Ordering::SeqCst);
+            for r in self.replicas.iter_mut() {
+                r.raft.tick();
+                r.raft.flush();
+                r.apply_committed();
+            }
+            self.deliver();
+        }
+    }
+
+    /// Deliver everything that has arrived by now, including replies that
+    /// arrive at once.
+    fn deliver(&mut self) {
+        loop {
+            self.post();
+            let now = self.now();
+            let due: Vec<Message> = {
+                let (due, later) = self.in_flight.drain(..).partition(|&(at, _)| at <= now);
+                self.in_flight = later;
+                due.into_iter().map(|(_, m): (u64, Message)| m).collect()
+            };
+            if due.is_empty() {
+                return;
+            }
+            for m in due {
+                let r = self.replica(m.to);
+                r.raft.step(m);
+                r.raft.flush();
+                r.apply_committed();
+            }
+        }
+    }
+
+    /// Put what the nodes have sent on its way, or lose it.
+    fn post(&mut self) {
+        let sent: Vec<Message> = self.outbox.lock().unwrap().drain(..).collect();
+        for m in sent {
+            let lost = self.random(1000) < self.faults.drop_per_mille;
+            if lost || self.cut.contains(&(m.from, m.to)) {
+                continue;
+            }
+            let delay = self.random(self.faults.max_delay + 1);
+            let at = self.now() + delay;
+            self.in_flight.push((at, m));
+        }
+    }
+
+    /// Heal the network, and run until every node has applied everything
+    /// the leader has committed.
+    pub fn settle(&mut self) {
+        self.heal();
+        self.faults = Faults::default();
+        let deadline = self.now() + 60000;
+        while self.now() < deadline {
+            self.advance(100);
+            let leaders = self.leaders();
+            if leaders.len() != 1 {
+                continue;
+            }
+            let commit = self.raft(leaders[0]).commit_index();
+            let caught_up = self.replicas.iter()
+                .all(|r| r.raft.commit_index() == commit && r.sm.applied() == commit);
+            if caught_up && self.raft(leaders[0]).last_index() == commit {
+                return;
+            }
+        }
+        panic!("cluster never settled");
+    }
+
+    /// How far every node has applied, once settled.
+    pub fn applied(&self) -> LogIndex {
+        self.replicas[0].sm.applied()
+    }
+
+    /// Check that every replica holds exactly the same files.
+    pub fn assert_identical(&self) {
+        let first = tree(&self.replicas[0].dir);
+        for r in &self.replicas[1..] {
+            let other = tree(&r.dir);
+            if let Some(i) = (0..first.len()).find(|&i| first.get(i) != other.get(i)) {
+                panic!("{:?} and {:?} differ: {:?} against {:?}",
+                       self.replicas[0].dir, r.dir, first.get(i), other.get(i));
+            }
+            assert_eq!(first.len(), other.len(), "{:?} has extra files", r.dir);
+        }
+    }
+
+    /// A random change to a small namespace, so that changes often
+    /// collide and many fail.
+    pub fn random_op(&mut self) -> FsOp {
+        let dirs = ["/", "/d", "/d/e"];
+        let names = ["a", "b", "d", "e"];
+        let parent = PathBuf::from(dirs[self.random(dirs.len() as u64) as usize]);
+        let name = OsString::from(names[self.random(names.len() as u64) as usize]);
+        let path = parent.join(&name);
+        match self.random(10) {
+            0 => FsOp::Mkdir { parent: parent, name: name, mode: 0o755 },
+            1 => FsOp::Create { parent: parent, name: name, mode: 0o644, flags: libc::O_WRONLY as u32, handle: None },
+            2 | 3 => {
+                let offset = self.random(16);
+                let data = format!("{}", self.random(1 << 20)).into_bytes();
+                FsOp::Write { path: path, handle: None, offset: offset, data: data }
+            },
+            4 => FsOp::Truncate { path: path, size: self.random(8) },
+            5 => FsOp::Unlink { parent: parent, name: name },
+            6 => FsOp::Rmdir { parent: parent, name: name },
+            7 => {
+                let newparent = PathBuf::from(dirs[self.random(dirs.len() as u64) as usize]);
+                let newname = OsString::from(names[self.random(names.len() as u64) as usize]);
+                FsOp::Rename { parent: parent, name: name, newparent: newparent, newname: newname }
+            },
+            8 => FsOp::Symlink { parent: parent, name: name, target: PathBuf::from("a") },
+            _ => FsOp::Chmod { path: path, mode: 0o600 | self.random(0o100) as u32 },
+        }
+    }
+
+    /// Throw the directories away, once the test has passed.
+    pub fn remove_dirs(self) {
+        fs::remove_dir_all(&self.root).ok();
+    }
+}
+
+/// Everything under `dir` but our own metadata, in order: each path with
+/// its mode and its contents (or where it points).
+fn tree(dir: &Path) -> Vec<(PathBuf, u32, Vec<u8>)> {
+    let mut out = Vec::new();
+    walk(dir, Path::new(""), &mut out);
+    out
+}
+
+fn walk(dir: &Path, rel: &Path, out: &mut Vec<(PathBuf, u32, Vec<u8>)>) {
+    let mut names: Vec<OsString> = fs::read_dir(dir.join(rel)).unwrap()
+        .map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    for name in names {
+        if rel == Path::new("") && name == META_DIR {
+            continue;
+        }
+        let rel = rel.join(&name);
+        let path = dir.join(&rel);
+        let meta = path.symlink_metadata().unwrap();
+        let contents = if meta.file_type().is_symlink() {
+            fs::read_link(&path).unwrap().as_os_str().as_bytes().to_vec()
+        } else if meta.is_file() {
+            fs::read(&path).unwrap()
+        } else {
+            Vec::new()
+        };
+        out.push((rel.clone(), meta.permissions().mode(), contents));
+        if meta.is_dir() {
+            walk(dir, &rel, out);
+        }
+    }
+}
+
+mod test {
+    use super::*;
+
+    /// Random changes sent to random nodes for `ms` milliseconds.
+    fn churn(sim: &mut Sim, ms: u64) {
+        for _ in 0..ms / 20 {
+            let id = 1 + sim.random(sim.ids().len() as u64);
+            let op = sim.random_op();
+            sim.submit(id, op);
+            sim.advance(20);
+        }
+    }
+
+    #[test]
+    fn lossy_network_converges() {
+        let mut sim = Sim::new("lossy", 3, 1);
+        sim.advance(1000);
+        sim.faults = Faults { drop_per_mille: 100, max_delay: 40 };
+        churn(&mut sim, 6000);
+        sim.settle();
+        assert!(sim.applied() > 100, "only {} entries applied", sim.applied());
+        sim.assert_identical();
+        sim.remove_dirs();
+    }
+
+    #[test]
+    fn partitions_heal_into_identical_replicas() {
+        let mut sim = Sim::new("partitions", 5, 2);
+        sim.advance(1000);
+        sim.faults = Faults { drop_per_mille: 20, max_delay: 20 };
+        for round in 0..8 {
+            if round % 2 == 0 {
+                // Cut two nodes off from the other three.
+                let mut ids = sim.ids();
+                let a = ids.remove(sim.random(5) as usize);
+                let b = ids.remove(sim.random(4) as usize);
+                sim.partition(&[&[a, b], &ids]);
+            } else {
+                sim.heal();
+            }
+            churn(&mut sim, 2000);
+        }
+        sim.settle();
+        assert_eq!(sim.leaders().len(), 1);
+        sim.assert_identical();
+        sim.remove_dirs();
+    }
+
+    #[test]
+    fn cut_off_node_does_not_disrupt_the_rest() {
+        let mut sim = Sim::new("cut-off", 5, 4);
+        sim.advance(1000);
+        let leader = sim.leaders()[0];
+        let term = sim.raft(leader).term();
+        let cut_off = if leader == 5 { 4 } else { 5 };
+        let rest: Vec<NodeId> = sim.ids().into_iter().filter(|&id| id != cut_off).collect();
+        sim.partition(&[&rest]);
+        churn(&mut sim, 5000);
+        // It keeps asking for pre-votes, but never stands for election.
+        assert_eq!(sim.raft(cut_off).term(), term);
+        sim.settle();
+        assert_eq!(sim.leaders(), vec![leader]);
+        for id in sim.ids() {
+            assert_eq!(sim.raft(id).term(), term);
+        }
+        sim.assert_identical();
+        sim.remove_dirs();
+    }
+
+    #[test]
+    fn cut_off_leader_steps_down() {
+        let mut sim = Sim::new("check-quorum", 5, 5);
+        sim.advance(1000);
+        let old = sim.leaders()[0];
+        let rest: Vec<NodeId> = sim.ids().into_iter().filter(|&id| id != old).collect();
+        sim.partition(&[&rest]);
+        churn(&mut sim, 3000);
+        assert_eq!(sim.raft(old).role(), Role::Follower);
+        assert!(!sim.raft(old).has_quorum());
+        let new = sim.leaders();
+        assert_eq!(new.len(), 1);
+        let term = sim.raft(new[0]).term();
+        // The old leader rejoins without forcing another election.
+        sim.settle();
+        assert_eq!(sim.leaders(), new);
+        assert_eq!(sim.raft(old).term(), term);
+        sim.assert_identical();
+        sim.remove_dirs();
+    }
+
+    #[test]
+    fn handing_over_ends_the_lease() {
+        let mut sim = Sim::new("handover", 3, 6);
+        sim.advance(1000);
+        let old = sim.leaders()[0];
+        let new = sim.ids().into_iter().find(|&id| id != old).unwrap();
+        let mkdir = |name: &str| FsOp::Mkdir { parent: PathBuf::from("/"), name: OsString::from(name), mode: 0o755 };
+        assert!(sim.submit(old, mkdir("before")));
+        sim.advance(100);
+        let before = sim.raft(old).commit_index();
+        assert_eq!(sim.read_index(old, true), Some(before));
+
+        // The old leader never hears that it has been replaced.
+        sim.deafen(old);
+        assert_eq!(sim.transfer_leadership(old, new), Ok(new));
+        sim.advance(50);
+        assert!(sim.leaders().contains(&new));
+        assert!(sim.submit(new, mkdir("after")));
+        sim.advance(50);
+        assert!(sim.raft(new).commit_index() > before);
+        // Its lease from before would still be running.
+        assert_eq!(sim.raft(old).role(), Role::Leader);
+        assert_eq!(sim.read_index(old, true), None);
+
+        sim.settle();
+        sim.assert_identical();
+        sim.remove_dirs();
+    }
+
+    #[test]
+    fn same_seed_same_history() {
+        let run = |name: &str| {
+            let mut sim = Sim::new(name, 3, 3);
+            sim.faults = Faults { drop_per_mille: 50, max_delay: 30 };
+            churn(&mut sim, 3000);
+            sim.settle();
+            let result = (sim.now(), sim.applied(), sim.leaders(), tree(&sim.replicas[0].dir));
+            sim.remove_dirs();
+            result
+        };
+        assert_eq!(run("seed-a"), run("seed-b"));
+    }
+}
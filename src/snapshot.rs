@@ -0,0 +1,516 @@
+// Snapshots :: Copy-on-write preservation of the backing directory.
+//
+// A snapshot named NAME lives in TARGET/.snapshots/NAME.  Anything in the
+// live tree that has not changed since the snapshot was taken is read
+// straight from the live tree.  Before a file is changed in any way (its
+// contents, size, mode, owner, times or extended attributes, or its name),
+// a copy of it is saved into every snapshot that lacks one, and a name that
+// comes into existence after a snapshot is marked in that snapshot with a
+// whiteout (a socket).  Only the first change after a snapshot costs a
+// copy; the snapshot has its own from then on.  Moving a directory moves
+// everything under it too, so all of that is saved first.  The copy is of
+// the whole inode: its type (a symlink is copied as a symlink, a device or
+// FIFO is made afresh), mode, owner, times and extended attributes, and a
+// directory in the snapshot keeps its times as copies are added to it.
+// A socket can't be saved this way, as it would read as a whiteout.
+//
+// Where the backing filesystem can clone a file (btrfs and XFS can), a
+// saved copy shares the original's extents rather than duplicating them,
+// so saving even a large file is quick and costs no space until the live
+// file is written.  Elsewhere the data is copied.
+//
+// Hard links make a file reachable by several names, and a change made
+// through any of them must not show through the others in a snapshot.
+// So the first change to a file with more than one name saves a copy of
+// it in the snapshot's own bookkeeping, .snapshots/NAME/.raftfs/links/INO,
+// under its inode number in the live tree.  A name the snapshot has no copy
+// of reads that copy if its inode has one, and when such a name comes to
+// be saved it becomes a hard link to it, so the names stay linked inside
+// the snapshot as they were when it was taken.  A new name made with
+// link is whited out like any other.  An inode number found there always
+// belongs to the file we saved: a name that had no copy and no whiteout
+// has named the same file since the snapshot was taken, and no other file
+// could have had its number in that time.
+//
+// Removing a snapshot (rmdir .snapshots/NAME) throws away everything saved
+// in it.  It is first moved into TARGET/.raftfs/trash, so that it vanishes
+// all at once and the rest can be finished after a crash.  The copies in a
+// snapshot are its own, and what it shares with the live tree is only read
+// through to, so nothing else is touched; where a saved copy is a hard link,
+// only that link goes, and the data stays for whoever else has it.
+//
+
+use std;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use super::libc_extras::libc;
+use super::libc_wrappers;
+use super::state_machine::META_DIR;
+
+pub fn is_snapshot(partial: &Path) -> bool {
+    if let Ok(child) = partial.strip_prefix("/.snapshots") {
+        return child.iter().next().is_some();
+    }
+    false
+}
+
+/// Whether `partial` names a snapshot itself, rather than something in one.
+pub fn is_snapshot_root(partial: &Path) -> bool {
+    match partial.strip_prefix("/.snapshots") {
+        Ok(child) => child.iter().count() == 1,
+        Err(_) => false,
+    }
+}
+
+pub fn mustnt_exist(target: &Path, partial: &Path) -> Result<(), i32> {
+    let partial = partial.strip_prefix("/").unwrap();
+    println!("backup_snapshot for {:?}", partial);
+    let path = target.join(partial);
+    if path.symlink_metadata().is_ok() {
+        return Err(libc::EROFS);
+    }
+    Ok(())
+}
+
+/// Whether something above `rest` in the snapshot `snap` came into being
+/// after the snapshot, so that nothing under it is in the snapshot at all.
+fn whited_out(snap: &Path, rest: &Path) -> bool {
+    rest.ancestors().skip(1).any(|a| match snap.join(a).symlink_metadata() {
+        Ok(meta) => !meta.is_dir(),
+        Err(_) => false,
+    })
+}
+
+fn cstr(path: &Path) -> CString {
+    unsafe { CString::from_vec_unchecked(path.as_os_str().as_bytes().to_vec()) }
+}
+
+/// Where the snapshot `snap` keeps its copy of the live inode `ino`.
+fn linked_copy(snap: &Path, ino: u64) -> PathBuf {
+    snap.join(META_DIR).join("links").join(ino.to_string())
+}
+
+/// The copy the snapshot `snap` has of the file at `live`, if that is one
+/// of several names for a file it saved.
+pub fn linked_copy_of(snap: &Path, live: &Path) -> Option<PathBuf> {
+    let meta = live.symlink_metadata().ok()?;
+    if meta.is_dir() {
+        return None;
+    }
+    let saved = linked_copy(snap, meta.ino());
+    if saved.symlink_metadata().is_ok() {
+        Some(saved)
+    } else {
+        None
+    }
+}
+
+fn copy_for_backup(snap: &Path, from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if to.symlink_metadata().is_err() {
+        let par = to.parent().unwrap();
+        if par.symlink_metadata().is_err() {
+            copy_for_backup(snap, from.parent().unwrap(), par)?;
+        }
+        let meta = from.symlink_metadata()?;
+        if meta.file_type().is_socket() {
+            return Ok(());
+        }
+        match linked_copy_of(snap, from) {
+            Some(saved) => adding_to(par, || fs::hard_link(&saved, to))?,
+            None => adding_to(par, || copy_inode(from, to, &meta))?,
+        }
+    }
+    Ok(())
+}
+
+/// Make `to` a copy of `from`, whose metadata is `meta`.
+fn copy_inode(from: &Path, to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let file_type = meta.file_type();
+    if file_type.is_file() {
+        copy_file(from, to, CopyMode::Reflink)?;
+    } else if file_type.is_dir() {
+        fs::create_dir(to)?;
+    } else if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if unsafe { libc::mknod(cstr(to).as_ptr(), meta.mode(), meta.rdev()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    copy_xattrs(from, to)?;
+    unsafe {
+        // Only root may give files away; anybody else keeps them.
+        if libc::lchown(cstr(to).as_ptr(), meta.uid(), meta.gid()) == -1 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EPERM) {
+                return Err(e);
+            }
+        }
+    }
+    if !file_type.is_symlink() {
+        // After the chown, which may clear the setuid and setgid bits.
+        fs::set_permissions(to, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    set_times(to, meta)
+}
+
+/// Copy the contents of the file `from` to the new file `to`, sharing
+/// extents if `mode` allows and we can, and say which it came to.
+fn copy_file(from: &Path, to: &Path, mode: CopyMode) -> io::Result<CopyMode> {
+    let mut source = File::open(from)?;
+    let mut dest = OpenOptions::new().write(true).create_new(true).mode(0o600).open(to)?;
+    if mode == CopyMode::Reflink && reflink(&source, &dest) {
+        return Ok(CopyMode::Reflink);
+    }
+    // This uses copy_file_range where there is one, which may still spare
+    // us moving the data through memory.
+    io::copy(&mut source, &mut dest)?;
+    Ok(CopyMode::Copy)
+}
+
+/// Make `to`, which is empty, a clone of `from`.
+fn reflink(from: &File, to: &File) -> bool {
+    unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) == 0 }
+}
+
+/// How saved copies of files are made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMode {
+    /// The copy shares the original's extents until one of them changes.
+    Reflink,
+    /// The data is copied.
+    Copy,
+}
+
+impl fmt::Display for CopyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CopyMode::Reflink => write!(f, "cloning (reflink)"),
+            CopyMode::Copy => write!(f, "copying"),
+        }
+    }
+}
+
+/// Find out how copies will be saved in the backing directory `target`.
+pub fn copy_mode(target: &Path) -> io::Result<CopyMode> {
+    let probe = target.join(META_DIR).join("reflink-probe");
+    let clone = probe.with_extension("clone");
+    let result = fs::write(&probe, b"raftfs").and_then(|_| {
+        let to = File::create(&clone)?;
+        Ok(reflink(&File::open(&probe)?, &to))
+    });
+    fs::remove_file(&probe).ok();
+    fs::remove_file(&clone).ok();
+    Ok(if result? { CopyMode::Reflink } else { CopyMode::Copy })
+}
+
+fn set_times(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let times = [
+        libc::timespec { tv_sec: meta.atime() as libc::time_t, tv_nsec: meta.atime_nsec() as libc::c_long },
+        libc::timespec { tv_sec: meta.mtime() as libc::time_t, tv_nsec: meta.mtime_nsec() as libc::c_long },
+    ];
+    let result = unsafe {
+        libc::utimensat(libc::AT_FDCWD, cstr(path).as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let errno = |e| io::Error::from_raw_os_error(e);
+    let (from, to) = (from.as_os_str().to_owned(), to.as_os_str().to_owned());
+    let mut names = match libc_wrappers::llistxattr(from.clone(), &mut []) {
+        Ok(0) | Err(libc::ENOTSUP) => return Ok(()),
+        Ok(size) => vec![0; size],
+        Err(e) => return Err(errno(e)),
+    };
+    let size = libc_wrappers::llistxattr(from.clone(), &mut names).map_err(errno)?;
+    for name in names[..size].split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = OsString::from(OsStr::from_bytes(name));
+        let size = libc_wrappers::lgetxattr(from.clone(), name.clone(), &mut []).map_err(errno)?;
+        let mut value = vec![0; size];
+        let size = libc_wrappers::lgetxattr(from.clone(), name.clone(), &mut value).map_err(errno)?;
+        match libc_wrappers::lsetxattr(to.clone(), name.clone(), &value[..size], 0, 0) {
+            // Some namespaces are for root alone, as with owners.
+            Ok(()) | Err(libc::EPERM) => (),
+            Err(e) => return Err(errno(e)),
+        }
+    }
+    Ok(())
+}
+
+/// Do `add`, which puts something into the snapshot's directory `dir`,
+/// leaving `dir` itself as it was.
+fn adding_to<F>(dir: &Path, add: F) -> io::Result<()>
+    where F: FnOnce() -> io::Result<()>
+{
+    let meta = dir.symlink_metadata()?;
+    let mode = meta.permissions().mode();
+    let locked = mode & 0o300 != 0o300;
+    if locked {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o300))?;
+    }
+    let result = add();
+    if locked {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+    }
+    set_times(dir, &meta)?;
+    result
+}
+
+pub fn backup_snapshot(target: &Path, partial: &Path) -> Result<(), std::io::Error> {
+    let partial = partial.strip_prefix("/").unwrap();
+    println!("backup_snapshot for {:?}", partial);
+    let from = target.join(partial);
+    let meta = match from.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()), // nothing there to save
+    };
+    for e in std::fs::read_dir(target.join(".snapshots"))? {
+        let snappath = e?.path();
+        println!("backup_snapshot: {:?} for {:?}", snappath, partial);
+        let saved = linked_copy(&snappath, meta.ino());
+        if !meta.is_dir() && meta.nlink() > 1 && saved.symlink_metadata().is_err() {
+            // Whichever name it is changed by, its other names must not
+            // see the change.
+            fs::create_dir_all(saved.parent().unwrap())?;
+            copy_inode(&from, &saved, &meta)?;
+        }
+        if whited_out(&snappath, partial) {
+            continue;
+        }
+        copy_for_backup(&snappath, &from, &snappath.join(partial))?;
+    }
+    Ok(())
+}
+
+/// Save `partial` and, if it is a directory, everything under it, into
+/// every snapshot that lacks them, as it is about to be moved and they
+/// would no longer be found under the names the snapshots read them by.
+pub fn backup_tree(target: &Path, partial: &Path) -> io::Result<()> {
+    backup_snapshot(target, partial)?;
+    let live = live_path(target, partial);
+    if !live.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+        return Ok(());
+    }
+    for e in fs::read_dir(&live)? {
+        backup_tree(target, &partial.join(e?.file_name()))?;
+    }
+    Ok(())
+}
+
+pub fn whiteout_snapshot(target: &Path, partial: &Path) -> Result<(), std::io::Error> {
+    let partial = partial.strip_prefix("/").unwrap();
+    println!("whiteout_snapshot for {:?}", partial);
+    for e in std::fs::read_dir(target.join(".snapshots"))? {
+        let snappath = e?.path();
+        let real = snappath.join(partial);
+        println!("whiteout_snapshot: {:?}", real);
+        if whited_out(&snappath, partial) || real.symlink_metadata().is_ok() {
+            continue;
+        }
+        if let Some(parent) = partial.parent() {
+            // The directory is joined with the live one, so making it
+            // in the snapshot hides nothing.
+            copy_for_backup(&snappath, &target.join(parent), &snappath.join(parent))?;
+        }
+        // whiteout is a socket
+        adding_to(real.parent().unwrap(), || {
+            let result = unsafe { libc::mknod(cstr(&real).as_ptr(), libc::S_IFSOCK, 0) };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("whiteout mknod error({:?}, S_IFCHR, 0): {}", real, e);
+                return Err(e)
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// The path in the backing directory for a path in the live tree.
+pub fn live_path(target: &Path, partial: &Path) -> PathBuf {
+    target.join(partial.strip_prefix("/").unwrap())
+}
+
+/// The path in the backing directory `target` to read `partial` through:
+/// in a snapshot, the copy saved there if there is one, and otherwise the
+/// live file.  A directory is joined between the snapshot and the live
+/// one, so its listing is read from the live one.
+pub fn real_path(target: &Path, partial: &Path) -> PathBuf {
+    let partial = partial.strip_prefix("/").unwrap();
+    if let Ok(child) = partial.strip_prefix(".snapshots") {
+        let mut childstuff = child.iter();
+        if let Some(snapname) = childstuff.next() {
+            let rest = childstuff.as_path();
+            if target.join(".snapshots").join(snapname).is_dir() {
+                // The snapshot exists! Now check if the path has
+                // a snapshot value or whiteout.
+                match target.join(partial).symlink_metadata() {
+                    Ok(meta) => {
+                        if meta.file_type().is_socket() {
+                            return PathBuf::from("this is an invalid whiteout path");
+                        }
+                        if meta.is_dir() {
+                            // It is not a file that has been
+                            // overridden.  Directories are joined
+                            // between the snapshot and the
+                            // "real" directory.
+                            println!("case 1 not overridden");
+                            return target.join(rest);
+                        }
+                    },
+                    Err(_) => {
+                        // It is not a file that has been overridden,
+                        // unless through another of its names.
+                        println!("case 2 not overridden {:?}", target.join(partial));
+                        let snap = target.join(".snapshots").join(snapname);
+                        let live = target.join(rest);
+                        return linked_copy_of(&snap, &live).unwrap_or(live);
+                    }
+                }
+                // Anything else is a saved copy, be it a file, a
+                // symlink or a special file.
+            }
+        }
+    }
+    target.join(partial)
+}
+
+/// The path in the backing directory `target` to find the mode, owner,
+/// times and extended attributes of `partial` at.  That is `real_path`,
+/// except for a directory the snapshot saved a copy of, which has them
+/// as they were, though its listing is still joined with the live one.
+pub fn attr_path(target: &Path, partial: &Path) -> PathBuf {
+    if is_snapshot(partial) && !is_snapshot_root(partial) {
+        let saved = live_path(target, partial);
+        if saved.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+            return saved;
+        }
+    }
+    real_path(target, partial)
+}
+
+/// Where snapshots go while they are being removed.
+fn trash_path(target: &Path) -> PathBuf {
+    target.join(META_DIR).join("trash")
+}
+
+/// Throw away the snapshot at `partial`, and everything saved in it.
+pub fn remove_snapshot(target: &Path, partial: &Path) -> io::Result<()> {
+    let snap = live_path(target, partial);
+    if !fs::symlink_metadata(&snap)?.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    let name = snap.file_name().unwrap();
+    let trash = trash_path(target);
+    fs::create_dir_all(&trash)?;
+    let doomed = trash.join(name);
+    if doomed.symlink_metadata().is_ok() {
+        // Left over from a removal that was cut short.
+        remove_tree(&doomed)?;
+    }
+    // Each snapshot of ours taken since, which is being packed up for
+    // another node, must still find it there, as it was; it goes when the
+    // last of them does.  All but one get a copy, made in the trash so
+    // that it appears all at once.
+    let mut keepers = Vec::new();
+    for e in fs::read_dir(target.join(".snapshots"))? {
+        let ours = e?.path();
+        if ours.file_name().unwrap().to_string_lossy().starts_with(META_DIR) &&
+            ours != snap && ours.join(".snapshots").join(name).symlink_metadata().is_err()
+        {
+            keepers.push(ours);
+        }
+    }
+    while let Some(ours) = keepers.pop() {
+        copy_for_backup(&ours, &target.join(".snapshots"), &ours.join(".snapshots"))?;
+        let keep = ours.join(".snapshots").join(name);
+        if keepers.is_empty() {
+            return adding_to(&ours.join(".snapshots"), || fs::rename(&snap, &keep));
+        }
+        copy_tree(&snap, &doomed)?;
+        adding_to(&ours.join(".snapshots"), || fs::rename(&doomed, &keep))?;
+    }
+    fs::rename(&snap, &doomed)?;
+    empty_trash(target)
+}
+
+/// Make `to` a copy of the saved tree at `from`, whose files are linked
+/// rather than copied, as nothing changes them once saved.
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = from.symlink_metadata()?;
+    if meta.is_file() {
+        return fs::hard_link(from, to);
+    }
+    copy_inode(from, to, &meta)?;
+    if meta.is_dir() {
+        for e in fs::read_dir(from)? {
+            let e = e?;
+            adding_to(to, || copy_tree(&e.path(), &to.join(e.file_name())))?;
+        }
+    }
+    Ok(())
+}
+
+/// Finish removing any snapshots that were on their way out.
+pub fn empty_trash(target: &Path) -> io::Result<()> {
+    let trash = trash_path(target);
+    if trash.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    for e in fs::read_dir(&trash)? {
+        remove_tree(&e?.path())?;
+    }
+    Ok(())
+}
+
+/// Remove `path` and everything under it, without following symlinks.
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return fs::remove_file(path);
+    }
+    // A directory saved from the live tree keeps its mode, which need not
+    // let us empty it.
+    if meta.permissions().mode() & 0o700 != 0o700 {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    for e in fs::read_dir(path)? {
+        remove_tree(&e?.path())?;
+    }
+    fs::remove_dir(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn saved_files_match_however_they_are_copied() {
+        let target = std::env::temp_dir().join(format!("raftfs-snapshot-{}", std::process::id()));
+        fs::remove_dir_all(&target).ok();
+        fs::create_dir_all(target.join(META_DIR)).unwrap();
+        // Either answer is right, so long as copies are then made that way.
+        let mode = copy_mode(&target).unwrap();
+        assert_eq!(fs::read_dir(target.join(META_DIR)).unwrap().count(), 0);
+
+        let data: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+        fs::write(target.join("big"), &data).unwrap();
+        assert_eq!(copy_file(&target.join("big"), &target.join("saved"), CopyMode::Reflink).unwrap(), mode);
+        // The fallback, which is all some filesystems have.
+        assert_eq!(copy_file(&target.join("big"), &target.join("copied"), CopyMode::Copy).unwrap(),
+                   CopyMode::Copy);
+        fs::write(target.join("big"), b"changed").unwrap();
+        assert_eq!(fs::read(target.join("saved")).unwrap(), data);
+        assert_eq!(fs::read(target.join("copied")).unwrap(), data);
+        fs::remove_dir_all(&target).unwrap();
+    }
+}
@@ -0,0 +1,1050 @@
+// StateMachine :: Applies committed FsOps to the backing directory.
+//
+// Every node, leader or follower, feeds the entries raft has committed
+// through here, in log order.  The index of the last entry applied is kept
+// in TARGET/.raftfs/applied so that replaying the log after a restart skips
+// everything that has already been done.
+//
+// Along with it goes what we know of each node's session: the results of
+// the Proposals it has made that it may not have heard back about.  A node
+// that proposes something again gets the result of the first time, rather
+// than having it done twice.  Every node keeps the same sessions, since
+// they are built from the log, and they are packed into snapshots too.
+//
+// We also keep the files each node has opened for writing, and the name
+// each has now, if any, so that its writes go to the file it opened.  They
+// are only kept in memory: after a restart, or once a snapshot has been
+// installed, the writes to the files that were open go by their names.
+//
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+
+use super::archive;
+use super::blob::{self, BlobStore};
+use super::fsop::{FsOp, Handle, Proposal};
+use super::libc_extras::libc;
+use super::libc_wrappers;
+use super::raft::{LogIndex, NodeId};
+use super::snapshot::{self, is_snapshot, is_snapshot_root, live_path};
+
+use time::Timespec;
+
+/// Where raftfs keeps its own bookkeeping inside the backing directory.
+/// It is hidden from the mounted filesystem.
+pub const META_DIR: &str = ".raftfs";
+
+/// Is `partial` (a path in the mount) inside our metadata directory, or
+/// one of the snapshots we take for our own purposes, or a snapshot's own
+/// bookkeeping?
+pub fn is_metadata(partial: &Path) -> bool {
+    let partial = match partial.strip_prefix("/") {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    if partial.starts_with(META_DIR) {
+        return true;
+    }
+    let mut in_snapshots = match partial.strip_prefix(".snapshots") {
+        Ok(p) => p.iter(),
+        Err(_) => return false,
+    };
+    match in_snapshots.next() {
+        Some(name) => name.to_string_lossy().starts_with(META_DIR) ||
+            in_snapshots.as_path().starts_with(META_DIR),
+        None => false,
+    }
+}
+
+/// Where the applied index and the sessions are recorded.
+pub fn applied_path(target: &Path) -> PathBuf {
+    target.join(META_DIR).join("applied")
+}
+
+/// The results of one node's recent Proposals.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Session {
+    boot: u64,
+    floor: u64,
+    results: BTreeMap<u64, Result<(), libc::c_int>>,
+}
+
+type Sessions = HashMap<NodeId, Session>;
+
+/// A file somebody has open for writing, and the name it has now, if it
+/// still has one.
+struct OpenFile {
+    file: File,
+    path: Option<PathBuf>,
+}
+
+/// The files open for writing, by the node that opened them, its boot and
+/// the handle it gave the file.
+type OpenFiles = HashMap<(NodeId, u64, Handle), OpenFile>;
+
+/// Who an op is being applied for, as a key into the OpenFiles.
+type Opener = (NodeId, u64);
+
+/// The contents of the applied file: the index on the first line, then a
+/// line for each session: `session ORIGIN BOOT FLOOR SERIAL:ERRNO...`,
+/// where an errno of 0 means success.
+fn format_applied(index: LogIndex, sessions: &Sessions) -> String {
+    let mut out = format!("{}\n", index);
+    let mut origins: Vec<&NodeId> = sessions.keys().collect();
+    origins.sort();
+    for origin in origins {
+        let session = &sessions[origin];
+        out.push_str(&format!("session {} {} {}", origin, session.boot, session.floor));
+        for (serial, result) in &session.results {
+            out.push_str(&format!(" {}:{}", serial, result.err().unwrap_or(0)));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn parse_applied(text: &str) -> io::Result<(LogIndex, Sessions)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad applied file {:?}", text));
+    let mut lines = text.lines();
+    let index = lines.next().unwrap_or("").trim().parse().map_err(|_| bad())?;
+    let mut sessions = HashMap::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 4 || words[0] != "session" {
+            return Err(bad());
+        }
+        let number = |w: &str| w.parse::<u64>().map_err(|_| bad());
+        let mut session = Session { boot: number(words[2])?, floor: number(words[3])?,
+                                    results: BTreeMap::new() };
+        for w in &words[4..] {
+            let mut parts = w.splitn(2, ':');
+            let serial = number(parts.next().unwrap_or(""))?;
+            let errno: libc::c_int = parts.next().unwrap_or("").parse().map_err(|_| bad())?;
+            session.results.insert(serial, if errno == 0 { Ok(()) } else { Err(errno) });
+        }
+        sessions.insert(number(words[1])?, session);
+    }
+    Ok((index, sessions))
+}
+
+pub struct StateMachine {
+    target: PathBuf,
+    applied: LogIndex,
+    sessions: Sessions,
+    open: OpenFiles,
+    // The entry just after the recorded index may have been partly (or
+    // entirely) applied before we crashed, so it gets extra care.
+    recovering: bool,
+}
+
+impl StateMachine {
+    pub fn open(target: PathBuf) -> io::Result<StateMachine> {
+        let meta = target.join(META_DIR);
+        if !meta.is_dir() {
+            fs::create_dir(&meta)?;
+        }
+        let (applied, sessions) = match File::open(applied_path(&target)) {
+            Ok(mut f) => {
+                let mut s = String::new();
+                f.read_to_string(&mut s)?;
+                parse_applied(&s)?
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (0, HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        debug!("state machine for {:?} has applied through {}", target, applied);
+        if let Err(e) = snapshot::empty_trash(&target) {
+            warn!("unable to finish removing snapshots in {:?}: {}", target, e);
+        }
+        Ok(StateMachine {
+            target: target,
+            applied: applied,
+            sessions: sessions,
+            open: HashMap::new(),
+            recovering: applied > 0,
+        })
+    }
+
+    /// The backing directory.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// The index of the last entry that has been applied.
+    pub fn applied(&self) -> LogIndex {
+        self.applied
+    }
+
+    /// Forget that anything past `index` was applied.  This is only for
+    /// when the log we are replaying is shorter than what we recorded.
+    pub fn rewind(&mut self, index: LogIndex) {
+        if index < self.applied {
+            self.record_applied(index);
+        }
+    }
+
+    /// Replace everything with the current snapshot archive, which runs
+    /// through `index`.
+    pub fn install(&mut self, index: LogIndex) -> io::Result<()> {
+        let (found, _, applied) = archive::unpack(&self.target, &archive::current_path(&self.target))?;
+        if found != index {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("expected a snapshot through {}, not {}", index, found)));
+        }
+        // The sessions as of the snapshot come with it.
+        self.sessions = if applied.is_empty() {
+            HashMap::new()
+        } else {
+            parse_applied(&String::from_utf8_lossy(&applied))?.1
+        };
+        self.open.clear();
+        self.recovering = false;
+        self.record_applied(index);
+        Ok(())
+    }
+
+    /// The file `origin` opened as `handle` when it started at `boot`, if
+    /// it is open.
+    pub fn open_file(&self, origin: NodeId, boot: u64, handle: Handle) -> Option<&File> {
+        self.open.get(&(origin, boot, handle)).map(|f| &f.file)
+    }
+
+    /// Note that the entry at `index` needed no work of ours.
+    pub fn skip(&mut self, index: LogIndex) {
+        if index > self.applied {
+            self.record_applied(index);
+        }
+    }
+
+    /// Apply the committed `op` found at `index` in the log.  Entries we
+    /// have already applied are ignored, so it is always safe to replay.
+    /// The node applies proposals; this is for trying ops out directly.
+    #[cfg(test)]
+    pub fn apply(&mut self, index: LogIndex, op: &FsOp) -> Result<(), libc::c_int> {
+        if index <= self.applied {
+            debug!("already applied {}: {:?}", index, op);
+            return Ok(());
+        }
+        let result = self.execute(index, (0, 0), op);
+        self.record_applied(index);
+        result
+    }
+
+    /// Apply the committed proposal found at `index` in the log, unless
+    /// the same proposal was applied before, in which case we give the
+    /// result it had then.
+    pub fn apply_proposal(&mut self, index: LogIndex, p: &Proposal) -> Result<(), libc::c_int> {
+        if index <= self.applied {
+            debug!("already applied {}: {:?}", index, p.op);
+            return self.earlier_result(p).unwrap_or(Ok(()));
+        }
+        if let Some(result) = self.earlier_result(p) {
+            debug!("entry {} repeats proposal {} from {}", index, p.serial, p.origin);
+            self.record_applied(index);
+            return result;
+        }
+        let result = self.execute(index, (p.origin, p.boot), &p.op);
+        let session = self.sessions.entry(p.origin).or_default();
+        if session.boot != p.boot {
+            // The node restarted, so it has given up on the old session,
+            // and closed the files it had open.
+            *session = Session { boot: p.boot, floor: 0, results: BTreeMap::new() };
+            self.open.retain(|&(origin, boot, _), _| origin != p.origin || boot == p.boot);
+        }
+        if p.floor > session.floor {
+            session.floor = p.floor;
+            session.results = session.results.split_off(&(p.floor + 1));
+        }
+        session.results.insert(p.serial, result);
+        self.record_applied(index);
+        result
+    }
+
+    /// If `p` has been applied before, or nobody can be waiting to hear
+    /// about it any more, what to say about it now.
+    fn earlier_result(&self, p: &Proposal) -> Option<Result<(), libc::c_int>> {
+        let session = self.sessions.get(&p.origin)?;
+        if session.boot > p.boot || (session.boot == p.boot && p.serial <= session.floor) {
+            return Some(Ok(()));
+        }
+        if session.boot == p.boot {
+            return session.results.get(&p.serial).cloned();
+        }
+        None
+    }
+
+    /// Carry out the op at `index`, without recording that we have.
+    fn execute(&mut self, index: LogIndex, opener: Opener, op: &FsOp) -> Result<(), libc::c_int> {
+        if index != self.applied + 1 {
+            warn!("applying {} after {}", index, self.applied);
+        }
+        let recovering = self.recovering && index == self.applied + 1;
+        self.recovering = false;
+
+        if touches_metadata(op) {
+            Err(libc::EPERM)
+        } else if recovering && self.already_applied(op) {
+            info!("entry {} was applied before we restarted: {:?}", index, op);
+            Ok(())
+        } else {
+            apply_op(&self.target, &mut self.open, opener, op)
+        }
+    }
+
+    /// Whether the effect of `op`, which cannot simply be done twice, is
+    /// already present.  Everything else can be repeated harmlessly.
+    fn already_applied(&self, op: &FsOp) -> bool {
+        let exists = |p: &Path| live_path(&self.target, p).symlink_metadata().is_ok();
+        match *op {
+            FsOp::Mknod { ref parent, ref name, .. } |
+            FsOp::Mkdir { ref parent, ref name, .. } |
+            FsOp::Symlink { ref parent, ref name, .. } |
+            FsOp::Create { ref parent, ref name, .. } => exists(&parent.join(name)),
+            FsOp::Link { ref newparent, ref newname, .. } => exists(&newparent.join(newname)),
+            FsOp::Unlink { ref parent, ref name } |
+            FsOp::Rmdir { ref parent, ref name } => !exists(&parent.join(name)),
+            FsOp::Rename { ref parent, ref name, ref newparent, ref newname } => {
+                !exists(&parent.join(name)) && exists(&newparent.join(newname))
+            },
+            _ => false,
+        }
+    }
+
+    fn record_applied(&mut self, index: LogIndex) {
+        let path = applied_path(&self.target);
+        let tmp = path.with_extension("tmp");
+        let text = format_applied(index, &self.sessions);
+        let result = File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(text.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            // We carry on regardless: at worst we will apply this entry
+            // again after a restart, which apply() is prepared for.
+            error!("unable to record applied index {}: {}", index, e);
+        }
+        self.applied = index;
+    }
+}
+
+fn touches_metadata(op: &FsOp) -> bool {
+    match *op {
+        FsOp::Mknod { ref parent, ref name, .. } |
+        FsOp::Mkdir { ref parent, ref name, .. } |
+        FsOp::Unlink { ref parent, ref name } |
+        FsOp::Rmdir { ref parent, ref name } |
+        FsOp::Symlink { ref parent, ref name, .. } |
+        FsOp::Create { ref parent, ref name, .. } => is_metadata(&parent.join(name)),
+        FsOp::Rename { ref parent, ref name, ref newparent, ref newname } => {
+            is_metadata(&parent.join(name)) || is_metadata(&newparent.join(newname))
+        },
+        FsOp::Link { ref path, ref newparent, ref newname } => {
+            is_metadata(path) || is_metadata(&newparent.join(newname))
+        },
+        FsOp::Write { ref path, .. } |
+        FsOp::WriteBlob { ref path, .. } |
+        FsOp::Truncate { ref path, .. } |
+        FsOp::Chmod { ref path, .. } |
+        FsOp::Chown { ref path, .. } |
+        FsOp::Utimens { ref path, .. } |
+        FsOp::SetXattr { ref path, .. } |
+        FsOp::RemoveXattr { ref path, .. } |
+        FsOp::Open { ref path, .. } => is_metadata(path),
+        FsOp::Checkpoint |
+        FsOp::Release { .. } => false,
+    }
+}
+
+/// The files in `open` that were at `from` are now at `to`, or nowhere.
+fn moved(open: &mut OpenFiles, from: &Path, to: Option<&Path>) {
+    for f in open.values_mut() {
+        let now = match f.path.as_ref().map(|path| path.strip_prefix(from)) {
+            Some(Ok(rest)) if rest.as_os_str().is_empty() => to.map(Path::to_owned),
+            Some(Ok(rest)) => to.map(|to| to.join(rest)),
+            _ => continue,
+        };
+        f.path = now;
+    }
+}
+
+/// Carry out `op` on the backing directory `target`, for `opener`, whose
+/// open files are among `open`.
+fn apply_op(target: &Path, open: &mut OpenFiles, opener: Opener, op: &FsOp) -> Result<(), libc::c_int> {
+    match *op {
+        FsOp::Mknod { ref parent, ref name, mode, rdev } => {
+            let parent_path_name = parent.join(name);
+            snapshot::mustnt_exist(target, &parent_path_name)?;
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::whiteout_snapshot(target, &parent_path_name).ok();
+
+            let real = live_path(target, parent).join(name);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
+                libc::mknod(path_c.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("mknod({:?}, {}, {}): {}", real, mode, rdev, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::Mkdir { ref parent, ref name, mode } => {
+            let parent_path_name = parent.join(name);
+            snapshot::mustnt_exist(target, &parent_path_name)?;
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::whiteout_snapshot(target, &parent_path_name).ok();
+
+            let real = live_path(target, parent).join(name);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
+                libc::mkdir(path_c.as_ptr(), mode as libc::mode_t)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("mkdir({:?}, {:#o}): {}", real, mode, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::Unlink { ref parent, ref name } => {
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, &parent.join(name)).ok();
+
+            let real = live_path(target, parent).join(name);
+            fs::remove_file(&real)
+                .map_err(|ioerr| {
+                    error!("unlink({:?}): {}", real, ioerr);
+                    ioerr.raw_os_error().unwrap()
+                })?;
+            moved(open, &parent.join(name), None);
+            Ok(())
+        },
+        FsOp::Rmdir { ref parent, ref name } => {
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            if is_snapshot_root(&parent.join(name)) {
+                return snapshot::remove_snapshot(target, &parent.join(name)).map_err(|e| {
+                    error!("removing snapshot {:?}: {}", name, e);
+                    e.raw_os_error().unwrap_or(libc::EIO)
+                });
+            }
+            snapshot::backup_snapshot(target, &parent.join(name)).ok();
+
+            let real = live_path(target, parent).join(name);
+            fs::remove_dir(&real)
+                .map_err(|ioerr| {
+                    error!("rmdir({:?}): {}", real, ioerr);
+                    ioerr.raw_os_error().unwrap()
+                })
+        },
+        FsOp::Symlink { ref parent, ref name, target: ref linktarget } => {
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::whiteout_snapshot(target, &parent.join(name)).ok();
+
+            let real = live_path(target, parent).join(name);
+            ::std::os::unix::fs::symlink(linktarget, &real)
+                .map_err(|e| {
+                    error!("symlink({:?}, {:?}): {}", real, linktarget, e);
+                    e.raw_os_error().unwrap()
+                })
+        },
+        FsOp::Rename { ref parent, ref name, ref newparent, ref newname } => {
+            if is_snapshot(parent) || is_snapshot(newparent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_tree(target, &parent.join(name)).ok();
+            snapshot::backup_snapshot(target, &newparent.join(newname)).ok();
+            snapshot::whiteout_snapshot(target, &newparent.join(newname)).ok();
+
+            let real = live_path(target, parent).join(name);
+            let newreal = live_path(target, newparent).join(newname);
+            fs::rename(&real, &newreal)
+                .map_err(|ioerr| {
+                    error!("rename({:?}, {:?}): {}", real, newreal, ioerr);
+                    ioerr.raw_os_error().unwrap()
+                })?;
+            if real != newreal {
+                moved(open, &newparent.join(newname), None);
+                moved(open, &parent.join(name), Some(&newparent.join(newname)));
+            }
+            Ok(())
+        },
+        FsOp::Link { ref path, ref newparent, ref newname } => {
+            if is_snapshot(path) || is_snapshot(newparent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::whiteout_snapshot(target, &newparent.join(newname)).ok();
+
+            let real = live_path(target, path);
+            let newreal = live_path(target, newparent).join(newname);
+            fs::hard_link(&real, &newreal)
+                .map_err(|e| {
+                    error!("link({:?}, {:?}): {}", real, newreal, e);
+                    e.raw_os_error().unwrap()
+                })
+        },
+        FsOp::Create { ref parent, ref name, mode, flags, handle } => {
+            if is_snapshot(parent) {
+                return Err(libc::EROFS);
+            }
+            snapshot::whiteout_snapshot(target, &parent.join(name)).ok();
+
+            // Writes come with their offsets, so the file we keep open
+            // for them mustn't append.
+            let flags = flags as i32 & !libc::O_APPEND | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC;
+            let real = live_path(target, parent).join(name);
+            let fd = unsafe {
+                let real_c = CString::from_vec_unchecked(real.clone().into_os_string().into_vec());
+                libc::open(real_c.as_ptr(), flags, mode)
+            };
+            if -1 == fd {
+                let ioerr = io::Error::last_os_error();
+                error!("create({:?}): {}", real, ioerr);
+                return Err(ioerr.raw_os_error().unwrap());
+            }
+            match handle {
+                Some(handle) => {
+                    let file = unsafe { File::from_raw_fd(fd) };
+                    let opened = OpenFile { file: file, path: Some(parent.join(name)) };
+                    open.insert((opener.0, opener.1, handle), opened);
+                    Ok(())
+                },
+                None => libc_wrappers::close(fd as u64),
+            }
+        },
+        FsOp::Open { ref path, flags, handle } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            let real = live_path(target, path);
+            let read = flags as i32 & libc::O_ACCMODE == libc::O_RDWR;
+            let file = OpenOptions::new().read(read).write(true).open(&real).map_err(|e| {
+                error!("open({:?}, {:#o}): {}", real, flags, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+            open.insert((opener.0, opener.1, handle), OpenFile { file: file, path: Some(path.clone()) });
+            Ok(())
+        },
+        FsOp::Release { handle } => {
+            open.remove(&(opener.0, opener.1, handle));
+            Ok(())
+        },
+        FsOp::Write { ref path, handle, offset, ref data } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            let opened = handle.and_then(|h| open.get(&(opener.0, opener.1, h)));
+            let result = match opened {
+                Some(f) => {
+                    // What has been unlinked was saved when it was.
+                    if let Some(ref path) = f.path {
+                        snapshot::backup_snapshot(target, path).ok();
+                    }
+                    f.file.write_all_at(data, offset)
+                },
+                None => {
+                    snapshot::backup_snapshot(target, path).ok();
+                    OpenOptions::new().write(true).open(live_path(target, path))
+                        .and_then(|f| f.write_all_at(data, offset))
+                },
+            };
+            result.map_err(|e| {
+                error!("write {:?}, {:#x} @ {:#x}: {}", path, data.len(), offset, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })
+        },
+        FsOp::WriteBlob { ref path, handle, offset, ref hash } => {
+            // The node makes sure we have the blob before applying this.
+            let data = BlobStore::new(target).get(hash).map_err(|e| {
+                error!("unable to read blob {} for {:?}: {}", blob::hex(hash), path, e);
+                libc::EIO
+            })?;
+            let write = FsOp::Write { path: path.clone(), handle: handle, offset: offset, data: data };
+            apply_op(target, open, opener, &write)
+        },
+        FsOp::Truncate { ref path, size } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let real = live_path(target, path);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.into_os_string().into_vec());
+                libc::truncate64(path_c.as_ptr(), size as i64)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("truncate({:?}, {}): {}", path, size, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::Chmod { ref path, mode } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let real = live_path(target, path);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.into_os_string().into_vec());
+                libc::chmod(path_c.as_ptr(), mode as libc::mode_t)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("chmod({:?}, {:#o}): {}", path, mode, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::Chown { ref path, uid, gid } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let uid = uid.unwrap_or(u32::MAX);   // docs say "-1", but uid_t is unsigned
+            let gid = gid.unwrap_or(u32::MAX);   // ditto for gid_t
+            let real = live_path(target, path);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.into_os_string().into_vec());
+                libc::chown(path_c.as_ptr(), uid, gid)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("chown({:?}, {}, {}): {}", path, uid, gid, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::Utimens { ref path, atime, mtime } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let times = [timespec_to_libc(atime), timespec_to_libc(mtime)];
+            let real = live_path(target, path);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.into_os_string().into_vec());
+                libc::utimensat(libc::AT_FDCWD, path_c.as_ptr(), &times as *const libc::timespec, libc::AT_SYMLINK_NOFOLLOW)
+            };
+            if -1 == result {
+                let e = io::Error::last_os_error();
+                error!("utimens({:?}, {:?}, {:?}): {}", path, atime, mtime, e);
+                return Err(e.raw_os_error().unwrap());
+            }
+            Ok(())
+        },
+        FsOp::SetXattr { ref path, ref name, ref value, flags, position } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let real = live_path(target, path);
+            libc_wrappers::lsetxattr(real.into_os_string(), name.to_owned(), value, flags, position)
+        },
+        FsOp::RemoveXattr { ref path, ref name } => {
+            if is_snapshot(path) {
+                return Err(libc::EROFS);
+            }
+            snapshot::backup_snapshot(target, path).ok();
+            let real = live_path(target, path);
+            libc_wrappers::lremovexattr(real.into_os_string(), name.to_owned())
+        },
+        // The node does the work, once this has been applied.
+        FsOp::Checkpoint => Ok(()),
+    }
+}
+
+fn timespec_to_libc(time: Option<Timespec>) -> libc::timespec {
+    if let Some(time) = time {
+        libc::timespec {
+            tv_sec: time.sec as libc::time_t,
+            tv_nsec: time.nsec as libc::time_t,
+        }
+    } else {
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("raftfs-sm-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ops() -> Vec<FsOp> {
+        vec![
+            FsOp::Mkdir { parent: PathBuf::from("/"), name: OsString::from("d"), mode: 0o755 },
+            FsOp::Create { parent: PathBuf::from("/d"), name: OsString::from("f"), mode: 0o644,
+                           flags: libc::O_WRONLY as u32, handle: None },
+            FsOp::Write { path: PathBuf::from("/d/f"), handle: None, offset: 0, data: b"hello".to_vec() },
+            FsOp::Rename { parent: PathBuf::from("/d"), name: OsString::from("f"),
+                           newparent: PathBuf::from("/"), newname: OsString::from("g") },
+        ]
+    }
+
+    /// A tree holding d/f, as `ops` leaves it before the rename, and a
+    /// snapshot s of it.
+    fn with_snapshot(name: &str) -> (PathBuf, StateMachine) {
+        let dir = tempdir(name);
+        let mkdir = |parent: &str, name: &str| {
+            FsOp::Mkdir { parent: PathBuf::from(parent), name: OsString::from(name), mode: 0o755 }
+        };
+        let mut sm = StateMachine::open(dir.clone()).unwrap();
+        let mut ops = vec![mkdir("/", ".snapshots")];
+        ops.extend(self::ops().into_iter().take(3));
+        ops.push(mkdir("/.snapshots", "s"));
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+        }
+        (dir, sm)
+    }
+
+    #[test]
+    fn replay_is_idempotent() {
+        let dir = tempdir("replay");
+        {
+            let mut sm = StateMachine::open(dir.clone()).unwrap();
+            for (i, op) in ops().iter().enumerate() {
+                assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+            }
+            assert_eq!(sm.applied(), 4);
+        }
+        // Replaying everything after a clean restart does nothing.
+        {
+            let mut sm = StateMachine::open(dir.clone()).unwrap();
+            assert_eq!(sm.applied(), 4);
+            for (i, op) in ops().iter().enumerate() {
+                assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+            }
+        }
+        assert_eq!(fs::read(dir.join("g")).unwrap(), b"hello");
+        assert!(!dir.join("d/f").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn entry_applied_but_not_recorded() {
+        let dir = tempdir("crash");
+        {
+            let mut sm = StateMachine::open(dir.clone()).unwrap();
+            for (i, op) in ops().iter().enumerate() {
+                sm.apply(i as LogIndex + 1, op).unwrap();
+            }
+        }
+        // Pretend we crashed after the rename but before noting it.
+        fs::write(dir.join(META_DIR).join("applied"), "3\n").unwrap();
+        let mut sm = StateMachine::open(dir.clone()).unwrap();
+        assert_eq!(sm.applied(), 3);
+        assert_eq!(sm.apply(4, &ops()[3]), Ok(()));
+        assert_eq!(sm.applied(), 4);
+        assert_eq!(fs::read(dir.join("g")).unwrap(), b"hello");
+        // A later failure of the same kind is reported as usual.
+        assert_eq!(sm.apply(5, &ops()[3]), Err(libc::ENOENT));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn writes_go_to_the_file_that_was_opened() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = tempdir("handles");
+        let mut sm = StateMachine::open(dir.clone()).unwrap();
+        let write = |path: &str, handle, data: &[u8]| {
+            FsOp::Write { path: PathBuf::from(path), handle: Some(handle), offset: 0, data: data.to_vec() }
+        };
+        let ops = [
+            ops()[0].clone(),
+            // Read-only, but opened for writing as it was made.
+            FsOp::Create { parent: PathBuf::from("/d"), name: OsString::from("f"), mode: 0o444,
+                           flags: libc::O_WRONLY as u32, handle: Some(1) },
+            write("/d/f", 1, b"hello"),
+            // Renamed to g, while the writer still calls it d/f.
+            ops()[3].clone(),
+            write("/d/f", 1, b"HELLO"),
+            FsOp::Create { parent: PathBuf::from("/d"), name: OsString::from("h"), mode: 0o644,
+                           flags: libc::O_WRONLY as u32, handle: None },
+            FsOp::Open { path: PathBuf::from("/d/h"), flags: libc::O_RDWR as u32, handle: 2 },
+            FsOp::Chmod { path: PathBuf::from("/d/h"), mode: 0o444 },
+            write("/d/h", 2, b"still open"),
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 1, op), Ok(()));
+        }
+        assert_eq!(fs::read(dir.join("g")).unwrap(), b"HELLO");
+        assert_eq!(dir.join("g").metadata().unwrap().mode() & 0o777, 0o444);
+        assert!(!dir.join("d/f").exists());
+        assert_eq!(fs::read(dir.join("d/h")).unwrap(), b"still open");
+
+        // An unlinked file can still be written, though nobody will see it.
+        let unlink = FsOp::Unlink { parent: PathBuf::from("/"), name: OsString::from("g") };
+        assert_eq!(sm.apply(10, &unlink), Ok(()));
+        assert_eq!(sm.apply(11, &write("/g", 1, b"gone")), Ok(()));
+        assert!(!dir.join("g").exists());
+        // Once released, the handle is forgotten, and the name is used.
+        assert_eq!(sm.apply(12, &FsOp::Release { handle: 1 }), Ok(()));
+        assert!(sm.open_file(0, 0, 1).is_none());
+        assert_eq!(sm.apply(13, &write("/g", 1, b"gone")), Err(libc::ENOENT));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn metadata_is_off_limits() {
+        let dir = tempdir("meta");
+        let mut sm = StateMachine::open(dir.clone()).unwrap();
+        let op = FsOp::Unlink { parent: PathBuf::from("/.raftfs"), name: OsString::from("applied") };
+        assert_eq!(sm.apply(1, &op), Err(libc::EPERM));
+        assert!(dir.join(META_DIR).join("applied").exists());
+        let op = FsOp::Mkdir { parent: PathBuf::from("/.snapshots"), name: OsString::from(".raftfs-1"),
+                               mode: 0o755 };
+        assert_eq!(sm.apply(2, &op), Err(libc::EPERM));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn proposals_are_applied_once() {
+        let dir = tempdir("once");
+        let proposal = |serial, floor, op: &FsOp| {
+            Proposal { origin: 2, boot: 7, serial: serial, floor: floor, op: op.clone() }
+        };
+        {
+            let mut sm = StateMachine::open(dir.clone()).unwrap();
+            assert_eq!(sm.apply_proposal(1, &proposal(1, 0, &ops()[0])), Ok(()));
+            let rmdir = FsOp::Rmdir { parent: PathBuf::from("/"), name: OsString::from("e") };
+            assert_eq!(sm.apply_proposal(2, &proposal(2, 0, &rmdir)), Err(libc::ENOENT));
+            // A proposal sent twice gives the same answer the second time.
+            assert_eq!(sm.apply_proposal(3, &proposal(1, 0, &ops()[0])), Ok(()));
+        }
+        // The sessions survive a restart.
+        let mut sm = StateMachine::open(dir.clone()).unwrap();
+        assert_eq!(sm.applied(), 3);
+        let rmdir = FsOp::Rmdir { parent: PathBuf::from("/"), name: OsString::from("e") };
+        assert_eq!(sm.apply_proposal(4, &proposal(2, 1, &rmdir)), Err(libc::ENOENT));
+        assert_eq!(sm.apply_proposal(5, &proposal(3, 2, &ops()[1])), Ok(()));
+        // Once the node has moved on, nobody is waiting for the answer.
+        assert_eq!(sm.apply_proposal(6, &proposal(2, 2, &rmdir)), Ok(()));
+        assert_eq!(sm.applied(), 6);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn removing_a_snapshot_removes_its_copies() {
+        let (dir, mut sm) = with_snapshot("rmsnap");
+        let mkdir = |parent: &str, name: &str| {
+            FsOp::Mkdir { parent: PathBuf::from(parent), name: OsString::from(name), mode: 0o755 }
+        };
+        let rmdir = |parent: &str, name: &str| {
+            FsOp::Rmdir { parent: PathBuf::from(parent), name: OsString::from(name) }
+        };
+        let ops = [
+            mkdir("/.snapshots", "b"),
+            // A copy of f is saved in both, and e is whited out in both.
+            FsOp::Unlink { parent: PathBuf::from("/d"), name: OsString::from("f") },
+            mkdir("/", "e"),
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 6, op), Ok(()));
+        }
+        assert_eq!(fs::read(dir.join(".snapshots/s/d/f")).unwrap(), b"hello");
+
+        assert_eq!(sm.apply(9, &rmdir("/.snapshots", "s")), Ok(()));
+        assert!(!dir.join(".snapshots/s").exists());
+        assert_eq!(fs::read_dir(dir.join(META_DIR).join("trash")).unwrap().count(), 0);
+        // The other snapshot and the live tree are as they were.
+        assert_eq!(fs::read(dir.join(".snapshots/b/d/f")).unwrap(), b"hello");
+        assert!(dir.join(".snapshots/b/e").symlink_metadata().is_ok());
+        assert!(dir.join("e").is_dir());
+        assert_eq!(sm.apply(10, &rmdir("/.snapshots", "s")), Err(libc::ENOENT));
+        // What is in a snapshot can't be removed piecemeal.
+        assert_eq!(sm.apply(11, &rmdir("/.snapshots/b", "d")), Err(libc::EROFS));
+
+        // A removal cut short is finished when we start up.
+        let trash = dir.join(META_DIR).join("trash");
+        fs::rename(dir.join(".snapshots/b"), trash.join("b")).unwrap();
+        drop(sm);
+        StateMachine::open(dir.clone()).unwrap();
+        assert_eq!(fs::read_dir(&trash).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn first_change_after_a_snapshot_saves_a_copy() {
+        use std::os::unix::fs::PermissionsExt;
+        let (dir, mut sm) = with_snapshot("cow");
+        let path = || PathBuf::from("/d/f");
+        let ops = [
+            FsOp::Write { path: path(), handle: None, offset: 0, data: b"HELLO, world".to_vec() },
+            FsOp::Chmod { path: path(), mode: 0o600 },
+            FsOp::Truncate { path: path(), size: 2 },
+            // Something new, in a directory the snapshot has no copy of.
+            FsOp::Create { parent: PathBuf::from("/d"), name: OsString::from("new"), mode: 0o644,
+                           flags: libc::O_WRONLY as u32, handle: None },
+            FsOp::Write { path: PathBuf::from("/d/new"), handle: None, offset: 0, data: b"new".to_vec() },
+            FsOp::Mkdir { parent: PathBuf::from("/d"), name: OsString::from("sub"), mode: 0o755 },
+            FsOp::Mkdir { parent: PathBuf::from("/"), name: OsString::from("e"), mode: 0o755 },
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 6, op), Ok(()));
+        }
+        assert_eq!(fs::read(dir.join("d/f")).unwrap(), b"HE");
+        let real = |path: &str| snapshot::real_path(&dir, Path::new(path));
+        // The snapshot kept the file as it was before the first change.
+        let saved = real("/.snapshots/s/d/f");
+        assert_eq!(saved, dir.join(".snapshots/s/d/f"));
+        assert_eq!(fs::read(&saved).unwrap(), b"hello");
+        assert_eq!(saved.metadata().unwrap().permissions().mode() & 0o777, 0o644);
+        // A directory it saved on the way is still listed from the live
+        // one, where what came since reads as missing.
+        assert!(dir.join(".snapshots/s/d").is_dir());
+        assert_eq!(real("/.snapshots/s/d"), dir.join("d"));
+        assert!(real("/.snapshots/s/d/new").symlink_metadata().is_err());
+        assert!(real("/.snapshots/s/d/sub").symlink_metadata().is_err());
+        assert!(real("/.snapshots/s/e").symlink_metadata().is_err());
+        assert_eq!(real("/.snapshots/s"), dir);
+
+        let op = FsOp::Chmod { path: PathBuf::from("/.snapshots/s/d/f"), mode: 0o777 };
+        assert_eq!(sm.apply(13, &op), Err(libc::EROFS));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn moving_a_directory_saves_what_is_in_it() {
+        let (dir, mut sm) = with_snapshot("cowmove");
+        let ops = [
+            FsOp::Rename { parent: PathBuf::from("/"), name: OsString::from("d"),
+                           newparent: PathBuf::from("/"), newname: OsString::from("e") },
+            FsOp::Write { path: PathBuf::from("/e/f"), handle: None, offset: 0, data: b"HELLO".to_vec() },
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 6, op), Ok(()));
+        }
+        let real = |path: &str| snapshot::real_path(&dir, Path::new(path));
+        // Nothing under d had changed, but none of it is where it was.
+        assert_eq!(fs::read(real("/.snapshots/s/d/f")).unwrap(), b"hello");
+        assert!(real("/.snapshots/s/e").symlink_metadata().is_err());
+        assert_eq!(fs::read(dir.join("e/f")).unwrap(), b"HELLO");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn saved_copies_keep_their_metadata() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let (dir, mut sm) = with_snapshot("cowmeta");
+        let ops = vec![
+            // Some of everything, for a second snapshot t to hold.
+            FsOp::Chmod { path: PathBuf::from("/d"), mode: 0o750 },
+            FsOp::Symlink { parent: PathBuf::from("/d"), name: OsString::from("ln"), target: PathBuf::from("../x") },
+            FsOp::Mknod { parent: PathBuf::from("/d"), name: OsString::from("fifo"),
+                          mode: libc::S_IFIFO | 0o640, rdev: 0 },
+            FsOp::Chmod { path: PathBuf::from("/d/f"), mode: 0o4755 },
+            FsOp::Utimens { path: PathBuf::from("/d/f"), atime: Some(Timespec::new(1000, 1)),
+                            mtime: Some(Timespec::new(2000, 2)) },
+            FsOp::Utimens { path: PathBuf::from("/d"), atime: None, mtime: Some(Timespec::new(3000, 3)) },
+            FsOp::Mkdir { parent: PathBuf::from("/.snapshots"), name: OsString::from("t"), mode: 0o755 },
+            FsOp::Unlink { parent: PathBuf::from("/d"), name: OsString::from("ln") },
+            FsOp::Unlink { parent: PathBuf::from("/d"), name: OsString::from("fifo") },
+            FsOp::Chmod { path: PathBuf::from("/d/f"), mode: 0o600 },
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 6, op), Ok(()));
+        }
+
+        let saved = dir.join(".snapshots/t/d");
+        assert_eq!(fs::read_link(saved.join("ln")).unwrap(), PathBuf::from("../x"));
+        let fifo = saved.join("fifo").symlink_metadata().unwrap();
+        assert!(fifo.file_type().is_fifo());
+        assert_eq!(fifo.mode() & 0o7777, 0o640);
+        let f = saved.join("f").symlink_metadata().unwrap();
+        assert_eq!(f.mode() & 0o7777, 0o4755);
+        assert_eq!((f.atime(), f.atime_nsec(), f.mtime(), f.mtime_nsec()), (1000, 1, 2000, 2));
+        // The directory was saved on the way, and kept its time as it filled.
+        let d = saved.symlink_metadata().unwrap();
+        assert_eq!((d.mode() & 0o7777, d.mtime(), d.mtime_nsec()), (0o750, 3000, 3));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn snapshot_directories_keep_their_attributes() {
+        use std::os::unix::fs::MetadataExt;
+        let (dir, mut sm) = with_snapshot("cowdirattr");
+        let op = FsOp::Chmod { path: PathBuf::from("/d"), mode: 0o700 };
+        assert_eq!(sm.apply(6, &op), Ok(()));
+
+        let partial = Path::new("/.snapshots/s/d");
+        let attrs = snapshot::attr_path(&dir, partial);
+        assert_eq!(attrs, dir.join(".snapshots/s/d"));
+        assert_eq!(attrs.metadata().unwrap().mode() & 0o7777, 0o755);
+        assert_eq!(dir.join("d").metadata().unwrap().mode() & 0o7777, 0o700);
+        // What is in it is still listed from the live directory.
+        assert_eq!(snapshot::real_path(&dir, partial), dir.join("d"));
+        // Nor is anything saved for the snapshot itself.
+        assert_eq!(snapshot::attr_path(&dir, Path::new("/.snapshots/s")), dir);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn snapshots_keep_hard_links_together() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let (dir, mut sm) = with_snapshot("cowlinks");
+        let link = |name: &str| FsOp::Link { path: PathBuf::from("/d/f"), newparent: PathBuf::from("/d"),
+                                             newname: OsString::from(name) };
+        let unlink = |name: &str| FsOp::Unlink { parent: PathBuf::from("/d"), name: OsString::from(name) };
+        let ops = [
+            link("g"),
+            FsOp::Mkdir { parent: PathBuf::from("/.snapshots"), name: OsString::from("t"), mode: 0o755 },
+            // A name made after the snapshot, which the change goes through.
+            link("new"),
+            FsOp::Write { path: PathBuf::from("/d/new"), handle: None, offset: 0, data: b"HELLO".to_vec() },
+        ];
+        for (i, op) in ops.iter().enumerate() {
+            assert_eq!(sm.apply(i as LogIndex + 6, op), Ok(()));
+        }
+        let snap = dir.join(".snapshots/t");
+        assert!(snap.join("d/new").symlink_metadata().unwrap().file_type().is_socket());
+        // Neither old name was changed directly, but both still read as
+        // they were.
+        for name in &["f", "g"] {
+            let saved = snapshot::linked_copy_of(&snap, &dir.join("d").join(name)).unwrap();
+            assert_eq!(fs::read(saved).unwrap(), b"hello");
+        }
+        assert!(is_metadata(Path::new("/.snapshots/t/.raftfs/links")));
+
+        // As they are saved, they stay one file.
+        assert_eq!(sm.apply(10, &unlink("f")), Ok(()));
+        assert_eq!(sm.apply(11, &unlink("g")), Ok(()));
+        let (f, g) = (snap.join("d/f").metadata().unwrap(), snap.join("d/g").metadata().unwrap());
+        assert_eq!(f.ino(), g.ino());
+        assert_eq!(fs::read(snap.join("d/g")).unwrap(), b"hello");
+
+        let op = FsOp::Link { path: PathBuf::from("/.snapshots/t/d/f"), newparent: PathBuf::from("/d"),
+                              newname: OsString::from("stolen") };
+        assert_eq!(sm.apply(12, &op), Err(libc::EROFS));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
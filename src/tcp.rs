@@ -0,0 +1,594 @@
+// Tcp :: Carries raft messages between nodes over TCP.
+//
+// Every node keeps one outgoing connection to each of its peers and
+// accepts their connections in turn, so a message and its reply travel on
+// different sockets.  Nothing ever waits for a reply: messages are written
+// as fast as raft produces them, which is what lets a leader keep several
+// AppendEntries in flight to each follower.
+//
+// A connection starts with MAGIC, and after that each message is a frame:
+// a u32 length followed by that many bytes of encoded message.  When a
+// peer cannot be reached we retry with exponential backoff, and throw away
+// whatever raft asked us to send meanwhile.  Raft expects a lossy network
+// and will send it again.
+//
+// The same port also answers requests from the raftfs command itself,
+// such as a new machine asking to join.  Those connections start with
+// ADMIN_MAGIC instead, and every request frame gets a response frame.
+//
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::codec::{self, DecodeError, Reader};
+use super::raft::{Entry, EntryKind, Member, Membership, Message, NodeId, Rpc, Transport};
+
+/// The first bytes sent on every connection.  The last byte is the
+/// version of the message encoding.
+const MAGIC: &[u8; 8] = b"raftfs\x00\x06";
+
+/// The first bytes sent by somebody with requests rather than messages.
+const ADMIN_MAGIC: &[u8; 8] = b"raftadm\x01";
+
+/// The largest frame we are willing to read.
+const MAX_FRAME: u32 = 256 << 20;
+
+/// How many messages may wait for a peer before we start dropping them.
+const QUEUE: usize = 1024;
+
+/// Limits on how long we wait between attempts to reach a peer.
+const MIN_BACKOFF: u64 = 50;
+const MAX_BACKOFF: u64 = 5000;
+
+/// How long we let a connect or a write take before giving up on a peer.
+const TIMEOUT: u64 = 2000;
+
+pub fn encode_message(msg: &Message) -> Vec<u8> {
+    let mut out = Vec::new();
+    codec::put_u64(&mut out, msg.from);
+    codec::put_u64(&mut out, msg.to);
+    codec::put_u64(&mut out, msg.term);
+    match msg.rpc {
+        Rpc::RequestVote { last_log_index, last_log_term, transfer } => {
+            codec::put_u8(&mut out, 1);
+            codec::put_u64(&mut out, last_log_index);
+            codec::put_u64(&mut out, last_log_term);
+            codec::put_bool(&mut out, transfer);
+        },
+        Rpc::RequestVoteReply { granted } => {
+            codec::put_u8(&mut out, 2);
+            codec::put_bool(&mut out, granted);
+        },
+        Rpc::AppendEntries { prev_log_index, prev_log_term, ref entries, leader_commit, seq } => {
+            codec::put_u8(&mut out, 3);
+            codec::put_u64(&mut out, prev_log_index);
+            codec::put_u64(&mut out, prev_log_term);
+            codec::put_u64(&mut out, leader_commit);
+            codec::put_u64(&mut out, seq);
+            codec::put_u32(&mut out, entries.len() as u32);
+            for e in entries {
+                codec::put_u64(&mut out, e.term);
+                codec::put_u64(&mut out, e.index);
+                codec::put_u8(&mut out, e.kind.tag());
+                codec::put_bytes(&mut out, &e.data);
+            }
+        },
+        Rpc::AppendEntriesReply { success, match_index, seq } => {
+            codec::put_u8(&mut out, 4);
+            codec::put_bool(&mut out, success);
+            codec::put_u64(&mut out, match_index);
+            codec::put_u64(&mut out, seq);
+        },
+        Rpc::InstallSnapshot { last_index, last_term, ref membership, offset, ref data, done } => {
+            codec::put_u8(&mut out, 5);
+            codec::put_u64(&mut out, last_index);
+            codec::put_u64(&mut out, last_term);
+            codec::put_bytes(&mut out, &membership.encode());
+            codec::put_u64(&mut out, offset);
+            codec::put_bytes(&mut out, data);
+            codec::put_bool(&mut out, done);
+        },
+        Rpc::InstallSnapshotReply { last_index, offset, done } => {
+            codec::put_u8(&mut out, 6);
+            codec::put_u64(&mut out, last_index);
+            codec::put_u64(&mut out, offset);
+            codec::put_bool(&mut out, done);
+        },
+        Rpc::ReadIndex { id, lease } => {
+            codec::put_u8(&mut out, 7);
+            codec::put_u64(&mut out, id);
+            codec::put_bool(&mut out, lease);
+        },
+        Rpc::ReadIndexReply { id, index } => {
+            codec::put_u8(&mut out, 8);
+            codec::put_u64(&mut out, id);
+            codec::put_bool(&mut out, index.is_some());
+            codec::put_u64(&mut out, index.unwrap_or(0));
+        },
+        Rpc::Propose { ref data } => {
+            codec::put_u8(&mut out, 9);
+            codec::put_bytes(&mut out, data);
+        },
+        Rpc::TimeoutNow => codec::put_u8(&mut out, 10),
+        Rpc::PreVote { last_log_index, last_log_term } => {
+            codec::put_u8(&mut out, 11);
+            codec::put_u64(&mut out, last_log_index);
+            codec::put_u64(&mut out, last_log_term);
+        },
+        Rpc::PreVoteReply { granted } => {
+            codec::put_u8(&mut out, 12);
+            codec::put_bool(&mut out, granted);
+        },
+    }
+    out
+}
+
+pub fn decode_message(data: &[u8]) -> Result<Message, DecodeError> {
+    let mut r = Reader::new(data);
+    let from = r.u64()?;
+    let to = r.u64()?;
+    let term = r.u64()?;
+    let rpc = match r.u8()? {
+        1 => Rpc::RequestVote { last_log_index: r.u64()?, last_log_term: r.u64()?, transfer: r.bool()? },
+        2 => Rpc::RequestVoteReply { granted: r.bool()? },
+        3 => {
+            let prev_log_index = r.u64()?;
+            let prev_log_term = r.u64()?;
+            let leader_commit = r.u64()?;
+            let seq = r.u64()?;
+            let n = r.u32()?;
+            let mut entries = Vec::new();
+            for _ in 0..n {
+                entries.push(Entry {
+                    term: r.u64()?,
+                    index: r.u64()?,
+                    kind: EntryKind::from_tag(r.u8()?)?,
+                    data: r.bytes()?,
+                });
+            }
+            Rpc::AppendEntries {
+                prev_log_index: prev_log_index,
+                prev_log_term: prev_log_term,
+                entries: entries,
+                leader_commit: leader_commit,
+                seq: seq,
+            }
+        },
+        4 => Rpc::AppendEntriesReply { success: r.bool()?, match_index: r.u64()?, seq: r.u64()? },
+        5 => Rpc::InstallSnapshot {
+            last_index: r.u64()?,
+            last_term: r.u64()?,
+            membership: Membership::decode(&r.bytes()?)?,
+            offset: r.u64()?,
+            data: r.bytes()?,
+            done: r.bool()?,
+        },
+        6 => Rpc::InstallSnapshotReply { last_index: r.u64()?, offset: r.u64()?, done: r.bool()? },
+        7 => Rpc::ReadIndex { id: r.u64()?, lease: r.bool()? },
+        8 => {
+            let id = r.u64()?;
+            let known = r.bool()?;
+            let index = r.u64()?;
+            Rpc::ReadIndexReply { id: id, index: if known { Some(index) } else { None } }
+        },
+        9 => Rpc::Propose { data: r.bytes()? },
+        10 => Rpc::TimeoutNow,
+        11 => Rpc::PreVote { last_log_index: r.u64()?, last_log_term: r.u64()? },
+        12 => Rpc::PreVoteReply { granted: r.bool()? },
+        tag => return Err(DecodeError::UnknownTag(tag)),
+    };
+    r.finish()?;
+    Ok(Message { from: from, to: to, term: term, rpc: rpc })
+}
+
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut len = Vec::new();
+    codec::put_u32(&mut len, payload.len() as u32);
+    w.write_all(&len)?;
+    w.write_all(payload)
+}
+
+/// Read one frame, or None if the other end closed the connection
+/// between frames.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = Reader::new(&len).u32().unwrap();
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("frame of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Sends raft messages to the peers it was told about.
+pub struct TcpTransport {
+    queues: HashMap<NodeId, (SocketAddr, SyncSender<Message>)>,
+}
+
+impl TcpTransport {
+    pub fn new(peers: &[(NodeId, SocketAddr)]) -> TcpTransport {
+        let mut t = TcpTransport { queues: HashMap::new() };
+        for &(id, addr) in peers {
+            t.add_peer(id, addr);
+        }
+        t
+    }
+
+    fn add_peer(&mut self, id: NodeId, addr: SocketAddr) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE);
+        thread::Builder::new()
+            .name(format!("raftfs-peer-{}", id))
+            .spawn(move || run_peer(id, addr, rx))
+            .unwrap();
+        // Any sender this replaces finds its queue gone and stops.
+        self.queues.insert(id, (addr, tx));
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, msg: Message) {
+        match self.queues.get(&msg.to) {
+            Some((_, queue)) => match queue.try_send(msg) {
+                Ok(()) => (),
+                Err(TrySendError::Full(msg)) => {
+                    debug!("queue for node {} is full; dropping a message", msg.to);
+                },
+                Err(TrySendError::Disconnected(msg)) => {
+                    error!("sender for node {} has died", msg.to);
+                },
+            },
+            None => warn!("no address for node {}", msg.to),
+        }
+    }
+
+    fn set_peers(&mut self, peers: &[Member]) {
+        let mut wanted = HashMap::new();
+        for m in peers {
+            match m.addr.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    wanted.insert(m.id, addr);
+                },
+                Err(_) => warn!("node {} has no usable address: {:?}", m.id, m.addr),
+            }
+        }
+        self.queues.retain(|id, &mut (addr, _)| wanted.get(id) == Some(&addr));
+        for (id, addr) in wanted {
+            if !self.queues.contains_key(&id) {
+                self.add_peer(id, addr);
+            }
+        }
+    }
+}
+
+fn run_peer(id: NodeId, addr: SocketAddr, queue: Receiver<Message>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        // Don't bother connecting until there is something to say.
+        let first = match queue.recv() {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let stream = match TcpStream::connect_timeout(&addr, Duration::from_millis(TIMEOUT)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("unable to reach node {} at {}: {}", id, addr, e);
+                thread::sleep(Duration::from_millis(backoff));
+                backoff = ::std::cmp::min(2 * backoff, MAX_BACKOFF);
+                while queue.try_recv().is_ok() {}
+                continue;
+            },
+        };
+        debug!("connected to node {} at {}", id, addr);
+        backoff = MIN_BACKOFF;
+        match pump(stream, first, &queue) {
+            Ok(()) => return,
+            Err(e) => debug!("lost connection to node {}: {}", id, e),
+        }
+    }
+}
+
+/// Write messages to `stream` until it fails or the transport goes away.
+fn pump(stream: TcpStream, first: Message, queue: &Receiver<Message>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(Duration::from_millis(TIMEOUT)))?;
+    let mut w = BufWriter::new(stream);
+    w.write_all(MAGIC)?;
+    let mut msg = first;
+    loop {
+        write_frame(&mut w, &encode_message(&msg))?;
+        msg = match queue.try_recv() {
+            Ok(msg) => msg,
+            Err(TryRecvError::Empty) => {
+                w.flush()?;
+                match queue.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => return Ok(()),
+                }
+            },
+            Err(TryRecvError::Disconnected) => return w.flush(),
+        };
+    }
+}
+
+/// Accepts connections from peers.
+pub struct Listener {
+    listener: TcpListener,
+}
+
+impl Listener {
+    pub fn bind(addr: &SocketAddr) -> io::Result<Listener> {
+        Ok(Listener { listener: TcpListener::bind(addr)? })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Hand every message that arrives to `deliver`, and every request
+    /// to `answer`, from now on.
+    pub fn serve<F, A>(self, deliver: F, answer: A)
+        where F: Fn(Message) + Send + Sync + 'static,
+              A: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static
+    {
+        let deliver = Arc::new(deliver);
+        let answer = Arc::new(answer);
+        thread::Builder::new().name("raftfs-listen".to_string()).spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("accept failed: {}", e);
+                        continue;
+                    },
+                };
+                let deliver = deliver.clone();
+                let answer = answer.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().ok();
+                    if let Err(e) = receive(stream, &*deliver, &*answer) {
+                        debug!("dropping connection from {:?}: {}", peer, e);
+                    }
+                });
+            }
+        }).unwrap();
+    }
+}
+
+fn receive<F, A>(stream: TcpStream, deliver: &F, answer: &A) -> io::Result<()>
+    where F: Fn(Message),
+          A: Fn(&[u8]) -> Vec<u8>
+{
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic == ADMIN_MAGIC {
+        return converse(r, stream, answer);
+    }
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("bad greeting {:?}", magic)));
+    }
+    while let Some(frame) = read_frame(&mut r)? {
+        let msg = decode_message(&frame).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })?;
+        deliver(msg);
+    }
+    Ok(())
+}
+
+/// Answer each request frame read from `r` with a response frame on `w`.
+pub fn converse<R: Read, W: Write, A>(mut r: R, mut w: W, answer: &A) -> io::Result<()>
+    where A: Fn(&[u8]) -> Vec<u8>
+{
+    while let Some(request) = read_frame(&mut r)? {
+        write_frame(&mut w, &answer(&request))?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+/// Send a request to the node listening at `addr` and wait for its
+/// response.
+pub fn ask(addr: &SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(addr, Duration::from_millis(TIMEOUT))?;
+    stream.set_write_timeout(Some(Duration::from_millis(TIMEOUT)))?;
+    stream.write_all(ADMIN_MAGIC)?;
+    write_frame(&mut stream, request)?;
+    match read_frame(&mut stream)? {
+        Some(response) => Ok(response),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                   format!("{} hung up without answering", addr))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use super::super::raft::{Clock, Config, HardState, HardStateStore, MemHardState, MemLog,
+                             MemSnapshots, Raft, Role};
+
+    fn messages() -> Vec<Message> {
+        let rpcs = vec![
+            Rpc::RequestVote { last_log_index: 7, last_log_term: 3, transfer: true },
+            Rpc::RequestVoteReply { granted: true },
+            Rpc::AppendEntries {
+                prev_log_index: 7,
+                prev_log_term: 3,
+                entries: vec![
+                    Entry { term: 4, index: 8, kind: EntryKind::Normal, data: b"op".to_vec() },
+                    Entry { term: 4, index: 9, kind: EntryKind::Membership, data: Vec::new() },
+                ],
+                leader_commit: 6,
+                seq: 12,
+            },
+            Rpc::AppendEntriesReply { success: false, match_index: 5, seq: 12 },
+            Rpc::InstallSnapshot {
+                last_index: 9,
+                last_term: 4,
+                membership: Membership {
+                    index: 6,
+                    members: vec![Member { id: 1, addr: "10.0.0.1:7420".to_string() },
+                                  Member { id: 2, addr: "[::1]:7420".to_string() }],
+                },
+                offset: 1 << 33,
+                data: vec![0; 100],
+                done: true,
+            },
+            Rpc::InstallSnapshotReply { last_index: 9, offset: 100, done: false },
+            Rpc::ReadIndex { id: 3, lease: true },
+            Rpc::ReadIndexReply { id: 3, index: Some(9) },
+            Rpc::ReadIndexReply { id: 4, index: None },
+            Rpc::Propose { data: b"op".to_vec() },
+            Rpc::TimeoutNow,
+            Rpc::PreVote { last_log_index: 7, last_log_term: 3 },
+            Rpc::PreVoteReply { granted: false },
+        ];
+        rpcs.into_iter().map(|rpc| Message { from: 1, to: 2, term: 4, rpc: rpc }).collect()
+    }
+
+    /// Pick a loopback address that nobody is listening on.
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn inbox(listener: Listener) -> Arc<Mutex<Vec<Message>>> {
+        let inbox = Arc::new(Mutex::new(Vec::new()));
+        let sink = inbox.clone();
+        listener.serve(move |msg| sink.lock().unwrap().push(msg), |req| req.to_vec());
+        inbox
+    }
+
+    fn wait_for<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for msg in messages() {
+            assert_eq!(decode_message(&encode_message(&msg)), Ok(msg));
+        }
+        let mut data = encode_message(&messages()[0]);
+        data[24] = 99;
+        assert_eq!(decode_message(&data), Err(DecodeError::UnknownTag(99)));
+        let data = encode_message(&messages()[2]);
+        assert_eq!(decode_message(&data[..data.len() - 1]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut data = Vec::new();
+        codec::put_u32(&mut data, MAX_FRAME + 1);
+        data.extend_from_slice(&[0; 16]);
+        assert!(read_frame(&mut &data[..]).is_err());
+        assert_eq!(read_frame(&mut &b""[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn delivers_in_order_over_loopback() {
+        let listener = Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = inbox(listener);
+        let mut t = TcpTransport::new(&[(2, addr)]);
+        for msg in messages() {
+            t.send(msg);
+        }
+        wait_for(|| received.lock().unwrap().len() == messages().len());
+        assert_eq!(*received.lock().unwrap(), messages());
+    }
+
+    #[test]
+    fn answers_requests_beside_messages() {
+        let listener = Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = inbox(listener);
+        let mut t = TcpTransport::new(&[(2, addr)]);
+        t.send(messages()[0].clone());
+        assert_eq!(ask(&addr, b"hello").unwrap(), b"hello".to_vec());
+        assert_eq!(ask(&addr, b"").unwrap(), Vec::<u8>::new());
+        wait_for(|| received.lock().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn reconnects_when_peer_appears() {
+        let addr = free_addr();
+        let mut t = TcpTransport::new(&[(2, addr)]);
+        t.send(messages()[0].clone());
+        thread::sleep(Duration::from_millis(200));
+        let received = inbox(Listener::bind(&addr).unwrap());
+        wait_for(|| {
+            t.send(messages()[1].clone());
+            !received.lock().unwrap().is_empty()
+        });
+        assert_eq!(received.lock().unwrap()[0], messages()[1]);
+    }
+
+    struct Wall;
+    impl Clock for Wall {
+        fn now(&self) -> u64 {
+            ::time::precise_time_ns() / 1_000_000
+        }
+    }
+
+    #[test]
+    fn three_nodes_over_loopback() {
+        let listeners: Vec<Listener> = (0..3).map(|_| {
+            Listener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        }).collect();
+        let addrs: Vec<(NodeId, SocketAddr)> = listeners.iter().enumerate()
+            .map(|(i, l)| (i as NodeId + 1, l.local_addr().unwrap())).collect();
+        let inboxes: Vec<_> = listeners.into_iter().map(inbox).collect();
+        // The transports learn where everybody is from the membership.
+        let members = addrs.iter().map(|&(id, addr)| Member { id: id, addr: addr.to_string() });
+        let membership = Membership { index: 0, members: members.collect() };
+        let mut nodes: Vec<Raft> = addrs.iter().map(|&(id, _)| {
+            let mut hard_state = MemHardState::new();
+            hard_state.save(&HardState { membership: membership.clone(), ..HardState::default() })
+                .unwrap();
+            Raft::new(id, Box::new(MemLog::new()), Box::new(hard_state),
+                      Box::new(MemSnapshots::new()), Box::new(TcpTransport::new(&[])),
+                      Box::new(Wall), Config::default())
+        }).collect();
+
+        let mut proposed = None;
+        wait_for(|| {
+            for (node, inbox) in nodes.iter_mut().zip(inboxes.iter()) {
+                for msg in inbox.lock().unwrap().drain(..) {
+                    node.step(msg);
+                }
+                node.tick();
+                node.flush();
+            }
+            if proposed.is_none() {
+                if let Some(leader) = nodes.iter_mut().find(|n| n.role() == Role::Leader) {
+                    proposed = leader.propose(b"over the wire".to_vec()).ok();
+                }
+            }
+            match proposed {
+                Some(index) => nodes.iter().all(|n| n.commit_index() >= index),
+                None => false,
+            }
+        });
+        let leaders = nodes.iter().filter(|n| n.role() == Role::Leader).count();
+        assert_eq!(leaders, 1);
+    }
+}
@@ -15,8 +15,11 @@ impl TempDir {
         let e = location_of_executables().join("raftfs");
         println!("executable = {:?}", &e);
         // Now run raftfs to mount us
+        assert!(std::process::Command::new(&e)
+                .args(&["init", "data"])
+                .current_dir(&p).status().unwrap().success());
         let s = std::process::Command::new(e)
-            .args(&["data", "mnt"])
+            .args(&["mount", "data", "mnt"])
             .current_dir(&p).spawn();
         if !s.is_ok() {
             println!("Bad news: {:?}", s);